@@ -0,0 +1,203 @@
+// the principled BSDF every material is an instance of: a diffuse base with sheen and GGX specular
+// reflection, blending into a metal and into rough glass, under a clearcoat layer
+
+#include "random.glsl"
+
+// smallest GGX alpha, perfectly smooth surfaces would make the distribution a delta
+#define MIN_ALPHA 1e-3
+
+struct BsdfSample {
+    vec3 direction;
+    // BSDF times cosine over pdf, what the path throughput gets multiplied by
+    vec3 weight;
+    // solid angle density of the lobes that aren't deltas, see evaluateBsdf
+    float pdf;
+    // picked by the glass lobe, which evaluateBsdf leaves out so light sampling can't reach it either
+    bool isDelta;
+    // picked by the subsurface lobe, direction goes into the object and the path has to random walk back out
+    bool isSubsurface;
+};
+
+float schlickFresnel(float cosine, float refractionIndex) {
+    float r0 = (1.0 - refractionIndex) / (1.0 + refractionIndex);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow((1.0 - cosine), 5.0);
+}
+
+// how much of the material each lobe makes up, the clearcoat sits on top of the rest
+float diffuseAmount(Material m) {
+    return (1. - m.metallic) * (1. - m.transmission) * (1. - m.subsurface);
+}
+
+float subsurfaceAmount(Material m) {
+    return (1. - m.metallic) * (1. - m.transmission) * m.subsurface;
+}
+
+float transmissionAmount(Material m) {
+    return (1. - m.metallic) * m.transmission;
+}
+
+float specularAmount(Material m) {
+    return 1. - transmissionAmount(m);
+}
+
+float clearcoatAmount(Material m) {
+    return .25 * m.clearcoat;
+}
+
+// chances of sampleBsdf picking the diffuse and subsurface, specular, clearcoat and glass lobes
+vec4 lobeProbabilities(Material m) {
+    // a dielectric's specular lobe reflects only a few percent, most samples are better spent on diffuse
+    vec4 weights = vec4(diffuseAmount(m) + subsurfaceAmount(m), specularAmount(m) * mix(.25, 1., m.metallic), clearcoatAmount(m), transmissionAmount(m));
+    return weights / (weights.x + weights.y + weights.z + weights.w);
+}
+
+float specularAlpha(Material m) {
+    return max(m.roughness * m.roughness, MIN_ALPHA);
+}
+
+float clearcoatAlpha(Material m) {
+    return max(m.clearcoatRoughness * m.clearcoatRoughness, MIN_ALPHA);
+}
+
+// head on reflectance of the specular lobe, up to 8% for dielectrics and the base color for metals
+vec3 specularF0(Material m) {
+    return mix(vec3(.08 * m.specular), m.baseColor, m.metallic);
+}
+
+float ggxDistribution(float nh, float alpha) {
+    float a2 = alpha * alpha;
+    float d = nh * nh * (a2 - 1.) + 1.;
+    return a2 / (M_PI * d * d);
+}
+
+float smithG1(float nv, float alpha) {
+    float a2 = alpha * alpha;
+    return 2. * nv / (nv + sqrt(a2 + (1. - a2) * nv * nv));
+}
+
+// GGX reflection off microfacets whose normal is the half vector h, and the density of sampling it
+vec3 microfacetReflection(vec3 f0, float alpha, float nv, float nl, float nh, float vh, out float pdf) {
+    float d = ggxDistribution(nh, alpha);
+    pdf = d * nh / (4. * vh);
+    vec3 fresnel = f0 + (1. - f0) * pow(1. - vh, 5.);
+    return d * smithG1(nv, alpha) * smithG1(nl, alpha) * fresnel / (4. * nv * nl);
+}
+
+// microfacet normal drawn from the GGX distribution around n
+vec3 sampleGgxNormal(vec3 n, float alpha) {
+    float u = random();
+    float a2 = alpha * alpha;
+    float cosTheta = sqrt((1. - u) / (1. + (a2 - 1.) * u));
+    float sinTheta = sqrt(max(1. - cosTheta * cosTheta, 0.));
+
+    float phi = 2. * M_PI * random();
+
+    vec3 t, b;
+    orthonormalBasis(n, t, b);
+
+    return (t * cos(phi) + b * sin(phi)) * sinTheta + n * cosTheta;
+}
+
+// wo reflected off a microfacet normal drawn from the GGX distribution around n
+vec3 sampleGgxReflection(vec3 n, vec3 wo, float alpha) {
+    return reflect(-wo, sampleGgxNormal(n, alpha));
+}
+
+// every lobe but glass and subsurface scattering light from wi towards wo, pdf is how likely sampleBsdf is to pick wi
+vec3 evaluateBsdf(Material m, vec3 n, vec3 wo, vec3 wi, out float pdf) {
+    pdf = 0.;
+
+    float nv = dot(n, wo);
+    float nl = dot(n, wi);
+    if (nv <= 0. || nl <= 0.) {
+        return vec3(0);
+    }
+
+    vec3 h = normalize(wo + wi);
+    float nh = max(dot(n, h), 0.);
+    float vh = clamp(dot(wo, h), 1e-6, 1.);
+    vec4 probabilities = lobeProbabilities(m);
+
+    vec3 f = diffuseAmount(m) * (m.baseColor * (.5 / M_PI) + m.sheen * pow(1. - vh, 5.));
+    pdf += probabilities.x * (1. - m.subsurface) * nl / M_PI;
+
+    float lobePdf;
+    f += specularAmount(m) * microfacetReflection(specularF0(m), specularAlpha(m), nv, nl, nh, vh, lobePdf);
+    pdf += probabilities.y * lobePdf;
+
+    f += clearcoatAmount(m) * microfacetReflection(vec3(.04), clearcoatAlpha(m), nv, nl, nh, vh, lobePdf);
+    pdf += probabilities.z * lobePdf;
+
+    return f;
+}
+
+// picks a lobe and a direction to continue the path in, n faces wo
+BsdfSample sampleBsdf(Material m, vec3 n, vec3 wo, bool frontFace) {
+    BsdfSample s;
+    vec4 probabilities = lobeProbabilities(m);
+    float u = random();
+
+    s.isSubsurface = false;
+
+    if (u < probabilities.w) {
+        s.pdf = 0.;
+        s.isDelta = true;
+
+        if (m.indexOfRefraction == 1.) {
+            // nothing to reflect or bend light at, only the medium behind changes
+            s.direction = -wo;
+            s.weight = vec3(transmissionAmount(m) / probabilities.w);
+            return s;
+        }
+
+        // refraction or reflection through a microfacet, picked by its Fresnel term
+        float alpha = specularAlpha(m);
+        vec3 h = sampleGgxNormal(n, alpha);
+        float refractionRatio = frontFace ? (1.0 / m.indexOfRefraction) : m.indexOfRefraction;
+        float cosTheta = min(dot(wo, h), 1.0);
+        float sinTheta = sqrt(max(1.0 - cosTheta*cosTheta, 0.));
+
+        bool cannotRefract = refractionRatio * sinTheta > 1.0;
+        bool reflected = cannotRefract || schlickFresnel(cosTheta, refractionRatio) > random();
+        s.direction = reflected ? reflect(-wo, h) : refract(-wo, h, refractionRatio);
+
+        // Walter et al.'s weight for sampling microfacets by D(h) (n.h), the Fresnel term cancels with picking
+        // between reflection and refraction, paths leaving on the wrong side of the surface get nothing
+        float nv = dot(n, wo);
+        float nl = dot(n, s.direction);
+        if (cosTheta > 0. && nv > 0. && (reflected ? nl > 0. : nl < 0.)) {
+            float g = smithG1(nv, alpha) * smithG1(abs(nl), alpha);
+            s.weight = vec3(g * cosTheta / (nv * dot(n, h)) * transmissionAmount(m) / probabilities.w);
+        } else {
+            s.weight = vec3(0);
+        }
+        return s;
+    }
+
+    if (u < probabilities.w + probabilities.x && m.subsurface > 0. && random() < m.subsurface) {
+        // into the object, where randomWalk takes over and picks up the subsurface color, paths already inside
+        // have nowhere to walk
+        float weight;
+        s.direction = getHemisphereCosineSample(-n, weight);
+        s.weight = frontFace ? vec3((1. - m.metallic) * (1. - m.transmission) / probabilities.x) : vec3(0);
+        s.pdf = 0.;
+        s.isDelta = true;
+        s.isSubsurface = true;
+        return s;
+    }
+
+    if (u < probabilities.w + probabilities.x) {
+        float weight;
+        s.direction = getHemisphereCosineSample(n, weight);
+    } else if (u < probabilities.w + probabilities.x + probabilities.y) {
+        s.direction = sampleGgxReflection(n, wo, specularAlpha(m));
+    } else {
+        s.direction = sampleGgxReflection(n, wo, clearcoatAlpha(m));
+    }
+
+    vec3 f = evaluateBsdf(m, n, wo, s.direction, s.pdf);
+    s.weight = s.pdf > 0. ? f * dot(n, s.direction) / s.pdf : vec3(0);
+    s.isDelta = false;
+    return s;
+}
@@ -0,0 +1,55 @@
+// types and constants shared by everything the path tracer is made of
+
+#define MAX_DIST 10000.0
+
+#define M_PI acos(-1.0)
+
+// parameters of the principled BSDF, see bsdf.glsl
+struct Material {
+    vec3 baseColor;
+    float metallic;
+    float roughness;
+    float specular;
+    float clearcoat;
+    float clearcoatRoughness;
+    float sheen;
+    float subsurface;
+    vec3 subsurfaceColor;
+    // per color channel, inside the object
+    vec3 meanFreePath;
+    float transmission;
+    float indexOfRefraction;
+    // per unit of distance inside the object
+    vec3 absorption;
+    vec3 emission;
+    // layers of materialTextures, or NO_TEXTURE
+    uint albedoTexture;
+    uint roughnessTexture;
+    uint metalnessTexture;
+    uint normalTexture;
+    // index into media of what fills the object, or NO_MEDIUM
+    uint medium;
+};
+
+struct HitInfo {
+    float dist;
+    vec3 normal;
+    bool frontFace;
+    bool didHit;
+    Material material;
+    int objectIndex;
+    // texture coordinates and the local space direction they increase in u, only filled in for meshes
+    // until applyTextures needs them
+    vec2 uv;
+    vec3 tangent;
+};
+
+// t and b perpendicular to n and each other, with cross(n, t) == b, see "Building an Orthonormal Basis, Revisited"
+// by Duff et al.; crossing n with a swizzle of itself instead leaves nothing when n is parallel to (1, 1, 1)
+void orthonormalBasis(vec3 n, out vec3 t, out vec3 b) {
+    float s = n.z >= 0. ? 1. : -1.;
+    float a = -1. / (s + n.z);
+    float c = n.x * n.y * a;
+    t = vec3(1. + s * n.x * n.x * a, s * c, -s * n.x);
+    b = vec3(c, s + n.y * n.y * a, -n.y);
+}
@@ -0,0 +1,145 @@
+// what paths see once they leave the scene, an equirectangular HDR image, a physical sky or a gradient
+
+#include "random.glsl"
+#include "scene.glsl"
+
+// rows of width conditional CDFs, then the marginal CDF over the rows, see EnvironmentMap::cdf
+layout(std430, binding = 7) buffer EnvironmentBuffer {
+    GpuEnvironment environment;
+    float environmentCdf[];
+};
+
+// only read when there is a map, the renderer fills it with nearest filtering
+uniform sampler2D environmentTexture;
+
+layout(std430, binding = 8) buffer SkyBuffer {
+    GpuSky sky;
+};
+
+bool hasEnvironmentMap() {
+    return environment.width > 0u;
+}
+
+bool hasSun() {
+    return sky.enabled != 0u && sky.sunRadiance != vec3(0);
+}
+
+// Preetham's distribution of luminance and chromaticity, theta from the zenith and gamma from the sun
+vec3 perezFunction(float cosTheta, float gamma) {
+    float cosGamma = cos(gamma);
+    return (1. + sky.perezA.xyz * exp(sky.perezB.xyz / cosTheta)) * (1. + sky.perezC.xyz * exp(sky.perezD.xyz * gamma) + sky.perezE.xyz * cosGamma * cosGamma);
+}
+
+// the sky without the sun disk, below the horizon it keeps the color it has at the horizon
+vec3 physicalSky(vec3 d) {
+    float cosTheta = max(d.y, .01);
+    float gamma = acos(clamp(dot(d, sky.sunDirection), -1., 1.));
+
+    vec3 xyY = sky.zenith * perezFunction(cosTheta, gamma);
+    float Y = xyY.x;
+    vec3 XYZ = vec3(xyY.y * Y / xyY.z, Y, (1. - xyY.y - xyY.z) * Y / xyY.z);
+
+    // XYZ to linear sRGB, written out by columns
+    mat3 toRgb = mat3(
+        3.2406, -0.9689, 0.0557,
+        -1.5372, 1.8758, -0.2040,
+        -0.4986, 0.0415, 1.0570
+    );
+    return max(toRgb * XYZ, vec3(0));
+}
+
+// radiance of the sun disk if d points into it
+vec3 sunDisk(vec3 d) {
+    return hasSun() && dot(d, sky.sunDirection) >= sky.cosSunRadius ? sky.sunRadiance : vec3(0);
+}
+
+vec2 environmentUv(vec3 d) {
+    float phi = atan(d.x, -d.z) - environment.rotation;
+    return vec2(fract(phi / (2. * M_PI) + .5), acos(clamp(d.y, -1., 1.)) / M_PI);
+}
+
+vec3 environmentDirection(vec2 uv) {
+    float phi = (uv.x - .5) * 2. * M_PI + environment.rotation;
+    float theta = uv.y * M_PI;
+    return vec3(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+}
+
+ivec2 environmentTexel(vec2 uv) {
+    ivec2 size = ivec2(environment.width, environment.height);
+    return min(ivec2(uv * vec2(size)), size - 1);
+}
+
+vec3 skyBox(vec3 rd) {
+    vec3 unitDirection = normalize(rd);
+
+    if (hasEnvironmentMap()) {
+        return texelFetch(environmentTexture, environmentTexel(environmentUv(unitDirection)), 0).rgb * environment.intensity;
+    }
+    if (sky.enabled != 0u) {
+        return physicalSky(unitDirection);
+    }
+
+    float t = 0.5 * (unitDirection.y + 1.0);
+    return (1.0-t)*vec3(1) + t*vec3(0.5, 0.7, 1.0);
+}
+
+// first of the count CDF entries starting at first that is above u, or the last one
+uint searchCdf(uint first, uint count, float u) {
+    uint low = 0u;
+    uint high = count - 1u;
+    while (low < high) {
+        uint middle = (low + high) / 2u;
+        if (environmentCdf[first + middle] > u) {
+            high = middle;
+        } else {
+            low = middle + 1u;
+        }
+    }
+    return low;
+}
+
+// probability of picking entry i of the CDF starting at first
+float cdfStep(uint first, uint i) {
+    return environmentCdf[first + i] - (i > 0u ? environmentCdf[first + i - 1u] : 0.);
+}
+
+// solid angle density of sampleEnvironmentDirection picking d
+float environmentPdf(vec3 d) {
+    uint width = environment.width;
+    uint height = environment.height;
+
+    float sinTheta = sqrt(max(1. - d.y * d.y, 0.));
+    if (sinTheta <= 0.) {
+        return 0.;
+    }
+
+    ivec2 texel = environmentTexel(environmentUv(d));
+    float uvPdf = cdfStep(width * height, uint(texel.y)) * cdfStep(uint(texel.y) * width, uint(texel.x)) * float(width * height);
+
+    return uvPdf / (2. * M_PI * M_PI * sinTheta);
+}
+
+// direction towards a point on the environment map, picked in proportion to how much light it gives off
+vec3 sampleEnvironmentDirection(out float pdf) {
+    uint width = environment.width;
+    uint height = environment.height;
+
+    float u = random();
+    float v = random();
+
+    uint y = searchCdf(width * height, height, v);
+    uint x = searchCdf(y * width, width, u);
+
+    float rowPdf = cdfStep(width * height, y);
+    float columnPdf = cdfStep(y * width, x);
+
+    // where within the pixel's CDF step the random numbers landed, so the whole pixel can be picked
+    float dy = rowPdf > 0. ? clamp((v - environmentCdf[width * height + y] + rowPdf) / rowPdf, 0., 1.) : .5;
+    float dx = columnPdf > 0. ? clamp((u - environmentCdf[y * width + x] + columnPdf) / columnPdf, 0., 1.) : .5;
+
+    vec2 uv = (vec2(x, y) + vec2(dx, dy)) / vec2(width, height);
+    float sinTheta = sin(uv.y * M_PI);
+
+    pdf = sinTheta > 0. ? rowPdf * columnPdf * float(width * height) / (2. * M_PI * M_PI * sinTheta) : 0.;
+    return environmentDirection(uv);
+}
@@ -0,0 +1,134 @@
+// ray intersection with the primitives objects are made of, in their local space
+
+#include "common.glsl"
+
+HitInfo hitSphere(vec3 ro, vec3 rd, vec3 center, float radius) {
+    HitInfo info;
+    vec3 oc = ro - center;
+    float a = dot(rd, rd);
+    float half_b = dot(oc, rd);
+    float c = dot(oc, oc) - radius*radius;
+    float discr = half_b*half_b - a*c;
+    if (discr < 0.) {
+        info.didHit = false;
+        return info;
+    }
+
+    float dist = (-half_b - sqrt(discr)) / a;
+
+    if (dist < 0.001) {
+        dist = (-half_b + sqrt(discr)) / a;
+        if (dist < 0.001) {
+            info.didHit = false;
+            return info;
+        }
+    }
+
+    info.didHit = true;
+    info.dist = dist;
+    vec3 outwardNormal = normalize(ro + dist*rd - center);
+    info.frontFace = dot(rd, outwardNormal) < 0.;
+    info.normal = info.frontFace ? outwardNormal : -outwardNormal;  
+
+    return info;
+}
+
+HitInfo hitBox(vec3 ro, vec3 rd, vec3 s) {
+    vec3 m = 1.0 / rd;
+    vec3 n = m*  ro;
+    vec3 k = abs(m) * s;
+    vec3 f = -n - k;
+    vec3 b = -n + k;
+    float fl = max(max(f.x, f.y), f.z);
+    float bl = min(min(b.x, b.y), b.z);
+    HitInfo info;
+    if (fl > bl || bl < 0.001) {
+        info.didHit = false;
+        return info;
+    }
+    // from inside the box, or right on its surface, the ray hits the face it leaves through
+    bool inside = fl < 0.001;
+    vec3 no = inside ? sign(rd) * step(b.xyz, b.yzx) * step(b.xyz, b.zxy) : -sign(rd) * step(f.yzx, f.xyz) * step(f.zxy, f.xyz);
+    info.didHit = true;
+    info.dist = inside ? bl : fl;
+    info.frontFace = dot(rd, no) < 0.;
+    info.normal = info.frontFace ? no : -no;
+    return info;
+}
+
+// the y = 0 plane, facing +y
+HitInfo hitPlane(vec3 ro, vec3 rd) {
+    HitInfo info;
+    float dist = -ro.y / rd.y;
+    info.didHit = dist >= 0.001 && dist < MAX_DIST;
+    info.dist = dist;
+    info.frontFace = rd.y < 0.;
+    info.normal = vec3(0, info.frontFace ? 1. : -1., 0);
+    return info;
+}
+
+// the unit disk on the y = 0 plane
+HitInfo hitDisk(vec3 ro, vec3 rd) {
+    HitInfo info = hitPlane(ro, rd);
+    vec2 p = ro.xz + rd.xz * info.dist;
+    info.didHit = info.didHit && dot(p, p) <= 1.;
+    return info;
+}
+
+// the -1 to 1 square on the y = 0 plane
+HitInfo hitQuad(vec3 ro, vec3 rd) {
+    HitInfo info = hitPlane(ro, rd);
+    vec2 p = ro.xz + rd.xz * info.dist;
+    info.didHit = info.didHit && max(abs(p.x), abs(p.y)) <= 1.;
+    return info;
+}
+
+// normal of the face of the -1 to 1 box that p is on
+vec3 localBoxNormal(vec3 p) {
+    vec3 a = abs(p);
+    if (a.x >= a.y && a.x >= a.z) {
+        return vec3(sign(p.x), 0, 0);
+    } else if (a.y >= a.z) {
+        return vec3(0, sign(p.y), 0);
+    }
+    return vec3(0, 0, sign(p.z));
+}
+
+// texture coordinates of a point on the unit sphere, wrapping around +y starting at -z, and the direction u increases in
+vec2 sphereUv(vec3 p, out vec3 tangent) {
+    vec3 n = normalize(p);
+    tangent = vec3(n.z, 0, -n.x);
+    return vec2(.5 + atan(n.x, n.z) / (2. * M_PI), .5 + asin(clamp(n.y, -1., 1.)) / M_PI);
+}
+
+// texture coordinates of a point on the -1 to 1 box, the whole texture on every face, upright on the sides
+vec2 boxUv(vec3 p, out vec3 tangent) {
+    vec3 n = localBoxNormal(p);
+    if (n.x != 0.) {
+        tangent = vec3(0, 0, -n.x);
+        return vec2(1. - n.x * p.z, 1. + p.y) * .5;
+    } else if (n.y != 0.) {
+        tangent = vec3(1, 0, 0);
+        return vec2(1. + p.x, 1. - n.y * p.z) * .5;
+    }
+    tangent = vec3(n.z, 0, 0);
+    return vec2(1. + n.z * p.x, 1. + p.y) * .5;
+}
+
+// texture coordinates of a point on the y = 0 plane, laid out like the top of the box and repeating every 2 units
+vec2 planeUv(vec3 p, out vec3 tangent) {
+    tangent = vec3(1, 0, 0);
+    return vec2(1. + p.x, 1. - p.z) * .5;
+}
+
+// whether the ray passes through the box anywhere between its origin and maxDist
+bool hitBounds(vec3 ro, vec3 rd, vec3 boundsMin, vec3 boundsMax, float maxDist) {
+    vec3 m = 1.0 / rd;
+    vec3 t0 = (boundsMin - ro) * m;
+    vec3 t1 = (boundsMax - ro) * m;
+    vec3 tMin = min(t0, t1);
+    vec3 tMax = max(t0, t1);
+    float near = max(max(tMin.x, tMin.y), tMin.z);
+    float far = min(min(tMax.x, tMax.y), tMax.z);
+    return near <= far && far >= 0.0 && near < maxDist;
+}
@@ -0,0 +1,228 @@
+// next event estimation towards emissive spheres and boxes, the analytic lights, the environment map and the sun,
+// through the medium p is in. A zero normal n puts p on a particle of that medium rather than on a surface.
+
+#include "random.glsl"
+#include "scene.glsl"
+#include "environment.glsl"
+#include "bsdf.glsl"
+#include "media.glsl"
+
+float powerHeuristic(float pdf, float otherPdf) {
+    float a = pdf * pdf;
+    return a / (a + otherPdf * otherPdf);
+}
+
+// cosine factor of light arriving from wi, which particles don't have
+float scatteringCosine(vec3 n, vec3 wi) {
+    return n == vec3(0) ? 1. : dot(n, wi);
+}
+
+// the material's BSDF on surfaces and the medium's phase function on particles, a zero wo is where a subsurface
+// walk left its object, diffusely whatever way it came in
+vec3 evaluateScattering(Material material, vec3 n, vec3 wo, vec3 wi, uint medium, out float pdf) {
+    if (n == vec3(0)) {
+        pdf = henyeyGreenstein(dot(-wo, wi), media[medium].anisotropy);
+        return vec3(pdf);
+    }
+    if (wo == vec3(0)) {
+        pdf = max(dot(n, wi), 0.) / M_PI;
+        return vec3(1. / M_PI);
+    }
+    return evaluateBsdf(material, n, wo, wi, pdf);
+}
+
+// disks and quads only give off light from their front
+float localSurfaceArea(uint type) {
+    if (type == OBJECT_DISK) {
+        return M_PI;
+    } else if (type == OBJECT_QUAD) {
+        return 4.0;
+    }
+    return type == OBJECT_SPHERE ? 4.0 * M_PI : 24.0;
+}
+
+// normal of the local surface of an object at p
+vec3 localSurfaceNormal(uint type, vec3 p) {
+    if (type == OBJECT_DISK || type == OBJECT_QUAD) {
+        return vec3(0, 1, 0);
+    }
+    return type == OBJECT_SPHERE ? normalize(p) : localBoxNormal(p);
+}
+
+// uniformly distributed point on the surface of a unit sphere, box, disk or quad in local space
+vec3 sampleLocalSurface(uint type, out vec3 localNormal) {
+    if (type == OBJECT_SPHERE) {
+        localNormal = randomUnitVector();
+        return localNormal;
+    }
+    if (type == OBJECT_DISK) {
+        float r = sqrt(random());
+        float phi = 2. * M_PI * random();
+        localNormal = vec3(0, 1, 0);
+        return vec3(r * cos(phi), 0, r * sin(phi));
+    }
+    if (type == OBJECT_QUAD) {
+        vec2 p = vec2(random(), random()) * 2. - 1.;
+        localNormal = vec3(0, 1, 0);
+        return vec3(p.x, 0, p.y);
+    }
+
+    int face = min(int(random() * 6.0), 5);
+    int axis = face / 2;
+    float side = (face % 2 == 0) ? 1.0 : -1.0;
+
+    vec3 p = vec3(random(), random(), random()) * 2.0 - 1.0;
+    p[axis] = side;
+    localNormal = vec3(0);
+    localNormal[axis] = side;
+    return p;
+}
+
+// density per world space area of a point sampled uniformly over the local surface
+float lightAreaPdf(mat4 modelMatrix, uint type, vec3 localNormal, out vec3 worldNormal) {
+    mat3 a = mat3(modelMatrix);
+    vec3 n = transpose(a) * localNormal;
+    worldNormal = normalize(n);
+    return abs(determinant(a)) / (localSurfaceArea(type) * length(n));
+}
+
+// solid angle density of sampleLights picking the point p on the light objects[i] when looking from ro
+float lightPdf(int i, vec3 ro, vec3 p) {
+    mat4 modelMatrix = objects[i].worldToLocal;
+    vec3 localPoint = (modelMatrix * vec4(p, 1.0)).xyz;
+    vec3 localNormal = localSurfaceNormal(objects[i].type, localPoint);
+
+    vec3 lightNormal;
+    float areaPdf = lightAreaPdf(modelMatrix, objects[i].type, localNormal, lightNormal);
+
+    vec3 toLight = p - ro;
+    float dist2 = dot(toLight, toLight);
+    float cosLight = abs(dot(lightNormal, toLight)) / sqrt(dist2);
+
+    return areaPdf * dist2 / max(cosLight, 1e-6) / float(lightCount);
+}
+
+// direct light scattered towards wo at p from a randomly picked emissive object
+vec3 sampleLights(vec3 p, vec3 n, vec3 wo, Material material, uint medium) {
+    uint light = min(uint(random() * float(lightCount)), lightCount - 1);
+    int i = int(lightIndices[light]);
+    mat4 modelMatrix = objects[i].worldToLocal;
+
+    vec3 localNormal;
+    vec3 localPoint = sampleLocalSurface(objects[i].type, localNormal);
+
+    vec3 lightNormal;
+    float areaPdf = lightAreaPdf(modelMatrix, objects[i].type, localNormal, lightNormal);
+    vec3 lightPoint = (inverse(modelMatrix) * vec4(localPoint, 1.0)).xyz;
+
+    vec3 toLight = lightPoint - p;
+    float dist2 = dot(toLight, toLight);
+    vec3 wi = toLight / sqrt(dist2);
+
+    float cosSurface = scatteringCosine(n, wi);
+    float cosLight = dot(lightNormal, -wi);
+    if (cosSurface <= 0. || cosLight <= 0.) {
+        return vec3(0);
+    }
+
+    // the first thing along the shadow ray has to be the light itself
+    HitInfo shadow = hitWorld(p + n * 1e-4, wi);
+    if (!shadow.didHit || shadow.objectIndex != i) {
+        return vec3(0);
+    }
+
+    float pdf = areaPdf * dist2 / cosLight / float(lightCount);
+    float bsdfPdf;
+    vec3 bsdf = evaluateScattering(material, n, wo, wi, medium, bsdfPdf);
+
+    return bsdf * cosSurface * shadow.material.emission * mediumTransmittance(medium, shadow.dist) * powerHeuristic(pdf, bsdfPdf) / pdf;
+}
+
+// smoothly goes from 0 at the outer cone to 1 at the inner one
+float spotFalloff(GpuLight light, vec3 fromLight) {
+    float t = clamp((dot(fromLight, light.direction) - light.cosOuter) / max(light.cosInner - light.cosOuter, 1e-4), 0., 1.);
+    return t * t * (3. - 2. * t);
+}
+
+// direct light scattered towards wo at p from every point, spot and directional light
+vec3 sampleAnalyticLights(vec3 p, vec3 n, vec3 wo, Material material, uint medium) {
+    vec3 result = vec3(0);
+
+    for (uint i = 0u; i < analyticLightCount; i++) {
+        GpuLight light = analyticLights[i];
+
+        vec3 wi;
+        float dist;
+        // irradiance on a surface facing the light
+        vec3 irradiance;
+        if (light.type == LIGHT_DIRECTIONAL) {
+            wi = sampleCone(-light.direction, light.cosOuter);
+            dist = MAX_DIST;
+            irradiance = light.intensity;
+        } else {
+            vec3 toLight = light.position - p;
+            float dist2 = dot(toLight, toLight);
+            dist = sqrt(dist2);
+            wi = toLight / dist;
+            irradiance = light.intensity / dist2;
+            if (light.type == LIGHT_SPOT) {
+                irradiance *= spotFalloff(light, -wi);
+            }
+        }
+
+        float cosSurface = scatteringCosine(n, wi);
+        if (cosSurface <= 0. || irradiance == vec3(0)) {
+            continue;
+        }
+
+        HitInfo shadow = hitWorld(p + n * 1e-4, wi);
+        if (shadow.didHit && shadow.dist < dist) {
+            continue;
+        }
+
+        float bsdfPdf;
+        result += evaluateScattering(material, n, wo, wi, medium, bsdfPdf) * irradiance * cosSurface * mediumTransmittance(medium, dist);
+    }
+
+    return result;
+}
+
+// light scattered towards wo at p from a point on the environment map, which has to exist
+vec3 sampleEnvironment(vec3 p, vec3 n, vec3 wo, Material material, uint medium) {
+    float pdf;
+    vec3 wi = sampleEnvironmentDirection(pdf);
+
+    float cosSurface = scatteringCosine(n, wi);
+    if (pdf <= 0. || cosSurface <= 0.) {
+        return vec3(0);
+    }
+
+    if (hitWorld(p + n * 1e-4, wi).didHit) {
+        return vec3(0);
+    }
+
+    float bsdfPdf;
+    vec3 bsdf = evaluateScattering(material, n, wo, wi, medium, bsdfPdf);
+
+    return bsdf * cosSurface * skyBox(wi) * mediumTransmittance(medium, MAX_DIST) * powerHeuristic(pdf, bsdfPdf) / pdf;
+}
+
+// light scattered towards wo at p from a point on the sun disk, which has to be up
+vec3 sampleSun(vec3 p, vec3 n, vec3 wo, Material material, uint medium) {
+    vec3 wi = sampleCone(sky.sunDirection, sky.cosSunRadius);
+
+    float cosSurface = scatteringCosine(n, wi);
+    if (cosSurface <= 0.) {
+        return vec3(0);
+    }
+
+    if (hitWorld(p + n * 1e-4, wi).didHit) {
+        return vec3(0);
+    }
+
+    float pdf = 1. / sky.sunSolidAngle;
+    float bsdfPdf;
+    vec3 bsdf = evaluateScattering(material, n, wo, wi, medium, bsdfPdf);
+
+    return bsdf * cosSurface * sky.sunRadiance * mediumTransmittance(medium, MAX_DIST) * powerHeuristic(pdf, bsdfPdf) / pdf;
+}
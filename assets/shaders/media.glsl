@@ -0,0 +1,74 @@
+// homogeneous participating media filling the scene or the inside of objects, see Medium
+
+#include "random.glsl"
+#include "scene.glsl"
+
+layout(std430, binding = 9) buffer MediumBuffer {
+    uint mediumCount;
+    // what paths start out in, or NO_MEDIUM
+    uint sceneMedium;
+    GpuMedium media[];
+};
+
+vec3 mediumExtinction(uint medium) {
+    return media[medium].scattering + media[medium].absorption;
+}
+
+// fraction of light making it dist along a straight line through medium
+vec3 mediumTransmittance(uint medium, float dist) {
+    return medium == NO_MEDIUM ? vec3(1) : exp(-mediumExtinction(medium) * dist);
+}
+
+// distance to the next scattering event in something with the given extinction, drawn for one of the color
+// channels picked at random
+float sampleFreeFlight(vec3 extinction) {
+    int channel = min(int(random() * 3.), 2);
+    float u = random();
+    return extinction[channel] > 0. ? -log(1. - u) / extinction[channel] : MAX_DIST;
+}
+
+// what the path throughput gets multiplied by for scattering after t, or for getting t further without
+// scattering when scattered is false, after drawing t from sampleFreeFlight with any of the channels
+vec3 freeFlightWeight(vec3 extinction, vec3 scattering, float t, bool scattered) {
+    vec3 transmittance = exp(-extinction * t);
+    if (scattered) {
+        vec3 density = extinction * transmittance;
+        return transmittance * scattering * 3. / (density.x + density.y + density.z);
+    }
+    return transmittance * 3. / (transmittance.x + transmittance.y + transmittance.z);
+}
+
+float sampleMediumDistance(uint medium) {
+    return sampleFreeFlight(mediumExtinction(medium));
+}
+
+vec3 mediumWeight(uint medium, float t, bool scattered) {
+    return freeFlightWeight(mediumExtinction(medium), media[medium].scattering, t, scattered);
+}
+
+// density of scattering by the angle whose cosine is cosTheta off the direction the path was going in
+float henyeyGreenstein(float cosTheta, float g) {
+    float d = 1. + g * g - 2. * g * cosTheta;
+    return (1. - g * g) / (4. * M_PI * d * sqrt(d));
+}
+
+// new direction for a path going along rd after scattering off a particle, the Henyey-Greenstein phase
+// function's own distribution, so its value and pdf cancel out
+vec3 sampleHenyeyGreenstein(vec3 rd, float g) {
+    float u = random();
+    float cosTheta;
+    if (abs(g) < 1e-3) {
+        cosTheta = 1. - 2. * u;
+    } else {
+        float s = (1. - g * g) / (1. - g + 2. * g * u);
+        cosTheta = (1. + g * g - s * s) / (2. * g);
+    }
+    float sinTheta = sqrt(max(1. - cosTheta * cosTheta, 0.));
+
+    float phi = 2. * M_PI * random();
+
+    vec3 t, b;
+    orthonormalBasis(rd, t, b);
+
+    return (t * cos(phi) + b * sin(phi)) * sinTheta + rd * cosTheta;
+}
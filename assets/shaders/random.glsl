@@ -0,0 +1,55 @@
+// PCG hash based random numbers, seed has to be set before the first call
+
+#include "common.glsl"
+
+uint seed;
+
+float random() {
+    seed = seed * 747796405u + 2891336453u;
+    uint result = ((seed >> ((seed >> 28) + 4u)) ^ seed) * 277803737u;
+    result = (result >> 22) ^ result;
+    return float(result) / 4294967295.0;
+}
+
+vec3 randomUnitVector() {
+    float theta = 2.0 * 3.14159265 * random();
+    float phi = acos(2.0 * random() - 1.0);
+    return vec3(sin(phi) * cos(theta), sin(phi) * sin(theta), cos(phi));
+}
+
+vec3 getHemisphereCosineSample(vec3 n, out float weight) {
+    float cosTheta2 = random();
+    float cosTheta = sqrt(cosTheta2);
+    float sinTheta = sqrt(1. - cosTheta2);
+
+    float phi = 2. * M_PI * random();
+
+    vec3 t, b;
+    orthonormalBasis(n, t, b);
+
+    vec3 l = (t * cos(phi) + b * sin(phi)) * sinTheta + n * cosTheta;
+
+    float pdf = (1. / M_PI) * cosTheta;
+    weight = (.5 / M_PI) / (pdf + 1e-6);
+
+    return l;
+}
+
+// uniformly distributed direction within the cone around n whose half angle has the cosine cosMax
+vec3 sampleCone(vec3 n, float cosMax) {
+    float cosTheta = 1. - random() * (1. - cosMax);
+    float sinTheta = sqrt(max(1. - cosTheta * cosTheta, 0.));
+
+    float phi = 2. * M_PI * random();
+
+    vec3 t, b;
+    orthonormalBasis(n, t, b);
+
+    return (t * cos(phi) + b * sin(phi)) * sinTheta + n * cosTheta;
+}
+
+vec2 randomInUnitDisk() {
+    float r = sqrt(random());
+    float theta = 2.0 * 3.14159265 * random();
+    return r * vec2(cos(theta), sin(theta));
+}
@@ -0,0 +1,203 @@
+// the scene's storage buffers and tracing rays through them
+
+#include "common.glsl"
+#include "intersection.glsl"
+
+#define BVH_STACK_SIZE 32
+
+// generated from src/gpu.rs
+#pragma gpu_layout
+
+layout(std430, binding = 0) buffer ObjectBuffer {
+    GpuHeader header;
+    GpuObject objects[];
+};
+
+// indices into objects of every sphere and box with an emissive material
+layout(std430, binding = 1) buffer LightBuffer {
+    uint lightCount;
+    uint lightIndices[];
+};
+
+// every mesh's vertices, 8 floats each: position, normal and uv
+layout(std430, binding = 2) buffer MeshVertexBuffer {
+    float meshVertices[];
+};
+
+// three indices into meshVertices per triangle
+layout(std430, binding = 3) buffer MeshIndexBuffer {
+    uint meshIndices[];
+};
+
+layout(std430, binding = 4) buffer BvhBuffer {
+    uint bvhNodeCount;
+    GpuBvhNode bvhNodes[];
+};
+
+layout(std430, binding = 5) buffer MaterialBuffer {
+    GpuMaterial materials[];
+};
+
+// point, spot and directional lights, which paths can't hit
+layout(std430, binding = 6) buffer AnalyticLightBuffer {
+    uint analyticLightCount;
+    GpuLight analyticLights[];
+};
+
+vec3 getMeshPosition(uint v) {
+    return vec3(meshVertices[v * 8u], meshVertices[v * 8u + 1u], meshVertices[v * 8u + 2u]);
+}
+
+vec3 getMeshNormal(uint v) {
+    return vec3(meshVertices[v * 8u + 3u], meshVertices[v * 8u + 4u], meshVertices[v * 8u + 5u]);
+}
+
+vec2 getMeshUv(uint v) {
+    return vec2(meshVertices[v * 8u + 6u], meshVertices[v * 8u + 7u]);
+}
+
+HitInfo hitMesh(vec3 ro, vec3 rd, uint firstIndex, uint triangleCount) {
+    HitInfo info;
+    info.didHit = false;
+    info.dist = MAX_DIST;
+
+    for (uint t = 0u; t < triangleCount; t++) {
+        uint i0 = meshIndices[firstIndex + t * 3u];
+        uint i1 = meshIndices[firstIndex + t * 3u + 1u];
+        uint i2 = meshIndices[firstIndex + t * 3u + 2u];
+
+        vec3 v0 = getMeshPosition(i0);
+        vec3 e1 = getMeshPosition(i1) - v0;
+        vec3 e2 = getMeshPosition(i2) - v0;
+
+        // Moller-Trumbore
+        vec3 p = cross(rd, e2);
+        float det = dot(e1, p);
+        if (abs(det) < 1e-9) {
+            continue;
+        }
+
+        float invDet = 1.0 / det;
+        vec3 s = ro - v0;
+        float u = dot(s, p) * invDet;
+        if (u < 0.0 || u > 1.0) {
+            continue;
+        }
+
+        vec3 q = cross(s, e1);
+        float v = dot(rd, q) * invDet;
+        if (v < 0.0 || u + v > 1.0) {
+            continue;
+        }
+
+        float dist = dot(e2, q) * invDet;
+        if (dist < 0.001 || dist >= info.dist) {
+            continue;
+        }
+
+        vec3 geometricNormal = cross(e1, e2);
+        vec3 smoothNormal = normalize((1.0 - u - v) * getMeshNormal(i0) + u * getMeshNormal(i1) + v * getMeshNormal(i2));
+        // keep the interpolated normal on the same side as the triangle so winding decides what's outside
+        vec3 outwardNormal = dot(smoothNormal, geometricNormal) < 0. ? -smoothNormal : smoothNormal;
+
+        info.didHit = true;
+        info.dist = dist;
+        info.frontFace = dot(rd, geometricNormal) < 0.;
+        info.normal = info.frontFace ? outwardNormal : -outwardNormal;
+
+        vec2 uv0 = getMeshUv(i0);
+        vec2 duv1 = getMeshUv(i1) - uv0;
+        vec2 duv2 = getMeshUv(i2) - uv0;
+        info.uv = uv0 + u * duv1 + v * duv2;
+        // solves e1 = tangent * duv1.x + bitangent * duv1.y and the same for e2, any edge will do without uvs
+        float uvDet = duv1.x * duv2.y - duv2.x * duv1.y;
+        info.tangent = abs(uvDet) > 1e-12 ? (e1 * duv2.y - e2 * duv1.y) / uvDet : e1;
+    }
+
+    return info;
+}
+
+Material getMaterial(int i) {
+    GpuMaterial material = materials[objects[i].material];
+    return Material(material.baseColor, material.metallic, material.roughness, material.specular, material.clearcoat, material.clearcoatRoughness,
+        material.sheen, material.subsurface, material.subsurfaceColor, material.meanFreePath, material.transmission, material.ior, material.absorption, material.emission, material.albedoTexture, material.roughnessTexture, material.metalnessTexture, material.normalTexture,
+        material.medium);
+}
+
+// intersects objects[i], replacing info if it's closer
+void hitObject(int i, vec3 ro, vec3 rd, inout HitInfo info) {
+    mat4 modelMatrix = objects[i].worldToLocal;
+
+    vec3 transformedRo = vec3(modelMatrix * vec4(ro, 1.0));
+    vec3 transformedRd = vec3(modelMatrix * vec4(rd, 0.0));
+
+    HitInfo tempInfo;
+    uint type = objects[i].type;
+    if (type == OBJECT_SPHERE) {
+        tempInfo = hitSphere(transformedRo, transformedRd, vec3(0), 1.0);
+    } else if (type == OBJECT_BOX) {
+        tempInfo = hitBox(transformedRo, transformedRd, vec3(1));
+    } else if (type == OBJECT_PLANE) {
+        tempInfo = hitPlane(transformedRo, transformedRd);
+    } else if (type == OBJECT_DISK) {
+        tempInfo = hitDisk(transformedRo, transformedRd);
+    } else if (type == OBJECT_QUAD) {
+        tempInfo = hitQuad(transformedRo, transformedRd);
+    } else if (type == OBJECT_MESH) {
+        if (hitBounds(transformedRo, transformedRd, objects[i].boundsMin.xyz, objects[i].boundsMax.xyz, info.dist)) {
+            tempInfo = hitMesh(transformedRo, transformedRd, objects[i].firstIndex, objects[i].triangleCount);
+        } else {
+            tempInfo.didHit = false;
+        }
+    } else {
+        return;
+    }
+
+    // the ray is only transformed affinely, so dist is already along the world space ray
+    if (tempInfo.didHit && tempInfo.dist < info.dist) {
+        info = tempInfo;
+        info.normal = normalize((transpose(modelMatrix) * vec4(tempInfo.normal, 0.0)).xyz);
+        info.material = getMaterial(i);
+        info.objectIndex = i;
+    }
+}
+
+HitInfo hitWorld(vec3 ro, vec3 rd) {
+    HitInfo info;
+    info.didHit = false;
+    info.dist = MAX_DIST;
+
+    if (bvhNodeCount == 0u) {
+        return info;
+    }
+
+    uint stack[BVH_STACK_SIZE];
+    int stackSize = 1;
+    stack[0] = 0u;
+
+    while (stackSize > 0) {
+        uint node = stack[--stackSize];
+
+        if (!hitBounds(ro, rd, bvhNodes[node].boundsMin, bvhNodes[node].boundsMax, info.dist)) {
+            continue;
+        }
+
+        uint count = bvhNodes[node].count;
+        if (count == 0u) {
+            // left child is right after its parent, visit it first. The stack holds at most one entry per level
+            // plus one, and Bvh::build splits at the median object so there are only log2 of the object count
+            // levels, the guard is only reached with billions of objects
+            if (stackSize + 2 <= BVH_STACK_SIZE) {
+                stack[stackSize++] = bvhNodes[node].offset;
+                stack[stackSize++] = node + 1u;
+            }
+            continue;
+        }
+
+        for (uint j = 0u; j < count; j++) {
+            hitObject(int(bvhNodes[node].offset + j), ro, rd, info);
+        }
+    }
+
+    return info;
+}
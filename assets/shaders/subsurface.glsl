@@ -0,0 +1,46 @@
+// subsurface scattering by random walking through the inside of closed objects until the path gets back out
+
+#include "random.glsl"
+#include "scene.glsl"
+#include "media.glsl"
+
+// scattering events a walk gives up after, the path is lost as if it had been absorbed
+#define MAX_WALK_STEPS 64
+
+// single scattering albedo that makes a thick slab reflect color, van de Hulst's fit inverted, for .5 of it like
+// the diffuse lobe reflects
+vec3 subsurfaceAlbedo(vec3 color) {
+    vec3 a = clamp(.5 * color, 0., .999);
+    vec3 s = 4.09712 + 4.20863 * a - sqrt(9.59217 + 41.6808 * a + 17.7126 * a * a);
+    return 1. - s * s;
+}
+
+// follows a path that entered m at ro along rd until it leaves the object again, through whatever surface hitWorld
+// finds first. On success ro is where it got out, exitNormal the surface normal facing outside and att includes
+// everything lost on the way
+bool randomWalk(Material m, inout vec3 ro, inout vec3 rd, inout vec3 att, out vec3 exitNormal) {
+    vec3 extinction = 1. / m.meanFreePath;
+    vec3 scattering = subsurfaceAlbedo(m.subsurfaceColor) * extinction;
+
+    for (int i = 0; i < MAX_WALK_STEPS; i++) {
+        HitInfo info = hitWorld(ro, rd);
+        if (!info.didHit) {
+            // an open mesh, nothing to bring the path back
+            return false;
+        }
+
+        float t = sampleFreeFlight(extinction);
+        if (t >= info.dist) {
+            att *= freeFlightWeight(extinction, scattering, info.dist, false);
+            ro += rd * info.dist;
+            exitNormal = dot(rd, info.normal) > 0. ? info.normal : -info.normal;
+            return true;
+        }
+
+        att *= freeFlightWeight(extinction, scattering, t, true);
+        ro += rd * t;
+        rd = randomUnitVector();
+    }
+
+    return false;
+}
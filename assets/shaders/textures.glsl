@@ -0,0 +1,61 @@
+// image textures modulating materials, one layer of a texture array each
+
+#include "scene.glsl"
+
+// RGBA8 layers of the same size with linear filtering and repeat wrapping, see TextureBuffer
+uniform sampler2DArray materialTextures;
+
+// layers are stored top row first while v points up
+vec4 sampleMaterialTexture(uint layer, vec2 uv) {
+    return textureLod(materialTextures, vec3(uv.x, 1. - uv.y, float(layer)), 0.);
+}
+
+bool hasTextures(Material material) {
+    return material.albedoTexture != NO_TEXTURE || material.roughnessTexture != NO_TEXTURE
+        || material.metalnessTexture != NO_TEXTURE || material.normalTexture != NO_TEXTURE;
+}
+
+// replaces the material's constants and the normal at p with what its textures say
+void applyTextures(inout HitInfo info, vec3 p) {
+    mat4 worldToLocal = objects[info.objectIndex].worldToLocal;
+    uint type = objects[info.objectIndex].type;
+    if (type == OBJECT_SPHERE) {
+        info.uv = sphereUv((worldToLocal * vec4(p, 1.0)).xyz, info.tangent);
+    } else if (type == OBJECT_BOX) {
+        info.uv = boxUv((worldToLocal * vec4(p, 1.0)).xyz, info.tangent);
+    } else if (type == OBJECT_PLANE || type == OBJECT_DISK || type == OBJECT_QUAD) {
+        info.uv = planeUv((worldToLocal * vec4(p, 1.0)).xyz, info.tangent);
+    }
+
+    if (info.material.albedoTexture != NO_TEXTURE) {
+        // albedo maps are sRGB encoded, the rest store linear values
+        vec3 albedo = pow(sampleMaterialTexture(info.material.albedoTexture, info.uv).rgb, vec3(2.2));
+        info.material.baseColor *= albedo;
+        info.material.subsurfaceColor *= albedo;
+    }
+    if (info.material.roughnessTexture != NO_TEXTURE) {
+        info.material.roughness = sampleMaterialTexture(info.material.roughnessTexture, info.uv).g;
+    }
+    if (info.material.metalnessTexture != NO_TEXTURE) {
+        info.material.metallic = sampleMaterialTexture(info.material.metalnessTexture, info.uv).b;
+    }
+    if (info.material.normalTexture != NO_TEXTURE) {
+        vec3 outwardNormal = info.frontFace ? info.normal : -info.normal;
+
+        // tangents are carried along by the object's transform, unlike normals
+        vec3 t = inverse(mat3(worldToLocal)) * info.tangent;
+        t -= outwardNormal * dot(t, outwardNormal);
+        // the poles of a sphere have no tangent, any direction along the surface will do there
+        if (dot(t, t) > 1e-12) {
+            t = normalize(t);
+        } else {
+            vec3 unused;
+            orthonormalBasis(outwardNormal, t, unused);
+        }
+        vec3 b = cross(outwardNormal, t);
+
+        vec3 m = sampleMaterialTexture(info.material.normalTexture, info.uv).xyz * 2. - 1.;
+        vec3 mapped = normalize(t * m.x + b * m.y + outwardNormal * m.z);
+        info.normal = info.frontFace ? mapped : -mapped;
+    }
+}
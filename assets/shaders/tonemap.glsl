@@ -0,0 +1,10 @@
+// maps linear radiance into 0 to 1 before gamma correction
+
+vec3 Tonemap_ACES(vec3 x) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return (x * (a * x + b)) / (x * (c * x + d) + e);
+}
@@ -0,0 +1,219 @@
+// everything the fragment and compute tracers share, from the camera ray to the accumulated pixel
+
+#define MAX_BOUNCES 10
+#define SAMPLES_PER_PIXEL 10
+
+#include "random.glsl"
+#include "scene.glsl"
+#include "environment.glsl"
+#include "lights.glsl"
+#include "textures.glsl"
+#include "media.glsl"
+#include "subsurface.glsl"
+
+uniform mat4 projectionMatrix;
+uniform mat4 viewMatrix;
+uniform vec3 cameraPosition;
+uniform float time;
+
+// average of the previous sampleCount frames, blended with this one
+uniform sampler2D accumulationTexture;
+uniform int sampleCount;
+
+vec3 getRayDirection(vec2 uv) {
+    vec4 clipSpace = vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+    vec4 eyeSpace = inverse(projectionMatrix) * clipSpace;
+    eyeSpace.z = -1.0;
+    eyeSpace.w = 0.0;
+    vec4 worldSpace = inverse(viewMatrix) * eyeSpace;
+    return normalize(worldSpace.xyz);
+}
+
+vec3 trace(vec3 rayOrigin, vec3 rayDirection) {
+    vec3 ro = rayOrigin;
+    vec3 rd = rayDirection;
+
+    vec3 col = vec3(0);
+    vec3 att = vec3(1);
+
+    // set after bounces off anything but glass, where light sampling may already have counted what the next hit emits
+    bool lightSampled = false;
+    // same for the environment map and the sun, and paths escaping the scene
+    bool environmentSampled = false;
+    bool sunSampled = false;
+    float bsdfPdf = 0.;
+    // what the path is traveling through, objects can't be nested in other objects' media
+    uint medium = sceneMedium;
+
+    for (int i = 0; i < MAX_BOUNCES; i++) {
+        HitInfo info = hitWorld(ro, rd);
+
+        if (medium != NO_MEDIUM) {
+            float t = sampleMediumDistance(medium);
+            if (t < info.dist) {
+                // scattered off a particle before getting to the surface
+                att *= mediumWeight(medium, t, true);
+                ro += rd * t;
+                vec3 wo = -rd;
+
+                // the light sampling functions take a zero normal for a particle
+                lightSampled = lightCount > 0u;
+                if (lightSampled) {
+                    col += att * sampleLights(ro, vec3(0), wo, info.material, medium);
+                }
+                col += att * sampleAnalyticLights(ro, vec3(0), wo, info.material, medium);
+                environmentSampled = hasEnvironmentMap();
+                if (environmentSampled) {
+                    col += att * sampleEnvironment(ro, vec3(0), wo, info.material, medium);
+                }
+                sunSampled = hasSun();
+                if (sunSampled) {
+                    col += att * sampleSun(ro, vec3(0), wo, info.material, medium);
+                }
+
+                float g = media[medium].anisotropy;
+                rd = sampleHenyeyGreenstein(rd, g);
+                bsdfPdf = henyeyGreenstein(dot(-wo, rd), g);
+                continue;
+            }
+            att *= mediumWeight(medium, info.dist, false);
+        }
+
+        if (!info.didHit) {
+            float weight = environmentSampled ? powerHeuristic(bsdfPdf, environmentPdf(normalize(rd))) : 1.0;
+            col += att * skyBox(rd) * weight;
+
+            float sunWeight = sunSampled ? powerHeuristic(bsdfPdf, 1. / sky.sunSolidAngle) : 1.0;
+            col += att * sunDisk(normalize(rd)) * sunWeight;
+            break;
+        }
+
+        vec3 hitPosition = ro + rd * info.dist;
+
+        if (!info.frontFace) {
+            // the path got here from inside the object, through its absorbing medium
+            att *= exp(-info.material.absorption * info.dist * length(rd));
+        }
+
+        if (hasTextures(info.material)) {
+            applyTextures(info, hitPosition);
+        }
+
+        uint type = objects[info.objectIndex].type;
+        // disks and quads only give off light from their front, the only side light sampling picks points on
+        if (info.material.emission != vec3(0) && (info.frontFace || (type != OBJECT_DISK && type != OBJECT_QUAD))) {
+            // meshes and planes aren't in the light list, so nothing else could have counted their light
+            float weight = lightSampled && type != OBJECT_MESH && type != OBJECT_PLANE ? powerHeuristic(bsdfPdf, lightPdf(info.objectIndex, ro, hitPosition)) : 1.0;
+            col += att * info.material.emission * weight;
+        }
+
+        ro = hitPosition;
+
+        vec3 wo = -normalize(rd);
+        vec3 facingNormal = dot(rd, info.normal) < 0. ? info.normal : -info.normal;
+
+        lightSampled = false;
+        environmentSampled = false;
+        sunSampled = false;
+
+        // glass only scatters through its own lobe, which light sampling doesn't cover
+        if (transmissionAmount(info.material) < 1.) {
+            if (lightCount > 0) {
+                col += att * sampleLights(ro, facingNormal, wo, info.material, medium);
+                lightSampled = true;
+            }
+            col += att * sampleAnalyticLights(ro, facingNormal, wo, info.material, medium);
+            if (hasEnvironmentMap()) {
+                col += att * sampleEnvironment(ro, facingNormal, wo, info.material, medium);
+                environmentSampled = true;
+            }
+            if (hasSun()) {
+                col += att * sampleSun(ro, facingNormal, wo, info.material, medium);
+                sunSampled = true;
+            }
+        }
+
+        BsdfSample bsdf = sampleBsdf(info.material, facingNormal, wo, info.frontFace);
+        if (bsdf.weight == vec3(0)) {
+            // the microfacet sent the path to the wrong side of the surface, nothing more can reach the camera along it
+            break;
+        }
+        if (bsdf.isSubsurface) {
+            att *= bsdf.weight;
+            rd = bsdf.direction;
+            vec3 exitNormal;
+            if (!randomWalk(info.material, ro, rd, att, exitNormal)) {
+                break;
+            }
+            // leaves diffusely wherever the walk came back out, which the light sampling functions take a zero wo for
+            lightSampled = lightCount > 0u;
+            if (lightSampled) {
+                col += att * sampleLights(ro, exitNormal, vec3(0), info.material, medium);
+            }
+            col += att * sampleAnalyticLights(ro, exitNormal, vec3(0), info.material, medium);
+            environmentSampled = hasEnvironmentMap();
+            if (environmentSampled) {
+                col += att * sampleEnvironment(ro, exitNormal, vec3(0), info.material, medium);
+            }
+            sunSampled = hasSun();
+            if (sunSampled) {
+                col += att * sampleSun(ro, exitNormal, vec3(0), info.material, medium);
+            }
+
+            float weight;
+            rd = getHemisphereCosineSample(exitNormal, weight);
+            bsdfPdf = dot(exitNormal, rd) / M_PI;
+            continue;
+        }
+        if (bsdf.isDelta) {
+            // whatever the glass lobe leads to wasn't covered by the light sampling above
+            lightSampled = false;
+            environmentSampled = false;
+            sunSampled = false;
+        }
+
+        if (dot(bsdf.direction, facingNormal) < 0.) {
+            // through the surface, into the object's medium or back out into the scene's
+            medium = info.frontFace ? info.material.medium : sceneMedium;
+        }
+
+        bsdfPdf = bsdf.pdf;
+        att *= bsdf.weight;
+        rd = bsdf.direction;
+    }
+
+    return col;
+}
+
+// traces the pixel whose center is at fragCoord, in pixels, and blends it into the accumulated image
+vec4 renderPixel(vec2 fragCoord, vec2 uv) {
+    // scene data written by a different version of the renderer, make that obvious
+    if (header.version != GPU_LAYOUT_VERSION) {
+        return vec4(1, 0, 1, 1);
+    }
+
+    seed = uint(floatBitsToInt(fragCoord.x) + floatBitsToInt(fragCoord.y * 5741.) + floatBitsToInt(time * 26717.));
+
+    vec3 color = vec3(0);
+    int samples = 0;
+
+    vec3 rayDirection = getRayDirection(uv);
+    for (int i = 0; i < SAMPLES_PER_PIXEL; i++) {
+        vec3 radiance = trace(cameraPosition, rayDirection);
+        // a single NaN or infinity would stay in the running average for good
+        if (any(isnan(radiance)) || any(isinf(radiance))) {
+            continue;
+        }
+        color += radiance;
+        samples++;
+    }
+
+    vec3 previous = sampleCount > 0 ? texelFetch(accumulationTexture, ivec2(fragCoord), 0).rgb : vec3(0);
+    if (samples == 0) {
+        return vec4(previous, 1.0);
+    }
+
+    color /= float(samples);
+
+    return vec4(mix(previous, color, 1.0 / float(sampleCount + 1)), 1.0);
+}
@@ -0,0 +1,309 @@
+use nalgebra::{Matrix4, Vector3};
+
+use crate::gpu::GpuBvhNode;
+
+/// Axis aligned bounding box in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Aabb { min, max }
+    }
+
+    /// Contains nothing, the union with any other box is that box.
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vector3::repeat(f32::INFINITY),
+            max: Vector3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    /// Bounds of this box's eight corners after being moved by `matrix`.
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        let mut bounds = Aabb::empty();
+        for corner in 0..8 {
+            let point = Vector3::new(
+                if corner & 1 == 0 { self.min.x } else { self.max.x },
+                if corner & 2 == 0 { self.min.y } else { self.max.y },
+                if corner & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            let point = matrix.transform_point(&point.into()).coords;
+            bounds.min = bounds.min.inf(&point);
+            bounds.max = bounds.max.sup(&point);
+        }
+        bounds
+    }
+}
+
+#[derive(Clone)]
+enum NodeKind {
+    Leaf { object: usize },
+    // the left child always directly follows its parent
+    Internal { right: usize },
+}
+
+#[derive(Clone)]
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+/// Bounding volume hierarchy with one scene object per leaf.
+///
+/// Objects with empty bounds, like groups without children, are left out.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    // root surface area right after the last build, refitting past a few times this rebuilds instead
+    built_surface_area: f32,
+}
+
+impl Bvh {
+    /// Sorts by center along the largest axis of the centers and splits at the median object, until every leaf
+    /// holds one object.
+    ///
+    /// Median splits keep the tree balanced, `log2` of the object count deep, which the shader's traversal
+    /// stack relies on.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut objects: Vec<usize> = (0..bounds.len()).filter(|&i| !bounds[i].is_empty()).collect();
+        let mut nodes = Vec::with_capacity(objects.len() * 2);
+
+        if !objects.is_empty() {
+            build_node(&mut nodes, bounds, &mut objects);
+        }
+
+        let built_surface_area = nodes.first().map_or(0.0, |node| node.bounds.surface_area());
+        Bvh {
+            nodes,
+            built_surface_area,
+        }
+    }
+
+    /// Number of objects the hierarchy was built over, not counting empty ones.
+    pub fn leaf_count(&self) -> usize {
+        self.nodes.iter().filter(|node| matches!(node.kind, NodeKind::Leaf { .. })).count()
+    }
+
+    /// Updates the node bounds for objects that moved, keeping the tree structure.
+    ///
+    /// Returns `false` without changing anything if the tree doesn't fit `bounds` anymore or would
+    /// get too loose, in which case it should be rebuilt.
+    pub fn refit(&mut self, bounds: &[Aabb]) -> bool {
+        let leaves_match = self.nodes.iter().all(|node| match node.kind {
+            NodeKind::Leaf { object } => bounds.get(object).is_some_and(|b| !b.is_empty()),
+            NodeKind::Internal { .. } => true,
+        });
+        if !leaves_match || self.leaf_count() != bounds.iter().filter(|b| !b.is_empty()).count() {
+            return false;
+        }
+
+        let mut nodes = self.nodes.clone();
+        // children always come after their parent, so walking backwards updates them first
+        for i in (0..nodes.len()).rev() {
+            nodes[i].bounds = match nodes[i].kind {
+                NodeKind::Leaf { object } => bounds[object],
+                NodeKind::Internal { right } => nodes[i + 1].bounds.union(&nodes[right].bounds),
+            };
+        }
+
+        if let Some(root) = nodes.first() {
+            if root.bounds.surface_area() > self.built_surface_area * 4.0 {
+                return false;
+            }
+        }
+
+        self.nodes = nodes;
+        true
+    }
+
+    /// Flattens the tree for the shader. `ranges` holds the index of each object's first
+    /// `GpuObject` and how many it was written as.
+    pub fn get_gpu_data(&self, ranges: &[(u32, u32)]) -> Vec<GpuBvhNode> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let (offset, count) = match node.kind {
+                    NodeKind::Leaf { object } => ranges[object],
+                    NodeKind::Internal { right } => (right as u32, 0),
+                };
+                GpuBvhNode {
+                    bounds_min: node.bounds.min.into(),
+                    offset,
+                    bounds_max: node.bounds.max.into(),
+                    count,
+                }
+            })
+            .collect()
+    }
+}
+
+fn build_node(nodes: &mut Vec<Node>, bounds: &[Aabb], objects: &mut [usize]) -> usize {
+    let index = nodes.len();
+    let node_bounds = objects.iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i]));
+
+    if let [object] = objects {
+        nodes.push(Node {
+            bounds: node_bounds,
+            kind: NodeKind::Leaf { object: *object },
+        });
+        return index;
+    }
+
+    let centers = objects.iter().fold(Aabb::empty(), |b, &i| {
+        let center = bounds[i].center();
+        b.union(&Aabb::new(center, center))
+    });
+    let axis = (centers.max - centers.min).imax();
+
+    objects.sort_by(|&a, &b| bounds[a].center()[axis].total_cmp(&bounds[b].center()[axis]));
+    let (left, right) = objects.split_at_mut(objects.len() / 2);
+
+    // placeholder until we know where the right child ends up
+    nodes.push(Node {
+        bounds: node_bounds,
+        kind: NodeKind::Internal { right: 0 },
+    });
+    build_node(nodes, bounds, left);
+    let right = build_node(nodes, bounds, right);
+    nodes[index].kind = NodeKind::Internal { right };
+
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // unit cube centered on `center`
+    fn cube(center: Vector3<f32>) -> Aabb {
+        Aabb::new(center - Vector3::repeat(0.5), center + Vector3::repeat(0.5))
+    }
+
+    fn scattered_cubes() -> Vec<Aabb> {
+        [[0.0, 0.0, 0.0], [3.0, 1.0, 0.0], [-2.0, 4.0, 1.0], [5.0, -3.0, 2.0], [1.0, 1.0, -6.0], [-4.0, -1.0, 3.0], [2.0, 2.0, 2.0]]
+            .iter()
+            .map(|&center| cube(center.into()))
+            .collect()
+    }
+
+    fn one_each(bounds: &[Aabb]) -> Vec<(u32, u32)> {
+        (0..bounds.len() as u32).map(|i| (i, 1)).collect()
+    }
+
+    fn contains(outer: &GpuBvhNode, inner: &GpuBvhNode) -> bool {
+        (0..3).all(|i| outer.bounds_min[i] <= inner.bounds_min[i] && outer.bounds_max[i] >= inner.bounds_max[i])
+    }
+
+    fn assert_children_contained(nodes: &[GpuBvhNode]) {
+        for (i, node) in nodes.iter().enumerate().filter(|(_, node)| node.count == 0) {
+            assert!(contains(node, &nodes[i + 1]), "node {} doesn't contain its left child", i);
+            assert!(contains(node, &nodes[node.offset as usize]), "node {} doesn't contain its right child", i);
+        }
+    }
+
+    #[test]
+    fn leaves_hold_every_object_once() {
+        let mut bounds = scattered_cubes();
+        // empty groups are left out
+        bounds.insert(2, Aabb::empty());
+
+        let nodes = Bvh::build(&bounds).get_gpu_data(&one_each(&bounds));
+        let mut leaves: Vec<u32> = nodes.iter().filter(|node| node.count > 0).map(|node| node.offset).collect();
+        leaves.sort();
+
+        assert_eq!(leaves, [0, 1, 3, 4, 5, 6, 7]);
+        assert_eq!(nodes.len(), 2 * leaves.len() - 1);
+    }
+
+    #[test]
+    fn nodes_contain_their_children() {
+        let bounds = scattered_cubes();
+        assert_children_contained(&Bvh::build(&bounds).get_gpu_data(&one_each(&bounds)));
+    }
+
+    #[test]
+    fn refit_grows_the_ancestors() {
+        let mut bounds = scattered_cubes();
+        let mut bvh = Bvh::build(&bounds);
+
+        bounds[0] = cube(Vector3::new(0.0, 0.0, 4.0));
+        assert!(bvh.refit(&bounds));
+
+        let nodes = bvh.get_gpu_data(&one_each(&bounds));
+        assert_children_contained(&nodes);
+        assert_eq!(nodes[0].bounds_max[2], 4.5);
+        let leaf = nodes.iter().find(|node| node.count > 0 && node.offset == 0).unwrap();
+        assert_eq!(leaf.bounds_min, [-0.5, -0.5, 3.5]);
+    }
+
+    #[test]
+    fn refit_gives_up_on_a_much_larger_root() {
+        let mut bounds = scattered_cubes();
+        let mut bvh = Bvh::build(&bounds);
+        let built = bvh.get_gpu_data(&one_each(&bounds));
+
+        // the root grows from 10x8x10 to 10x8x60, past four times its surface area
+        bounds[0] = cube(Vector3::new(0.0, 0.0, 53.5));
+        assert!(!bvh.refit(&bounds));
+        assert_eq!(bvh.get_gpu_data(&one_each(&bounds)), built);
+
+        // a different set of objects can't be refit either
+        bounds.pop();
+        assert!(!bvh.refit(&bounds));
+    }
+
+    #[test]
+    fn no_objects_make_no_nodes() {
+        let mut bvh = Bvh::build(&[]);
+        assert_eq!(bvh.leaf_count(), 0);
+        assert!(bvh.get_gpu_data(&[]).is_empty());
+        assert!(bvh.refit(&[]));
+
+        let bounds = [Aabb::empty(), Aabb::empty()];
+        let bvh = Bvh::build(&bounds);
+        assert!(bvh.get_gpu_data(&one_each(&bounds)).is_empty());
+    }
+
+    #[test]
+    fn leaves_point_at_their_objects_range() {
+        let bounds = scattered_cubes()[..3].to_vec();
+        // the second object is a group written as three GpuObjects
+        let ranges = [(0, 1), (1, 3), (4, 1)];
+
+        let nodes = Bvh::build(&bounds).get_gpu_data(&ranges);
+        let mut leaves: Vec<(u32, u32)> = nodes.iter().filter(|node| node.count > 0).map(|node| (node.offset, node.count)).collect();
+        leaves.sort();
+
+        assert_eq!(leaves, ranges);
+    }
+}
@@ -71,12 +71,7 @@ impl Camera {
         self.euler_angle.y += yoffset;
 
         if constrain_pitch {
-            if self.euler_angle.y > 89.0 {
-                self.euler_angle.y = 89.0;
-            }
-            if self.euler_angle.y < -89.0 {
-                self.euler_angle.y = -89.0;
-            }
+            self.euler_angle.y = self.euler_angle.y.clamp(-89.0, 89.0);
         }
 
         self.update_camera_vectors();
@@ -1,3 +1,5 @@
+use crate::bvh::Aabb;
+use crate::gpu::GpuScene;
 use crate::objects::Object;
 use crate::transform::Transform;
 
@@ -7,29 +9,29 @@ pub struct CompoundObject {
 }
 
 impl Object for CompoundObject {
-    fn get_gpu_data(&self) -> Vec<u32> {
-        let mut data = Vec::new();
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
         for object in &self.objects {
-            let transform = self.transform.clone() * object.get_transform().clone();
-            let mut object_data = object.get_gpu_data_custom_transform(&transform);
-            data.append(&mut object_data);
+            let transform1 = transform.clone() * object.get_transform().clone();
+            object.write_gpu_data_custom_transform(&transform1, scene);
         }
-        data
     }
 
-    fn get_gpu_data_custom_transform(&self, transform: &Transform) -> Vec<u32> {
-        let mut data = Vec::new();
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        let mut bounds = Aabb::empty();
         for object in &self.objects {
             let transform1 = transform.clone() * object.get_transform().clone();
-            let mut object_data = object.get_gpu_data_custom_transform(&transform1);
-            data.append(&mut object_data);
+            bounds = bounds.union(&object.bounds_custom_transform(&transform1));
         }
-        data
+        bounds
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
 }
 
 impl CompoundObject {
@@ -0,0 +1,125 @@
+use nalgebra::Vector3;
+
+use std::f32::consts::PI;
+use std::path::Path;
+
+use crate::gpu::GpuEnvironment;
+
+/// Equirectangular HDR image surrounding the scene, seen wherever paths escape and sampled as a light.
+///
+/// The middle of the image looks along -z with +x to its right, the top row looks straight up.
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    pixels: Vec<[f32; 3]>,
+    cdf: Vec<f32>,
+    /// Turns the image around +y, in degrees.
+    pub rotation: f32,
+    /// Multiplies the image's radiance.
+    pub intensity: f32,
+}
+
+impl EnvironmentMap {
+    /// `pixels` is linear RGB radiance, top row first.
+    pub fn new(width: u32, height: u32, pixels: Vec<[f32; 3]>) -> Result<Self, String> {
+        if pixels.len() != width as usize * height as usize {
+            return Err(format!(
+                "A {}x{} environment map needs {} pixels, got {}",
+                width,
+                height,
+                width as usize * height as usize,
+                pixels.len()
+            ));
+        }
+
+        let cdf = build_cdf(width as usize, height as usize, &pixels);
+
+        Ok(EnvironmentMap {
+            width,
+            height,
+            pixels,
+            cdf,
+            rotation: 0.0,
+            intensity: 1.0,
+        })
+    }
+
+    /// Loads a Radiance `.hdr` file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Failed to load environment map {}: {}", path.display(), e))?
+            .into_rgb32f();
+
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|p| p.0).collect();
+
+        Self::new(width, height, pixels).map_err(|e| format!("Failed to load environment map {}: {}", path.display(), e))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Linear RGB radiance, top row first.
+    pub fn pixels(&self) -> &[[f32; 3]] {
+        &self.pixels
+    }
+
+    /// For picking pixels in proportion to how much light they give off.
+    ///
+    /// Every row's running sum of its pixels' weights, normalized to end at 1, followed by the same for
+    /// the rows' totals. A pixel's weight is its luminance times the solid angle it covers.
+    pub fn cdf(&self) -> &[f32] {
+        &self.cdf
+    }
+
+    pub fn gpu_header(&self) -> GpuEnvironment {
+        GpuEnvironment {
+            width: self.width,
+            height: self.height,
+            rotation: self.rotation.to_radians(),
+            intensity: self.intensity,
+        }
+    }
+}
+
+fn luminance(color: [f32; 3]) -> f32 {
+    Vector3::from(color).dot(&Vector3::new(0.2126, 0.7152, 0.0722))
+}
+
+// normalizes a running sum in place, spreading it evenly if everything is zero so every entry can still be picked
+fn normalize_running_sum(sums: &mut [f32]) {
+    let total = *sums.last().unwrap();
+    let count = sums.len() as f32;
+    for (i, sum) in sums.iter_mut().enumerate() {
+        *sum = if total > 0.0 { *sum / total } else { (i + 1) as f32 / count };
+    }
+}
+
+fn build_cdf(width: usize, height: usize, pixels: &[[f32; 3]]) -> Vec<f32> {
+    let mut cdf = Vec::with_capacity(width * height + height);
+    let mut row_totals = Vec::with_capacity(height);
+
+    for y in 0..height {
+        // rows near the poles cover less of the sphere
+        let sin_theta = ((y as f32 + 0.5) / height as f32 * PI).sin();
+
+        let mut sum = 0.0;
+        let row_start = cdf.len();
+        for pixel in &pixels[y * width..(y + 1) * width] {
+            sum += luminance(*pixel).max(0.0) * sin_theta;
+            cdf.push(sum);
+        }
+
+        row_totals.push(row_totals.last().copied().unwrap_or(0.0) + sum);
+        normalize_running_sum(&mut cdf[row_start..]);
+    }
+
+    normalize_running_sum(&mut row_totals);
+    cdf.extend(row_totals);
+    cdf
+}
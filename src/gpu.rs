@@ -0,0 +1,485 @@
+//! Layout of the storage buffers the path tracer reads its scene from.
+//!
+//! Every struct here is `#[repr(C)]` and laid out to match std430, and `glsl_definitions`
+//! generates the GLSL side from the same field lists so the two can't drift apart.
+
+use nalgebra::Vector3;
+
+use std::mem;
+
+use crate::light::Light;
+use crate::medium::Medium;
+use crate::objects::Material;
+use crate::transform::Transform;
+
+/// Bumped whenever any of the structs below change, the shader refuses to read other versions.
+pub const LAYOUT_VERSION: u32 = 9;
+
+/// Texture slot of a `GpuMaterial` without a texture in it.
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// Medium of a `GpuMaterial` or scene that isn't filled with any.
+pub const NO_MEDIUM: u32 = u32::MAX;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpuObjectType {
+    Sphere = 1,
+    Box = 2,
+    Mesh = 3,
+    Plane = 4,
+    Disk = 5,
+    Quad = 6,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpuLightType {
+    Point = 1,
+    Spot = 2,
+    Directional = 3,
+}
+
+/// Sits in front of the objects in the object buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuHeader {
+    pub version: u32,
+    pub object_count: u32,
+    /// Size of a `GpuObject` in bytes.
+    pub object_stride: u32,
+    pub material_count: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuMaterial {
+    pub base_color: [f32; 3],
+    pub roughness: f32,
+    /// Already multiplied by the material's emission strength.
+    pub emission: [f32; 3],
+    pub ior: f32,
+    pub absorption: [f32; 3],
+    pub metallic: f32,
+    pub subsurface_color: [f32; 3],
+    pub subsurface: f32,
+    pub mean_free_path: [f32; 3],
+    pub specular: f32,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub sheen: f32,
+    pub transmission: f32,
+    /// Layers of the material texture array, or `NO_TEXTURE`.
+    pub albedo_texture: u32,
+    pub roughness_texture: u32,
+    pub metalness_texture: u32,
+    pub normal_texture: u32,
+    /// Index into the media table of what fills the object, or `NO_MEDIUM`.
+    pub medium: u32,
+    pub padding: [u32; 3],
+}
+
+impl GpuMaterial {
+    pub fn new(material: &Material, medium: u32) -> Self {
+        GpuMaterial {
+            base_color: material.base_color.into(),
+            roughness: material.roughness,
+            emission: (material.emission * material.emission_strength).into(),
+            ior: material.ior,
+            absorption: material.absorption.into(),
+            metallic: material.metallic,
+            subsurface_color: material.subsurface_color.into(),
+            subsurface: material.subsurface,
+            mean_free_path: material.mean_free_path.into(),
+            specular: material.specular,
+            clearcoat: material.clearcoat,
+            clearcoat_roughness: material.clearcoat_roughness,
+            sheen: material.sheen,
+            transmission: material.transmission,
+            albedo_texture: material.albedo_texture.unwrap_or(NO_TEXTURE),
+            roughness_texture: material.roughness_texture.unwrap_or(NO_TEXTURE),
+            metalness_texture: material.metalness_texture.unwrap_or(NO_TEXTURE),
+            normal_texture: material.normal_texture.unwrap_or(NO_TEXTURE),
+            medium,
+            padding: [0; 3],
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        Vector3::from(self.emission).max() > 0.0
+    }
+}
+
+/// A single sphere, box, plane, disk, quad or mesh.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuObject {
+    /// Column major, maps world space into the space where spheres and boxes span -1 to 1, and planes, disks and
+    /// quads lie on y = 0 facing +y.
+    pub world_to_local: [[f32; 4]; 4],
+    /// A `GpuObjectType`.
+    pub object_type: u32,
+    /// Index into the material table.
+    pub material: u32,
+    /// Mesh only, the range of `MeshBuffer::indices` holding its triangles.
+    pub first_index: u32,
+    pub triangle_count: u32,
+    /// Mesh only, local space bounds of its triangles. `w` is unused.
+    pub bounds_min: [f32; 4],
+    pub bounds_max: [f32; 4],
+}
+
+/// A point, spot or directional light, in the analytic light buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuLight {
+    /// Unused by directional lights.
+    pub position: [f32; 3],
+    /// A `GpuLightType`.
+    pub light_type: u32,
+    /// Normalized direction the light shines in, unused by point lights.
+    pub direction: [f32; 3],
+    /// Cosine of the spot's outer cone angle, or of a directional light's angular radius.
+    pub cos_outer: f32,
+    /// Color multiplied by the intensity.
+    pub intensity: [f32; 3],
+    /// Cosine of the spot's inner cone angle, inside of which it is at full intensity.
+    pub cos_inner: f32,
+}
+
+impl GpuLight {
+    pub fn new(light: &Light) -> Self {
+        match *light {
+            Light::Point { position, color, intensity } => GpuLight {
+                position: position.into(),
+                light_type: GpuLightType::Point as u32,
+                intensity: (color * intensity).into(),
+                ..Default::default()
+            },
+            Light::Spot { position, direction, color, intensity, inner_angle, outer_angle } => GpuLight {
+                position: position.into(),
+                light_type: GpuLightType::Spot as u32,
+                direction: direction.normalize().into(),
+                cos_outer: outer_angle.to_radians().cos(),
+                // an inner cone wider than the outer one would light more than the outer cone
+                cos_inner: inner_angle.min(outer_angle).to_radians().cos(),
+                intensity: (color * intensity).into(),
+            },
+            Light::Directional { direction, color, intensity, angular_radius } => GpuLight {
+                light_type: GpuLightType::Directional as u32,
+                direction: direction.normalize().into(),
+                cos_outer: angular_radius.to_radians().cos(),
+                intensity: (color * intensity).into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Sits in front of the environment map's CDF in the environment buffer, all zeros without a map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuEnvironment {
+    pub width: u32,
+    pub height: u32,
+    /// Around +y, in radians.
+    pub rotation: f32,
+    pub intensity: f32,
+}
+
+/// Preetham sky and sun, in the sky buffer. All zeros means the scene has none.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuSky {
+    /// Unit vector pointing at the sun.
+    pub sun_direction: [f32; 3],
+    /// 1 if there is a sky, 0 falls back to the gradient.
+    pub enabled: u32,
+    /// Radiance of the sun disk after the atmosphere, zero once it has set.
+    pub sun_radiance: [f32; 3],
+    pub cos_sun_radius: f32,
+    /// Luminance, x and y chromaticity at the zenith, divided by the Perez function there.
+    pub zenith: [f32; 3],
+    pub sun_solid_angle: f32,
+    /// Perez coefficients A to E, each for luminance, x and y. `w` is unused.
+    pub perez: [[f32; 4]; 5],
+}
+
+/// Scattering and absorption coefficients of a `Medium`, in the media buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuMedium {
+    pub scattering: [f32; 3],
+    pub anisotropy: f32,
+    pub absorption: [f32; 3],
+    pub padding: u32,
+}
+
+/// Node of the flattened BVH, the root comes first and a left child always directly follows its parent.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuBvhNode {
+    pub bounds_min: [f32; 3],
+    /// Index of the right child for internal nodes, of the first object for leaves.
+    pub offset: u32,
+    pub bounds_max: [f32; 3],
+    /// Number of objects in a leaf, 0 for internal nodes.
+    pub count: u32,
+}
+
+/// Everything the shader needs to know about a scene apart from the mesh triangles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuScene {
+    pub objects: Vec<GpuObject>,
+    pub materials: Vec<GpuMaterial>,
+    pub bvh: Vec<GpuBvhNode>,
+    /// Point, spot and directional lights, separate from the emissive objects.
+    pub lights: Vec<GpuLight>,
+    pub sky: GpuSky,
+    pub media: Vec<GpuMedium>,
+    /// Index into `media` of what fills the space between objects, or `NO_MEDIUM`.
+    pub medium: u32,
+}
+
+impl Default for GpuScene {
+    fn default() -> Self {
+        GpuScene {
+            objects: Vec::new(),
+            materials: Vec::new(),
+            bvh: Vec::new(),
+            lights: Vec::new(),
+            sky: GpuSky::default(),
+            media: Vec::new(),
+            medium: NO_MEDIUM,
+        }
+    }
+}
+
+impl GpuScene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index of `medium` in the media table, adding it if no identical medium is there yet.
+    pub fn add_medium(&mut self, medium: &Medium) -> u32 {
+        let medium = medium.gpu_data();
+        match self.media.iter().position(|m| *m == medium) {
+            Some(index) => index as u32,
+            None => {
+                self.media.push(medium);
+                self.media.len() as u32 - 1
+            },
+        }
+    }
+
+    /// Index of `material` in the material table, adding it and its medium if no identical material is there yet.
+    pub fn add_material(&mut self, material: &Material) -> u32 {
+        let medium = material.medium.as_ref().map_or(NO_MEDIUM, |medium| self.add_medium(medium));
+        let material = GpuMaterial::new(material, medium);
+        match self.materials.iter().position(|m| *m == material) {
+            Some(index) => index as u32,
+            None => {
+                self.materials.push(material);
+                self.materials.len() as u32 - 1
+            },
+        }
+    }
+
+    /// Appends an object, mesh objects still need their triangle range and bounds filled in.
+    pub fn push_object(&mut self, object_type: GpuObjectType, transform: &Transform, material: &Material) -> &mut GpuObject {
+        let material = self.add_material(material);
+        self.objects.push(GpuObject {
+            // the model matrix is laid out for row vectors, the shader wants it the other way around
+            world_to_local: transform.get_model_matrix().transpose().into(),
+            object_type: object_type as u32,
+            material,
+            ..Default::default()
+        });
+        self.objects.last_mut().unwrap()
+    }
+
+    pub fn header(&self) -> GpuHeader {
+        GpuHeader {
+            version: LAYOUT_VERSION,
+            object_count: self.objects.len() as u32,
+            object_stride: mem::size_of::<GpuObject>() as u32,
+            material_count: self.materials.len() as u32,
+        }
+    }
+
+    /// Indices of every sphere, box, disk and quad whose material gives off light.
+    ///
+    /// Emissive meshes and planes still light the scene when paths hit them, but can't be sampled directly.
+    pub fn emissive_objects(&self) -> Vec<u32> {
+        (0..self.objects.len() as u32)
+            .filter(|&i| {
+                let object = &self.objects[i as usize];
+                object.object_type != GpuObjectType::Mesh as u32
+                    && object.object_type != GpuObjectType::Plane as u32
+                    && self.materials[object.material as usize].is_emissive()
+            })
+            .collect()
+    }
+}
+
+struct GlslField {
+    glsl_type: &'static str,
+    name: &'static str,
+    offset: usize,
+}
+
+macro_rules! glsl_field {
+    ($struct:ty, $field:ident, $glsl_type:literal, $name:literal) => {
+        GlslField {
+            glsl_type: $glsl_type,
+            name: $name,
+            offset: mem::offset_of!($struct, $field),
+        }
+    };
+}
+
+// size and alignment of a member under std430
+fn std430_layout(glsl_type: &str) -> (usize, usize) {
+    match glsl_type {
+        "float" | "uint" | "int" => (4, 4),
+        "vec3" => (12, 16),
+        "vec4" => (16, 16),
+        "mat4" => (64, 16),
+        _ => panic!("No std430 layout for {}", glsl_type),
+    }
+}
+
+// panics if std430 would place any member somewhere else than the Rust struct does
+fn glsl_struct(name: &str, size: usize, fields: &[GlslField]) -> String {
+    let mut glsl = format!("struct {} {{\n", name);
+    let mut offset: usize = 0;
+    let mut struct_align = 4;
+
+    for field in fields {
+        let (field_size, align) = std430_layout(field.glsl_type);
+        offset = offset.next_multiple_of(align);
+        struct_align = struct_align.max(align);
+        assert_eq!(offset, field.offset, "{}.{} is at a different offset in GLSL", name, field.name);
+
+        glsl += &format!("    {} {};\n", field.glsl_type, field.name);
+        offset += field_size;
+    }
+
+    assert_eq!(offset.next_multiple_of(struct_align), size, "{} has a different size in GLSL", name);
+
+    glsl + "};\n"
+}
+
+/// GLSL versions of the structs and constants above, meant to replace `#pragma gpu_layout` in a shader.
+pub fn glsl_definitions() -> String {
+    let mut glsl = String::new();
+
+    glsl += &format!("#define GPU_LAYOUT_VERSION {}u\n", LAYOUT_VERSION);
+    glsl += &format!("#define OBJECT_SPHERE {}u\n", GpuObjectType::Sphere as u32);
+    glsl += &format!("#define OBJECT_BOX {}u\n", GpuObjectType::Box as u32);
+    glsl += &format!("#define OBJECT_MESH {}u\n", GpuObjectType::Mesh as u32);
+    glsl += &format!("#define OBJECT_PLANE {}u\n", GpuObjectType::Plane as u32);
+    glsl += &format!("#define OBJECT_DISK {}u\n", GpuObjectType::Disk as u32);
+    glsl += &format!("#define OBJECT_QUAD {}u\n", GpuObjectType::Quad as u32);
+    glsl += &format!("#define LIGHT_POINT {}u\n", GpuLightType::Point as u32);
+    glsl += &format!("#define LIGHT_SPOT {}u\n", GpuLightType::Spot as u32);
+    glsl += &format!("#define LIGHT_DIRECTIONAL {}u\n", GpuLightType::Directional as u32);
+    glsl += &format!("#define NO_TEXTURE {}u\n", NO_TEXTURE);
+    glsl += &format!("#define NO_MEDIUM {}u\n\n", NO_MEDIUM);
+
+    glsl += &glsl_struct("GpuHeader", mem::size_of::<GpuHeader>(), &[
+        glsl_field!(GpuHeader, version, "uint", "version"),
+        glsl_field!(GpuHeader, object_count, "uint", "objectCount"),
+        glsl_field!(GpuHeader, object_stride, "uint", "objectStride"),
+        glsl_field!(GpuHeader, material_count, "uint", "materialCount"),
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuMaterial", mem::size_of::<GpuMaterial>(), &[
+        glsl_field!(GpuMaterial, base_color, "vec3", "baseColor"),
+        glsl_field!(GpuMaterial, roughness, "float", "roughness"),
+        glsl_field!(GpuMaterial, emission, "vec3", "emission"),
+        glsl_field!(GpuMaterial, ior, "float", "ior"),
+        glsl_field!(GpuMaterial, absorption, "vec3", "absorption"),
+        glsl_field!(GpuMaterial, metallic, "float", "metallic"),
+        glsl_field!(GpuMaterial, subsurface_color, "vec3", "subsurfaceColor"),
+        glsl_field!(GpuMaterial, subsurface, "float", "subsurface"),
+        glsl_field!(GpuMaterial, mean_free_path, "vec3", "meanFreePath"),
+        glsl_field!(GpuMaterial, specular, "float", "specular"),
+        glsl_field!(GpuMaterial, clearcoat, "float", "clearcoat"),
+        glsl_field!(GpuMaterial, clearcoat_roughness, "float", "clearcoatRoughness"),
+        glsl_field!(GpuMaterial, sheen, "float", "sheen"),
+        glsl_field!(GpuMaterial, transmission, "float", "transmission"),
+        glsl_field!(GpuMaterial, albedo_texture, "uint", "albedoTexture"),
+        glsl_field!(GpuMaterial, roughness_texture, "uint", "roughnessTexture"),
+        glsl_field!(GpuMaterial, metalness_texture, "uint", "metalnessTexture"),
+        glsl_field!(GpuMaterial, normal_texture, "uint", "normalTexture"),
+        glsl_field!(GpuMaterial, medium, "uint", "medium"),
+        GlslField { glsl_type: "uint", name: "padding0", offset: mem::offset_of!(GpuMaterial, padding) },
+        GlslField { glsl_type: "uint", name: "padding1", offset: mem::offset_of!(GpuMaterial, padding) + 4 },
+        GlslField { glsl_type: "uint", name: "padding2", offset: mem::offset_of!(GpuMaterial, padding) + 8 },
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuObject", mem::size_of::<GpuObject>(), &[
+        glsl_field!(GpuObject, world_to_local, "mat4", "worldToLocal"),
+        glsl_field!(GpuObject, object_type, "uint", "type"),
+        glsl_field!(GpuObject, material, "uint", "material"),
+        glsl_field!(GpuObject, first_index, "uint", "firstIndex"),
+        glsl_field!(GpuObject, triangle_count, "uint", "triangleCount"),
+        glsl_field!(GpuObject, bounds_min, "vec4", "boundsMin"),
+        glsl_field!(GpuObject, bounds_max, "vec4", "boundsMax"),
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuBvhNode", mem::size_of::<GpuBvhNode>(), &[
+        glsl_field!(GpuBvhNode, bounds_min, "vec3", "boundsMin"),
+        glsl_field!(GpuBvhNode, offset, "uint", "offset"),
+        glsl_field!(GpuBvhNode, bounds_max, "vec3", "boundsMax"),
+        glsl_field!(GpuBvhNode, count, "uint", "count"),
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuLight", mem::size_of::<GpuLight>(), &[
+        glsl_field!(GpuLight, position, "vec3", "position"),
+        glsl_field!(GpuLight, light_type, "uint", "type"),
+        glsl_field!(GpuLight, direction, "vec3", "direction"),
+        glsl_field!(GpuLight, cos_outer, "float", "cosOuter"),
+        glsl_field!(GpuLight, intensity, "vec3", "intensity"),
+        glsl_field!(GpuLight, cos_inner, "float", "cosInner"),
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuEnvironment", mem::size_of::<GpuEnvironment>(), &[
+        glsl_field!(GpuEnvironment, width, "uint", "width"),
+        glsl_field!(GpuEnvironment, height, "uint", "height"),
+        glsl_field!(GpuEnvironment, rotation, "float", "rotation"),
+        glsl_field!(GpuEnvironment, intensity, "float", "intensity"),
+    ]);
+    glsl += "\n";
+
+    glsl += &glsl_struct("GpuMedium", mem::size_of::<GpuMedium>(), &[
+        glsl_field!(GpuMedium, scattering, "vec3", "scattering"),
+        glsl_field!(GpuMedium, anisotropy, "float", "anisotropy"),
+        glsl_field!(GpuMedium, absorption, "vec3", "absorption"),
+        glsl_field!(GpuMedium, padding, "uint", "padding"),
+    ]);
+    glsl += "\n";
+
+    let perez = mem::offset_of!(GpuSky, perez);
+    glsl += &glsl_struct("GpuSky", mem::size_of::<GpuSky>(), &[
+        glsl_field!(GpuSky, sun_direction, "vec3", "sunDirection"),
+        glsl_field!(GpuSky, enabled, "uint", "enabled"),
+        glsl_field!(GpuSky, sun_radiance, "vec3", "sunRadiance"),
+        glsl_field!(GpuSky, cos_sun_radius, "float", "cosSunRadius"),
+        glsl_field!(GpuSky, zenith, "vec3", "zenith"),
+        glsl_field!(GpuSky, sun_solid_angle, "float", "sunSolidAngle"),
+        GlslField { glsl_type: "vec4", name: "perezA", offset: perez },
+        GlslField { glsl_type: "vec4", name: "perezB", offset: perez + 16 },
+        GlslField { glsl_type: "vec4", name: "perezC", offset: perez + 32 },
+        GlslField { glsl_type: "vec4", name: "perezD", offset: perez + 48 },
+        GlslField { glsl_type: "vec4", name: "perezE", offset: perez + 64 },
+    ]);
+
+    glsl
+}
@@ -0,0 +1,279 @@
+use khronos_egl as egl;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use crate::renderer::{self, Backend, Renderer};
+use crate::scene::{self, Scene};
+
+// EGL_MESA_platform_surfaceless, lets us get a display without X11 or Wayland
+const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+pub struct HeadlessOptions {
+    pub width: u32,
+    pub height: u32,
+    /// Traces at this size and scales the result to `width` by `height`, instead of tracing at the output size.
+    pub resolution: Option<(u32, u32)>,
+    /// Overrides the shader's `MAX_BOUNCES`.
+    pub max_bounces: Option<u32>,
+    /// Overrides the shader's `SAMPLES_PER_PIXEL`.
+    pub samples_per_pixel: Option<u32>,
+    pub backend: Backend,
+    /// Overrides the compute shader's workgroup size, only valid with the compute backend.
+    pub workgroup_size: Option<(u32, u32)>,
+    pub frames: u32,
+    pub output: PathBuf,
+    pub scene: PathBuf,
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        HeadlessOptions {
+            width: 800,
+            height: 600,
+            resolution: None,
+            max_bounces: None,
+            samples_per_pixel: None,
+            backend: Backend::default(),
+            workgroup_size: None,
+            frames: 1,
+            output: PathBuf::from("render.png"),
+            scene: PathBuf::from(scene::DEFAULT_SCENE),
+        }
+    }
+}
+
+impl HeadlessOptions {
+    /// Parses `--width`, `--height`, `--resolution`, `--bounces`, `--samples`, `--backend`, `--workgroup-size`,
+    /// `--frames`, `--output` and `--scene` out of the command line arguments, anything else besides `--headless` is an error.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = HeadlessOptions {
+            resolution: renderer::resolution_from_args(args)?,
+            backend: renderer::backend_from_args(args)?,
+            workgroup_size: renderer::workgroup_size_from_args(args)?,
+            ..Default::default()
+        };
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--width" => options.width = parse_number(arg, value()?)?,
+                "--height" => options.height = parse_number(arg, value()?)?,
+                "--bounces" => options.max_bounces = Some(parse_number(arg, value()?)?),
+                "--samples" => options.samples_per_pixel = Some(parse_number(arg, value()?)?),
+                "--frames" => options.frames = parse_number(arg, value()?)?,
+                "--output" => options.output = PathBuf::from(value()?),
+                "--scene" => options.scene = PathBuf::from(value()?),
+                // already parsed by the renderer's parsers above
+                "--resolution" | "--backend" | "--workgroup-size" => {
+                    value()?;
+                },
+                "--headless" => (),
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        if options.width == 0 || options.height == 0 {
+            return Err("Resolution must be at least 1x1".to_string());
+        }
+        if options.frames == 0 {
+            return Err("Frames must be at least 1".to_string());
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value))
+}
+
+/// A GL 4.3 core context that isn't attached to any window, made current on creation.
+pub(crate) struct HeadlessContext {
+    egl: egl::DynamicInstance<egl::EGL1_5>,
+    display: egl::Display,
+    context: egl::Context,
+}
+
+impl HeadlessContext {
+    pub(crate) fn new() -> Result<Self, String> {
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .map_err(|e| format!("Failed to load libEGL: {}", e))?;
+
+        // prefer the surfaceless platform so this works on machines without a display server
+        let display = unsafe { egl.get_platform_display(PLATFORM_SURFACELESS_MESA, egl::DEFAULT_DISPLAY, &[egl::ATTRIB_NONE]) }
+            .ok()
+            .or_else(|| unsafe { egl.get_display(egl::DEFAULT_DISPLAY) })
+            .ok_or("Failed to get an EGL display")?;
+
+        egl.initialize(display).map_err(|e| format!("Failed to initialize EGL: {}", e))?;
+        egl.bind_api(egl::OPENGL_API).map_err(|e| format!("Failed to bind the OpenGL API: {}", e))?;
+
+        // we only render into our own framebuffer, so a config is only needed if EGL_KHR_no_config_context is missing
+        let config = match egl.choose_first_config(display, &[egl::RENDERABLE_TYPE, egl::OPENGL_BIT, egl::NONE]) {
+            Ok(Some(config)) => config,
+            _ => unsafe { egl::Config::from_ptr(std::ptr::null_mut()) },
+        };
+
+        let context = egl.create_context(display, config, None, &[
+            egl::CONTEXT_MAJOR_VERSION, 4,
+            egl::CONTEXT_MINOR_VERSION, 3,
+            egl::CONTEXT_OPENGL_PROFILE_MASK, egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            egl::NONE,
+        ]).map_err(|e| format!("Failed to create an OpenGL 4.3 context: {}", e))?;
+
+        egl.make_current(display, None, None, Some(context))
+            .map_err(|e| format!("Failed to make the context current: {}", e))?;
+
+        gl::load_with(|symbol| egl.get_proc_address(symbol).map_or(std::ptr::null(), |f| f as *const _));
+
+        Ok(HeadlessContext {
+            egl,
+            display,
+            context,
+        })
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        let _ = self.egl.make_current(self.display, None, None, None);
+        let _ = self.egl.destroy_context(self.display, self.context);
+        let _ = self.egl.terminate(self.display);
+    }
+}
+
+/// Offscreen color target the renderer draws into instead of the default framebuffer.
+struct Framebuffer {
+    fbo: u32,
+    texture: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Framebuffer {
+    fn new(width: u32, height: u32) -> Result<Self, String> {
+        // built up front so dropping it on an error deletes whatever was created
+        let mut framebuffer = Framebuffer {
+            fbo: 0,
+            texture: 0,
+            width,
+            height,
+        };
+
+        unsafe {
+            gl::GenTextures(1, &mut framebuffer.texture);
+            gl::BindTexture(gl::TEXTURE_2D, framebuffer.texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA8 as i32, width as i32, height as i32, 0, gl::RGBA, gl::UNSIGNED_BYTE, std::ptr::null());
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+
+            gl::GenFramebuffers(1, &mut framebuffer.fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.fbo);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, framebuffer.texture, 0);
+
+            if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+                return Err("Offscreen framebuffer is incomplete".to_string());
+            }
+        }
+
+        Ok(framebuffer)
+    }
+
+    /// Reads the color attachment back as tightly packed RGBA8, top row first.
+    fn read_pixels(&self) -> Vec<u8> {
+        let row_len = self.width as usize * 4;
+        let mut pixels = vec![0u8; row_len * self.height as usize];
+
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::ReadPixels(0, 0, self.width as i32, self.height as i32, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_mut_ptr() as *mut _);
+        }
+
+        // GL's origin is the bottom left, images start at the top
+        pixels
+            .chunks_exact(row_len)
+            .rev()
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::DeleteFramebuffers(1, &self.fbo);
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+}
+
+fn write_png(options: &HeadlessOptions, pixels: &[u8]) -> Result<(), String> {
+    let file = File::create(&options.output)
+        .map_err(|e| format!("Failed to create {}: {}", options.output.display(), e))?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), options.width, options.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(pixels))
+        .map_err(|e| format!("Failed to write {}: {}", options.output.display(), e))
+}
+
+/// Renders `options.scene` for `options.frames` frames without opening a window and writes the last one to `options.output`.
+pub fn run(options: &HeadlessOptions) -> Result<(), String> {
+    let pixels = render(options)?;
+    write_png(options, &pixels)
+}
+
+/// Renders `options.scene` for `options.frames` frames and returns the last one as RGBA8, top row first.
+pub fn render(options: &HeadlessOptions) -> Result<Vec<u8>, String> {
+    let mut scene = Scene::load(&options.scene)?;
+
+    let _context = HeadlessContext::new()?;
+
+    let framebuffer = Framebuffer::new(options.width, options.height)?;
+    let mut renderer = Renderer::new(options.width, options.height, options.backend)?;
+    if let Some((width, height)) = options.resolution {
+        renderer.set_render_size(width, height);
+    }
+    if let Some(bounces) = options.max_bounces {
+        renderer.set_max_bounces(bounces)?;
+    }
+    if let Some(samples) = options.samples_per_pixel {
+        renderer.set_samples_per_pixel(samples)?;
+    }
+    if let Some((x, y)) = options.workgroup_size {
+        renderer.set_workgroup_size(x, y)?;
+    }
+
+    renderer.upload_meshes(&scene.meshes);
+    renderer.upload_environment(scene.environment.as_ref());
+    renderer.upload_textures(&scene.textures);
+
+    let projection = nalgebra::Perspective3::new(renderer.aspect_ratio(), 45.0, 0.1, 100.0);
+
+    for frame in 0..options.frames {
+        // step time at a fixed rate so output doesn't depend on how fast the machine is
+        let time = frame as f32 / 60.0;
+
+        scene.update(time);
+        renderer.upload_scene(&scene.get_gpu_data());
+
+        renderer.draw(&scene.camera, &projection, time, framebuffer.fbo);
+    }
+
+    unsafe {
+        gl::Finish();
+    }
+
+    Ok(framebuffer.read_pixels())
+}
@@ -0,0 +1,17 @@
+pub mod shader;
+pub mod camera;
+pub mod objects;
+pub mod light;
+pub mod environment;
+pub mod sky;
+pub mod medium;
+pub mod transform;
+pub mod compound_object;
+pub mod mesh;
+pub mod texture;
+pub mod bvh;
+pub mod gpu;
+pub mod renderer;
+pub mod scene;
+pub mod headless;
+pub mod reference;
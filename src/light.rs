@@ -0,0 +1,42 @@
+use nalgebra::Vector3;
+
+use crate::gpu::{GpuLight, GpuScene};
+
+/// A light that isn't part of any surface, cheaper than emissive objects and without their noise.
+///
+/// Paths can't hit these, so they never show up in the image directly or in reflections. They only
+/// light diffuse surfaces, through a shadow ray towards every light at each bounce.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Light {
+    /// Shines equally in every direction, falling off with the squared distance.
+    Point {
+        position: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+    },
+    /// A point light limited to a cone around `direction`. Full intensity within `inner_angle` of the axis,
+    /// fading out towards `outer_angle`, both in degrees.
+    Spot {
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    /// Parallel light from infinitely far away shining along `direction`, like the sun. `intensity` is the
+    /// irradiance on a surface facing it, `angular_radius` in degrees softens the shadows.
+    Directional {
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+        angular_radius: f32,
+    },
+}
+
+impl Light {
+    /// Appends this light to the analytic lights of `scene`.
+    pub fn write_gpu_data(&self, scene: &mut GpuScene) {
+        scene.lights.push(GpuLight::new(self));
+    }
+}
@@ -0,0 +1,42 @@
+use nalgebra::Vector3;
+
+use crate::gpu::GpuMedium;
+
+/// A homogeneous participating medium like fog, smoke or milk, filling the whole scene or the inside of an
+/// object whose material points to it.
+///
+/// Coefficients are per unit of distance, so light traveling a distance `d` through the medium without
+/// interacting with it is left with `exp(-(scattering + absorption) * d)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Medium {
+    /// How often red, green and blue light bounces off particles in the medium.
+    pub scattering: Vector3<f32>,
+    /// How often it gets swallowed by them.
+    pub absorption: Vector3<f32>,
+    /// Henyey-Greenstein `g` between -1 and 1, positive values scatter mostly forward, 0 in every direction alike.
+    pub anisotropy: f32,
+}
+
+impl Medium {
+    pub fn new(scattering: Vector3<f32>, absorption: Vector3<f32>, anisotropy: f32) -> Self {
+        Medium {
+            scattering,
+            absorption,
+            anisotropy,
+        }
+    }
+
+    /// Scattering and absorption together, how quickly the medium thins out light passing straight through.
+    pub fn extinction(&self) -> Vector3<f32> {
+        self.scattering + self.absorption
+    }
+
+    pub fn gpu_data(&self) -> GpuMedium {
+        GpuMedium {
+            scattering: self.scattering.into(),
+            anisotropy: self.anisotropy,
+            absorption: self.absorption.into(),
+            padding: 0,
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use nalgebra::Vector3;
+
+use std::path::Path;
+
+use crate::bvh::Aabb;
+use crate::gpu::{GpuObjectType, GpuScene};
+use crate::objects::{Material, Object};
+use crate::transform::Transform;
+
+/// Number of floats per vertex in `MeshBuffer::vertices`: position, normal and uv.
+pub const VERTEX_SIZE: usize = 8;
+
+/// Triangles of every mesh in a scene, uploaded to the shader as one vertex and one index buffer.
+#[derive(Default)]
+pub struct MeshBuffer {
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn position(&self, index: u32) -> Vector3<f32> {
+        let i = index as usize * VERTEX_SIZE;
+        Vector3::new(self.vertices[i], self.vertices[i + 1], self.vertices[i + 2])
+    }
+}
+
+/// Triangle mesh loaded from a Wavefront `.obj` file.
+///
+/// The triangles themselves live in a shared `MeshBuffer`, the mesh only remembers its range of it.
+pub struct Mesh {
+    pub transform: Transform,
+    pub material: Material,
+    first_index: u32,
+    triangle_count: u32,
+    // local space bounding box, lets the shader skip the triangles of meshes the ray misses
+    bounds_min: Vector3<f32>,
+    bounds_max: Vector3<f32>,
+}
+
+impl Mesh {
+    /// Appends every triangle in `path` to `buffer`. Normals are generated if the file has none.
+    pub fn load(path: &Path, buffer: &mut MeshBuffer, transform: Transform, material: Material) -> Result<Self, String> {
+        let (models, _) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)
+            .map_err(|e| format!("Failed to load mesh {}: {}", path.display(), e))?;
+
+        let first_index = buffer.indices.len() as u32;
+
+        for model in &models {
+            let mesh = &model.mesh;
+            let base = (buffer.vertices.len() / VERTEX_SIZE) as u32;
+            let vertex_count = mesh.positions.len() / 3;
+
+            let normals = if mesh.normals.len() == mesh.positions.len() {
+                mesh.normals.clone()
+            } else {
+                generate_normals(&mesh.positions, &mesh.indices)
+            };
+
+            for v in 0..vertex_count {
+                buffer.vertices.extend_from_slice(&mesh.positions[v * 3..v * 3 + 3]);
+                buffer.vertices.extend_from_slice(&normals[v * 3..v * 3 + 3]);
+                if mesh.texcoords.len() == vertex_count * 2 {
+                    buffer.vertices.extend_from_slice(&mesh.texcoords[v * 2..v * 2 + 2]);
+                } else {
+                    buffer.vertices.extend_from_slice(&[0.0, 0.0]);
+                }
+            }
+
+            buffer.indices.extend(mesh.indices.iter().map(|index| base + index));
+        }
+
+        let triangle_count = (buffer.indices.len() as u32 - first_index) / 3;
+        if triangle_count == 0 {
+            return Err(format!("Mesh {} has no triangles", path.display()));
+        }
+
+        let mut bounds_min = Vector3::repeat(f32::MAX);
+        let mut bounds_max = Vector3::repeat(f32::MIN);
+        for &index in &buffer.indices[first_index as usize..] {
+            let p = buffer.position(index);
+            bounds_min = bounds_min.inf(&p);
+            bounds_max = bounds_max.sup(&p);
+        }
+
+        Ok(Mesh {
+            transform,
+            material,
+            first_index,
+            triangle_count,
+            bounds_min,
+            bounds_max,
+        })
+    }
+}
+
+// area weighted vertex normals, cross products of larger triangles are longer
+fn generate_normals(positions: &[f32], indices: &[u32]) -> Vec<f32> {
+    let position = |i: u32| Vector3::new(positions[i as usize * 3], positions[i as usize * 3 + 1], positions[i as usize * 3 + 2]);
+
+    let mut normals = vec![Vector3::zeros(); positions.len() / 3];
+    for triangle in indices.chunks_exact(3) {
+        let a = position(triangle[0]);
+        let face_normal = (position(triangle[1]) - a).cross(&(position(triangle[2]) - a));
+        for &index in triangle {
+            normals[index as usize] += face_normal;
+        }
+    }
+
+    normals
+        .iter()
+        .flat_map(|n| {
+            let n = n.try_normalize(1e-12).unwrap_or(Vector3::new(0.0, 1.0, 0.0));
+            [n.x, n.y, n.z]
+        })
+        .collect()
+}
+
+impl Object for Mesh {
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        let object = scene.push_object(GpuObjectType::Mesh, transform, &self.material);
+        object.first_index = self.first_index;
+        object.triangle_count = self.triangle_count;
+        object.bounds_min = self.bounds_min.push(0.0).into();
+        object.bounds_max = self.bounds_max.push(0.0).into();
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        Aabb::new(self.bounds_min, self.bounds_max).transformed(&transform.get_inverse_model_matrix())
+    }
+
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
+}
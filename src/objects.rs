@@ -1,19 +1,119 @@
 use nalgebra::Vector3;
 
+use crate::bvh::Aabb;
+use crate::gpu::{GpuObjectType, GpuScene};
+use crate::medium::Medium;
 use crate::transform::Transform;
 
+/// Parameters of the principled BSDF every surface is shaded with.
+///
+/// A dielectric base with a diffuse and a GGX specular lobe blends into a metal with `metallic` and into
+/// glass with `transmission`, optionally under a clearcoat.
+#[derive(Clone)]
 pub struct Material {
-    pub color: Vector3<f32>,
+    /// Color of the diffuse and metal lobes, glass gets its color from `absorption` instead.
+    pub base_color: Vector3<f32>,
+    /// 0 for a dielectric, 1 for a metal whose reflections are tinted by `base_color`.
+    pub metallic: f32,
+    /// Microfacet roughness of the specular and glass lobes, squared into the GGX alpha.
     pub roughness: f32,
-    pub isMetal: bool,
-    pub isDielectric: bool,
-    pub ior: f32
+    /// Head on reflectance of the dielectric base, 0.5 gives the usual 4%.
+    pub specular: f32,
+    /// Strength of a colorless glossy layer on top of everything else.
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// Share of the diffuse lobe that enters the surface and random walks through the object before coming back
+    /// out somewhere else, for skin, wax and marble. Only makes sense on closed spheres, boxes and meshes.
+    pub subsurface: f32,
+    /// Color the subsurface lobe converges to, like `base_color` for the diffuse lobe.
+    pub subsurface_color: Vector3<f32>,
+    /// Average distance red, green and blue light travel inside between scattering events.
+    pub mean_free_path: Vector3<f32>,
+    /// Brightens the diffuse lobe at grazing angles, mostly for cloth.
+    pub sheen: f32,
+    /// 0 for an opaque dielectric, 1 for glass refracting with `ior`.
+    pub transmission: f32,
+    /// Glass with an index of refraction of 1 neither reflects nor bends light, an invisible boundary of its `medium`.
+    pub ior: f32,
+    /// How quickly red, green and blue light die out inside the object, which lets through `exp(-absorption * d)`
+    /// after a distance `d` following Beer-Lambert. Only glass lets paths get inside.
+    pub absorption: Vector3<f32>,
+    /// What fills the object, which paths only get into through glass.
+    pub medium: Option<Medium>,
+    /// Light given off by the surface, scaled by `emission_strength`.
+    pub emission: Vector3<f32>,
+    pub emission_strength: f32,
+    /// Layer in the scene's `TextureBuffer` whose sRGB color multiplies `base_color`.
+    pub albedo_texture: Option<u32>,
+    /// Layer whose green channel replaces `roughness`.
+    pub roughness_texture: Option<u32>,
+    /// Layer whose blue channel replaces `metallic`.
+    pub metalness_texture: Option<u32>,
+    /// Layer holding a tangent space normal map.
+    pub normal_texture: Option<u32>,
+}
+
+impl Default for Material {
+    /// A white, slightly glossy dielectric.
+    fn default() -> Self {
+        Material {
+            base_color: Vector3::new(1.0, 1.0, 1.0),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            subsurface: 0.0,
+            subsurface_color: Vector3::new(1.0, 1.0, 1.0),
+            mean_free_path: Vector3::repeat(0.1),
+            sheen: 0.0,
+            transmission: 0.0,
+            ior: 1.5,
+            absorption: Vector3::zeros(),
+            medium: None,
+            emission: Vector3::zeros(),
+            emission_strength: 0.0,
+            albedo_texture: None,
+            roughness_texture: None,
+            metalness_texture: None,
+            normal_texture: None,
+        }
+    }
+}
+
+/// How far planes reach in world space, as far as the shader traces rays.
+const PLANE_EXTENT: f32 = 10000.0;
+
+/// World space bounds of the -1 to 1 cube spheres and boxes are defined in.
+pub fn unit_bounds(transform: &Transform) -> Aabb {
+    Aabb::new(Vector3::repeat(-1.0), Vector3::repeat(1.0)).transformed(&transform.get_inverse_model_matrix())
+}
+
+/// World space bounds of the -1 to 1 square on y = 0 disks and quads are cut out of.
+pub fn flat_bounds(transform: &Transform) -> Aabb {
+    Aabb::new(Vector3::new(-1.0, 0.0, -1.0), Vector3::new(1.0, 0.0, 1.0)).transformed(&transform.get_inverse_model_matrix())
 }
 
 pub trait Object {
-    fn get_gpu_data(&self) -> Vec<u32>;
-    fn get_gpu_data_custom_transform(&self, transform: &Transform) -> Vec<u32>;
+    /// Appends the spheres, boxes and meshes this object is made of to `scene`.
+    fn write_gpu_data(&self, scene: &mut GpuScene) {
+        self.write_gpu_data_custom_transform(self.get_transform(), scene);
+    }
+
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene);
     fn get_transform(&self) -> &Transform;
+    fn get_transform_mut(&mut self) -> &mut Transform;
+
+    /// World space bounds of everything `write_gpu_data` writes.
+    fn bounds(&self) -> Aabb {
+        self.bounds_custom_transform(self.get_transform())
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb;
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        None
+    }
 }
 
 pub struct Sphere {
@@ -26,6 +126,24 @@ pub struct Box {
     pub transform: Transform,
 }
 
+/// The infinite y = 0 plane in local space, facing +y. Can't be sampled as a light.
+pub struct Plane {
+    pub material: Material,
+    pub transform: Transform,
+}
+
+/// The unit disk on y = 0 in local space, lighting the scene from its +y side when emissive.
+pub struct Disk {
+    pub material: Material,
+    pub transform: Transform,
+}
+
+/// The -1 to 1 square on y = 0 in local space, lighting the scene from its +y side when emissive.
+pub struct Quad {
+    pub material: Material,
+    pub transform: Transform,
+}
+
 impl Sphere {
     pub fn new(transform: Transform, material: Material) -> Self {
         Sphere {
@@ -36,61 +154,25 @@ impl Sphere {
 }
 
 impl Object for Sphere {
-    fn get_gpu_data(&self) -> Vec<u32> {
-        let mut data = Vec::new();
-        data.push(1);
-        let transform = self.transform.get_model_matrix();
-        data.push(transform[(0, 0)].to_bits());
-        data.push(transform[(0, 1)].to_bits());
-        data.push(transform[(0, 2)].to_bits());
-        data.push(transform[(1, 0)].to_bits());
-        data.push(transform[(1, 1)].to_bits());
-        data.push(transform[(1, 2)].to_bits());
-        data.push(transform[(2, 0)].to_bits());
-        data.push(transform[(2, 1)].to_bits());
-        data.push(transform[(2, 2)].to_bits());
-        data.push(transform[(3, 0)].to_bits());
-        data.push(transform[(3, 1)].to_bits());
-        data.push(transform[(3, 2)].to_bits());
-        data.push(self.material.color.x.to_bits());
-        data.push(self.material.color.y.to_bits());
-        data.push(self.material.color.z.to_bits());
-        data.push(self.material.roughness.to_bits());
-        data.push(self.material.isMetal as u32);
-        data.push(self.material.isDielectric as u32);
-        data.push(self.material.ior.to_bits());
-        data
-    }
-
-    fn get_gpu_data_custom_transform(&self, transform: &Transform) -> Vec<u32> {
-        let mut data = Vec::new();
-        data.push(1);
-        let transform = transform.get_model_matrix();
-        data.push(transform[(0, 0)].to_bits());
-        data.push(transform[(0, 1)].to_bits());
-        data.push(transform[(0, 2)].to_bits());
-        data.push(transform[(1, 0)].to_bits());
-        data.push(transform[(1, 1)].to_bits());
-        data.push(transform[(1, 2)].to_bits());
-        data.push(transform[(2, 0)].to_bits());
-        data.push(transform[(2, 1)].to_bits());
-        data.push(transform[(2, 2)].to_bits());
-        data.push(transform[(3, 0)].to_bits());
-        data.push(transform[(3, 1)].to_bits());
-        data.push(transform[(3, 2)].to_bits());
-        data.push(self.material.color.x.to_bits());
-        data.push(self.material.color.y.to_bits());
-        data.push(self.material.color.z.to_bits());
-        data.push(self.material.roughness.to_bits());
-        data.push(self.material.isMetal as u32);
-        data.push(self.material.isDielectric as u32);
-        data.push(self.material.ior.to_bits());
-        data
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        scene.push_object(GpuObjectType::Sphere, transform, &self.material);
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        unit_bounds(transform)
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
 }
 
 impl Box {
@@ -103,59 +185,118 @@ impl Box {
 }
 
 impl Object for Box {
-    fn get_gpu_data(&self) -> Vec<u32> {
-        let mut data = Vec::new();
-        data.push(2);
-        let transform = self.transform.get_model_matrix();
-        data.push(transform[(0, 0)].to_bits());
-        data.push(transform[(0, 1)].to_bits());
-        data.push(transform[(0, 2)].to_bits());
-        data.push(transform[(1, 0)].to_bits());
-        data.push(transform[(1, 1)].to_bits());
-        data.push(transform[(1, 2)].to_bits());
-        data.push(transform[(2, 0)].to_bits());
-        data.push(transform[(2, 1)].to_bits());
-        data.push(transform[(2, 2)].to_bits());
-        data.push(transform[(3, 0)].to_bits());
-        data.push(transform[(3, 1)].to_bits());
-        data.push(transform[(3, 2)].to_bits());
-        data.push(self.material.color.x.to_bits());
-        data.push(self.material.color.y.to_bits());
-        data.push(self.material.color.z.to_bits());
-        data.push(self.material.roughness.to_bits());
-        data.push(self.material.isMetal as u32);
-        data.push(self.material.isDielectric as u32);
-        data.push(self.material.ior.to_bits());
-        data
-    }
-
-    fn get_gpu_data_custom_transform(&self, transform: &Transform) -> Vec<u32> {
-        let mut data = Vec::new();
-        data.push(2);
-        let transform = transform.get_model_matrix();
-        data.push(transform[(0, 0)].to_bits());
-        data.push(transform[(0, 1)].to_bits());
-        data.push(transform[(0, 2)].to_bits());
-        data.push(transform[(1, 0)].to_bits());
-        data.push(transform[(1, 1)].to_bits());
-        data.push(transform[(1, 2)].to_bits());
-        data.push(transform[(2, 0)].to_bits());
-        data.push(transform[(2, 1)].to_bits());
-        data.push(transform[(2, 2)].to_bits());
-        data.push(transform[(3, 0)].to_bits());
-        data.push(transform[(3, 1)].to_bits());
-        data.push(transform[(3, 2)].to_bits());
-        data.push(self.material.color.x.to_bits());
-        data.push(self.material.color.y.to_bits());
-        data.push(self.material.color.z.to_bits());
-        data.push(self.material.roughness.to_bits());
-        data.push(self.material.isMetal as u32);
-        data.push(self.material.isDielectric as u32);
-        data.push(self.material.ior.to_bits());
-        data
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        scene.push_object(GpuObjectType::Box, transform, &self.material);
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        unit_bounds(transform)
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
-}
\ No newline at end of file
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
+}
+
+impl Plane {
+    pub fn new(transform: Transform, material: Material) -> Self {
+        Plane {
+            material,
+            transform
+        }
+    }
+}
+
+impl Object for Plane {
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        scene.push_object(GpuObjectType::Plane, transform, &self.material);
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        // the transform scales the plane, so the local extent shrinks by that much to still reach as far
+        let extent = Vector3::new(PLANE_EXTENT / transform.scale.x.abs(), 0.0, PLANE_EXTENT / transform.scale.z.abs());
+        Aabb::new(-extent, extent).transformed(&transform.get_inverse_model_matrix())
+    }
+
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
+}
+
+impl Disk {
+    pub fn new(transform: Transform, material: Material) -> Self {
+        Disk {
+            material,
+            transform
+        }
+    }
+}
+
+impl Object for Disk {
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        scene.push_object(GpuObjectType::Disk, transform, &self.material);
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        flat_bounds(transform)
+    }
+
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
+}
+
+impl Quad {
+    pub fn new(transform: Transform, material: Material) -> Self {
+        Quad {
+            material,
+            transform
+        }
+    }
+}
+
+impl Object for Quad {
+    fn write_gpu_data_custom_transform(&self, transform: &Transform, scene: &mut GpuScene) {
+        scene.push_object(GpuObjectType::Quad, transform, &self.material);
+    }
+
+    fn bounds_custom_transform(&self, transform: &Transform) -> Aabb {
+        flat_bounds(transform)
+    }
+
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn get_transform_mut(&mut self) -> &mut Transform {
+        &mut self.transform
+    }
+
+    fn get_material_mut(&mut self) -> Option<&mut Material> {
+        Some(&mut self.material)
+    }
+}
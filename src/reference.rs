@@ -0,0 +1,1450 @@
+//! Pure Rust port of `assets/shaders/main.fsh`, plus the tonemapping from `display.fsh`.
+//!
+//! Reads the same `GpuScene` the GPU gets and follows the shader line by line,
+//! including its RNG and seeding, so reference images can be rendered in `cargo test` and
+//! compared against what the GPU produced.
+
+use nalgebra::{Matrix3, Matrix4, Perspective3, Vector2, Vector3, Vector4};
+
+// both `M_PI` and the `3.14159265` literals in the shader round to this as floats
+use std::f32::consts::PI;
+
+use crate::camera::Camera;
+use crate::environment::EnvironmentMap;
+use crate::mesh::{MeshBuffer, VERTEX_SIZE};
+use crate::gpu::{GpuLight, GpuLightType, GpuMedium, GpuObjectType, GpuScene, GpuSky, NO_MEDIUM, NO_TEXTURE};
+use crate::objects::Material;
+use crate::texture::TextureBuffer;
+
+const MAX_DIST: f32 = 10000.0;
+const MIN_ALPHA: f32 = 1e-3;
+const MAX_WALK_STEPS: usize = 64;
+
+struct HitInfo {
+    dist: f32,
+    normal: Vector3<f32>,
+    front_face: bool,
+    did_hit: bool,
+    material: Material,
+    object_index: usize,
+    uv: Vector2<f32>,
+    tangent: Vector3<f32>,
+}
+
+impl HitInfo {
+    fn miss() -> Self {
+        HitInfo {
+            dist: MAX_DIST,
+            normal: Vector3::zeros(),
+            front_face: false,
+            did_hit: false,
+            object_index: 0,
+            material: Material {
+                base_color: Vector3::zeros(),
+                ..Default::default()
+            },
+            uv: Vector2::zeros(),
+            tangent: Vector3::zeros(),
+        }
+    }
+}
+
+/// The shader's PCG style generator, seeded per pixel.
+struct Rng {
+    seed: u32,
+}
+
+impl Rng {
+    fn random(&mut self) -> f32 {
+        self.seed = self.seed.wrapping_mul(747796405).wrapping_add(2891336453);
+        let mut result = ((self.seed >> ((self.seed >> 28) + 4)) ^ self.seed).wrapping_mul(277803737);
+        result = (result >> 22) ^ result;
+        result as f32 / 4294967295.0
+    }
+
+    fn random_unit_vector(&mut self) -> Vector3<f32> {
+        let theta = 2.0 * PI * self.random();
+        let phi = (2.0 * self.random() - 1.0).acos();
+        Vector3::new(phi.sin() * theta.cos(), phi.sin() * theta.sin(), phi.cos())
+    }
+
+    /// Uniformly distributed point on the surface of a unit sphere, box, disk or quad in local space, and its normal.
+    fn local_surface_sample(&mut self, object_type: u32) -> (Vector3<f32>, Vector3<f32>) {
+        if object_type == GpuObjectType::Sphere as u32 {
+            let n = self.random_unit_vector();
+            return (n, n);
+        }
+        if object_type == GpuObjectType::Disk as u32 {
+            let r = self.random().sqrt();
+            let phi = 2.0 * PI * self.random();
+            return (Vector3::new(r * phi.cos(), 0.0, r * phi.sin()), Vector3::y());
+        }
+        if object_type == GpuObjectType::Quad as u32 {
+            let p = Vector2::new(self.random(), self.random()) * 2.0 - Vector2::new(1.0, 1.0);
+            return (Vector3::new(p.x, 0.0, p.y), Vector3::y());
+        }
+
+        let face = ((self.random() * 6.0) as usize).min(5);
+        let axis = face / 2;
+        let side = if face.is_multiple_of(2) { 1.0 } else { -1.0 };
+
+        let mut p = Vector3::new(self.random(), self.random(), self.random()) * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+        p[axis] = side;
+        let mut n = Vector3::zeros();
+        n[axis] = side;
+        (p, n)
+    }
+
+    fn hemisphere_cosine_sample(&mut self, n: Vector3<f32>, weight: &mut f32) -> Vector3<f32> {
+        let cos_theta2 = self.random();
+        let cos_theta = cos_theta2.sqrt();
+        let sin_theta = (1.0 - cos_theta2).sqrt();
+
+        let phi = 2.0 * PI * self.random();
+
+        let (t, b) = orthonormal_basis(n);
+
+        let l = (t * phi.cos() + b * phi.sin()) * sin_theta + n * cos_theta;
+
+        let pdf = (1.0 / PI) * cos_theta;
+        *weight = (0.5 / PI) / (pdf + 1e-6);
+
+        l
+    }
+
+    fn ggx_normal_sample(&mut self, n: Vector3<f32>, alpha: f32) -> Vector3<f32> {
+        let u = self.random();
+        let a2 = alpha * alpha;
+        let cos_theta = ((1.0 - u) / (1.0 + (a2 - 1.0) * u)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+        let phi = 2.0 * PI * self.random();
+
+        let (t, b) = orthonormal_basis(n);
+
+        (t * phi.cos() + b * phi.sin()) * sin_theta + n * cos_theta
+    }
+
+    fn ggx_reflection_sample(&mut self, n: Vector3<f32>, wo: Vector3<f32>, alpha: f32) -> Vector3<f32> {
+        reflect(-wo, self.ggx_normal_sample(n, alpha))
+    }
+
+    fn cone_sample(&mut self, n: Vector3<f32>, cos_max: f32) -> Vector3<f32> {
+        let cos_theta = 1.0 - self.random() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+        let phi = 2.0 * PI * self.random();
+
+        let (t, b) = orthonormal_basis(n);
+
+        (t * phi.cos() + b * phi.sin()) * sin_theta + n * cos_theta
+    }
+
+    fn free_flight_sample(&mut self, extinction: Vector3<f32>) -> f32 {
+        let channel = ((self.random() * 3.0) as usize).min(2);
+        let u = self.random();
+        if extinction[channel] > 0.0 { -(1.0 - u).ln() / extinction[channel] } else { MAX_DIST }
+    }
+
+    fn henyey_greenstein_sample(&mut self, rd: Vector3<f32>, g: f32) -> Vector3<f32> {
+        let u = self.random();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+        let phi = 2.0 * PI * self.random();
+
+        let (t, b) = orthonormal_basis(rd);
+
+        (t * phi.cos() + b * phi.sin()) * sin_theta + rd * cos_theta
+    }
+}
+
+fn orthonormal_basis(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let s = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (s + n.z);
+    let c = n.x * n.y * a;
+    (Vector3::new(1.0 + s * n.x * n.x * a, s * c, -s * n.x), Vector3::new(c, s + n.y * n.y * a, -n.y))
+}
+
+fn medium_extinction(medium: &GpuMedium) -> Vector3<f32> {
+    Vector3::from(medium.scattering) + Vector3::from(medium.absorption)
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let d = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * d * d.sqrt())
+}
+
+fn scattering_cosine(n: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+    if n == Vector3::zeros() { 1.0 } else { n.dot(&wi) }
+}
+
+fn medium_weight(medium: &GpuMedium, t: f32, scattered: bool) -> Vector3<f32> {
+    free_flight_weight(medium_extinction(medium), Vector3::from(medium.scattering), t, scattered)
+}
+
+fn free_flight_weight(extinction: Vector3<f32>, scattering: Vector3<f32>, t: f32, scattered: bool) -> Vector3<f32> {
+    let transmittance = (-extinction * t).map(f32::exp);
+    if scattered {
+        let density = extinction.component_mul(&transmittance);
+        return transmittance.component_mul(&scattering) * 3.0 / density.sum();
+    }
+    transmittance * 3.0 / transmittance.sum()
+}
+
+fn subsurface_albedo(color: Vector3<f32>) -> Vector3<f32> {
+    color.map(|c| {
+        let a = (0.5 * c).clamp(0.0, 0.999);
+        let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+        1.0 - s * s
+    })
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    a / (a + other_pdf * other_pdf)
+}
+
+fn local_surface_area(object_type: u32) -> f32 {
+    if object_type == GpuObjectType::Disk as u32 {
+        PI
+    } else if object_type == GpuObjectType::Quad as u32 {
+        4.0
+    } else if object_type == GpuObjectType::Sphere as u32 {
+        4.0 * PI
+    } else {
+        24.0
+    }
+}
+
+fn local_surface_normal(object_type: u32, p: Vector3<f32>) -> Vector3<f32> {
+    if object_type == GpuObjectType::Disk as u32 || object_type == GpuObjectType::Quad as u32 {
+        Vector3::y()
+    } else if object_type == GpuObjectType::Sphere as u32 {
+        p.normalize()
+    } else {
+        local_box_normal(p)
+    }
+}
+
+fn local_box_normal(p: Vector3<f32>) -> Vector3<f32> {
+    let a = p.abs();
+    if a.x >= a.y && a.x >= a.z {
+        Vector3::new(sign(p.x), 0.0, 0.0)
+    } else if a.y >= a.z {
+        Vector3::new(0.0, sign(p.y), 0.0)
+    } else {
+        Vector3::new(0.0, 0.0, sign(p.z))
+    }
+}
+
+fn sphere_uv(p: Vector3<f32>) -> (Vector2<f32>, Vector3<f32>) {
+    let n = p.normalize();
+    let tangent = Vector3::new(n.z, 0.0, -n.x);
+    (Vector2::new(0.5 + n.x.atan2(n.z) / (2.0 * PI), 0.5 + n.y.clamp(-1.0, 1.0).asin() / PI), tangent)
+}
+
+fn plane_uv(p: Vector3<f32>) -> (Vector2<f32>, Vector3<f32>) {
+    (Vector2::new(1.0 + p.x, 1.0 - p.z) * 0.5, Vector3::new(1.0, 0.0, 0.0))
+}
+
+fn box_uv(p: Vector3<f32>) -> (Vector2<f32>, Vector3<f32>) {
+    let n = local_box_normal(p);
+    if n.x != 0.0 {
+        (Vector2::new(1.0 - n.x * p.z, 1.0 + p.y) * 0.5, Vector3::new(0.0, 0.0, -n.x))
+    } else if n.y != 0.0 {
+        (Vector2::new(1.0 + p.x, 1.0 - n.y * p.z) * 0.5, Vector3::new(1.0, 0.0, 0.0))
+    } else {
+        (Vector2::new(1.0 + n.z * p.x, 1.0 + p.y) * 0.5, Vector3::new(n.z, 0.0, 0.0))
+    }
+}
+
+/// Density per world space area of a point sampled uniformly over the local surface, and the world space normal there.
+fn light_area_pdf(model_matrix: &Matrix4<f32>, object_type: u32, local_normal: Vector3<f32>) -> (f32, Vector3<f32>) {
+    let a = model_matrix.fixed_view::<3, 3>(0, 0).into_owned();
+    let n = a.transpose() * local_normal;
+    (a.determinant().abs() / (local_surface_area(object_type) * n.norm()), n.normalize())
+}
+
+fn spot_falloff(light: &GpuLight, from_light: Vector3<f32>) -> f32 {
+    let t = ((from_light.dot(&Vector3::from(light.direction)) - light.cos_outer) / (light.cos_inner - light.cos_outer).max(1e-4)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn reflect(i: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    i - 2.0 * n.dot(&i) * n
+}
+
+fn refract(i: Vector3<f32>, n: Vector3<f32>, eta: f32) -> Vector3<f32> {
+    let n_dot_i = n.dot(&i);
+    let k = 1.0 - eta * eta * (1.0 - n_dot_i * n_dot_i);
+    if k < 0.0 {
+        Vector3::zeros()
+    } else {
+        eta * i - (eta * n_dot_i + k.sqrt()) * n
+    }
+}
+
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+fn step(edge: f32, x: f32) -> f32 {
+    if x < edge { 0.0 } else { 1.0 }
+}
+
+fn tonemap_aces(x: Vector3<f32>) -> Vector3<f32> {
+    const A: f32 = 2.51;
+    const B: f32 = 0.03;
+    const C: f32 = 2.43;
+    const D: f32 = 0.59;
+    const E: f32 = 0.14;
+    x.map(|x| (x * (A * x + B)) / (x * (C * x + D) + E))
+}
+
+fn schlick_fresnel(cosine: f32, refraction_index: f32) -> f32 {
+    let r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+}
+
+/// What `sampleBsdf` picked.
+struct BsdfSample {
+    direction: Vector3<f32>,
+    weight: Vector3<f32>,
+    pdf: f32,
+    is_delta: bool,
+    is_subsurface: bool,
+}
+
+fn diffuse_amount(m: &Material) -> f32 {
+    (1.0 - m.metallic) * (1.0 - m.transmission) * (1.0 - m.subsurface)
+}
+
+fn subsurface_amount(m: &Material) -> f32 {
+    (1.0 - m.metallic) * (1.0 - m.transmission) * m.subsurface
+}
+
+fn transmission_amount(m: &Material) -> f32 {
+    (1.0 - m.metallic) * m.transmission
+}
+
+fn specular_amount(m: &Material) -> f32 {
+    1.0 - transmission_amount(m)
+}
+
+fn clearcoat_amount(m: &Material) -> f32 {
+    0.25 * m.clearcoat
+}
+
+fn lobe_probabilities(m: &Material) -> Vector4<f32> {
+    let weights = Vector4::new(diffuse_amount(m) + subsurface_amount(m), specular_amount(m) * (0.25 * (1.0 - m.metallic) + m.metallic), clearcoat_amount(m), transmission_amount(m));
+    weights / (weights.x + weights.y + weights.z + weights.w)
+}
+
+fn specular_alpha(m: &Material) -> f32 {
+    (m.roughness * m.roughness).max(MIN_ALPHA)
+}
+
+fn clearcoat_alpha(m: &Material) -> f32 {
+    (m.clearcoat_roughness * m.clearcoat_roughness).max(MIN_ALPHA)
+}
+
+fn specular_f0(m: &Material) -> Vector3<f32> {
+    Vector3::repeat(0.08 * m.specular).lerp(&m.base_color, m.metallic)
+}
+
+fn ggx_distribution(nh: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = nh * nh * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+fn smith_g1(nv: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * nv / (nv + (a2 + (1.0 - a2) * nv * nv).sqrt())
+}
+
+fn microfacet_reflection(f0: Vector3<f32>, alpha: f32, nv: f32, nl: f32, nh: f32, vh: f32) -> (Vector3<f32>, f32) {
+    let d = ggx_distribution(nh, alpha);
+    let pdf = d * nh / (4.0 * vh);
+    let fresnel = f0 + (Vector3::repeat(1.0) - f0) * (1.0 - vh).powf(5.0);
+    (fresnel * (d * smith_g1(nv, alpha) * smith_g1(nl, alpha) / (4.0 * nv * nl)), pdf)
+}
+
+/// Every lobe but glass and subsurface, and how likely `sample_bsdf` is to pick `wi`.
+fn evaluate_bsdf(m: &Material, n: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>) -> (Vector3<f32>, f32) {
+    let nv = n.dot(&wo);
+    let nl = n.dot(&wi);
+    if nv <= 0.0 || nl <= 0.0 {
+        return (Vector3::zeros(), 0.0);
+    }
+
+    let h = (wo + wi).normalize();
+    let nh = n.dot(&h).max(0.0);
+    let vh = wo.dot(&h).clamp(1e-6, 1.0);
+    let probabilities = lobe_probabilities(m);
+
+    let mut f = (m.base_color * (0.5 / PI) + Vector3::repeat(m.sheen * (1.0 - vh).powf(5.0))) * diffuse_amount(m);
+    let mut pdf = probabilities.x * (1.0 - m.subsurface) * nl / PI;
+
+    let (specular, lobe_pdf) = microfacet_reflection(specular_f0(m), specular_alpha(m), nv, nl, nh, vh);
+    f += specular * specular_amount(m);
+    pdf += probabilities.y * lobe_pdf;
+
+    let (clearcoat, lobe_pdf) = microfacet_reflection(Vector3::repeat(0.04), clearcoat_alpha(m), nv, nl, nh, vh);
+    f += clearcoat * clearcoat_amount(m);
+    pdf += probabilities.z * lobe_pdf;
+
+    (f, pdf)
+}
+
+fn sample_bsdf(rng: &mut Rng, m: &Material, n: Vector3<f32>, wo: Vector3<f32>, front_face: bool) -> BsdfSample {
+    let probabilities = lobe_probabilities(m);
+    let u = rng.random();
+
+    if u < probabilities.w {
+        if m.ior == 1.0 {
+            return BsdfSample {
+                direction: -wo,
+                weight: Vector3::repeat(transmission_amount(m) / probabilities.w),
+                pdf: 0.0,
+                is_delta: true,
+                is_subsurface: false,
+            };
+        }
+
+        let alpha = specular_alpha(m);
+        let h = rng.ggx_normal_sample(n, alpha);
+        let refraction_ratio = if front_face { 1.0 / m.ior } else { m.ior };
+        let cos_theta = wo.dot(&h).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+        let cannot_refract = refraction_ratio * sin_theta > 1.0;
+        let reflected = cannot_refract || schlick_fresnel(cos_theta, refraction_ratio) > rng.random();
+        let direction = if reflected { reflect(-wo, h) } else { refract(-wo, h, refraction_ratio) };
+
+        let nv = n.dot(&wo);
+        let nl = n.dot(&direction);
+        let weight = if cos_theta > 0.0 && nv > 0.0 && (if reflected { nl > 0.0 } else { nl < 0.0 }) {
+            let g = smith_g1(nv, alpha) * smith_g1(nl.abs(), alpha);
+            Vector3::repeat(g * cos_theta / (nv * n.dot(&h)) * transmission_amount(m) / probabilities.w)
+        } else {
+            Vector3::zeros()
+        };
+
+        return BsdfSample {
+            direction,
+            weight,
+            pdf: 0.0,
+            is_delta: true,
+            is_subsurface: false,
+        };
+    }
+
+    if u < probabilities.w + probabilities.x && m.subsurface > 0.0 && rng.random() < m.subsurface {
+        let mut weight = 0.0;
+        let direction = rng.hemisphere_cosine_sample(-n, &mut weight);
+        return BsdfSample {
+            direction,
+            weight: if front_face { Vector3::repeat((1.0 - m.metallic) * (1.0 - m.transmission) / probabilities.x) } else { Vector3::zeros() },
+            pdf: 0.0,
+            is_delta: true,
+            is_subsurface: true,
+        };
+    }
+
+    let direction = if u < probabilities.w + probabilities.x {
+        let mut weight = 0.0;
+        rng.hemisphere_cosine_sample(n, &mut weight)
+    } else if u < probabilities.w + probabilities.x + probabilities.y {
+        rng.ggx_reflection_sample(n, wo, specular_alpha(m))
+    } else {
+        rng.ggx_reflection_sample(n, wo, clearcoat_alpha(m))
+    };
+
+    let (f, pdf) = evaluate_bsdf(m, n, wo, direction);
+    BsdfSample {
+        direction,
+        weight: if pdf > 0.0 { f * n.dot(&direction) / pdf } else { Vector3::zeros() },
+        pdf,
+        is_delta: false,
+        is_subsurface: false,
+    }
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn perez_function(sky: &GpuSky, cos_theta: f32, gamma: f32) -> Vector3<f32> {
+    let [a, b, c, d, e] = sky.perez.map(|c| Vector4::from(c).xyz());
+    let cos_gamma = gamma.cos();
+    (Vector3::repeat(1.0) + a.component_mul(&(b / cos_theta).map(f32::exp)))
+        .component_mul(&(Vector3::repeat(1.0) + c.component_mul(&(d * gamma).map(f32::exp)) + e * cos_gamma * cos_gamma))
+}
+
+fn physical_sky(sky: &GpuSky, d: Vector3<f32>) -> Vector3<f32> {
+    let cos_theta = d.y.max(0.01);
+    let gamma = d.dot(&Vector3::from(sky.sun_direction)).clamp(-1.0, 1.0).acos();
+
+    let xyy = Vector3::from(sky.zenith).component_mul(&perez_function(sky, cos_theta, gamma));
+    let luminance = xyy.x;
+    let xyz = Vector3::new(xyy.y * luminance / xyy.z, luminance, (1.0 - xyy.y - xyy.z) * luminance / xyy.z);
+
+    let to_rgb = Matrix3::new(
+        3.2406, -1.5372, -0.4986,
+        -0.9689, 1.8758, 0.0415,
+        0.0557, -0.2040, 1.0570,
+    );
+    (to_rgb * xyz).map(|c| c.max(0.0))
+}
+
+/// The environment map the way the shader sees it through its buffer and texture.
+struct Environment<'a> {
+    map: &'a EnvironmentMap,
+    width: u32,
+    height: u32,
+    rotation: f32,
+    intensity: f32,
+}
+
+impl<'a> Environment<'a> {
+    fn new(map: &'a EnvironmentMap) -> Self {
+        let header = map.gpu_header();
+        Environment {
+            map,
+            width: header.width,
+            height: header.height,
+            rotation: header.rotation,
+            intensity: header.intensity,
+        }
+    }
+
+    fn uv(&self, d: Vector3<f32>) -> Vector2<f32> {
+        let phi = d.x.atan2(-d.z) - self.rotation;
+        Vector2::new(fract(phi / (2.0 * PI) + 0.5), d.y.clamp(-1.0, 1.0).acos() / PI)
+    }
+
+    fn direction(&self, uv: Vector2<f32>) -> Vector3<f32> {
+        let phi = (uv.x - 0.5) * 2.0 * PI + self.rotation;
+        let theta = uv.y * PI;
+        Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+    }
+
+    fn texel(&self, uv: Vector2<f32>) -> (u32, u32) {
+        (((uv.x * self.width as f32) as u32).min(self.width - 1), ((uv.y * self.height as f32) as u32).min(self.height - 1))
+    }
+
+    fn radiance(&self, d: Vector3<f32>) -> Vector3<f32> {
+        let (x, y) = self.texel(self.uv(d));
+        Vector3::from(self.map.pixels()[(y * self.width + x) as usize]) * self.intensity
+    }
+
+    fn search_cdf(&self, first: u32, count: u32, u: f32) -> u32 {
+        let cdf = self.map.cdf();
+        let mut low = 0;
+        let mut high = count - 1;
+        while low < high {
+            let middle = (low + high) / 2;
+            if cdf[(first + middle) as usize] > u {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        low
+    }
+
+    fn cdf_step(&self, first: u32, i: u32) -> f32 {
+        let cdf = self.map.cdf();
+        cdf[(first + i) as usize] - if i > 0 { cdf[(first + i - 1) as usize] } else { 0.0 }
+    }
+
+    fn pdf(&self, d: Vector3<f32>) -> f32 {
+        let sin_theta = (1.0 - d.y * d.y).max(0.0).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let (x, y) = self.texel(self.uv(d));
+        let uv_pdf = self.cdf_step(self.width * self.height, y) * self.cdf_step(y * self.width, x) * (self.width * self.height) as f32;
+
+        uv_pdf / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn sample_direction(&self, rng: &mut Rng) -> (Vector3<f32>, f32) {
+        let cdf = self.map.cdf();
+        let (width, height) = (self.width, self.height);
+
+        let u = rng.random();
+        let v = rng.random();
+
+        let y = self.search_cdf(width * height, height, v);
+        let x = self.search_cdf(y * width, width, u);
+
+        let row_pdf = self.cdf_step(width * height, y);
+        let column_pdf = self.cdf_step(y * width, x);
+
+        let dy = if row_pdf > 0.0 { ((v - cdf[(width * height + y) as usize] + row_pdf) / row_pdf).clamp(0.0, 1.0) } else { 0.5 };
+        let dx = if column_pdf > 0.0 { ((u - cdf[(y * width + x) as usize] + column_pdf) / column_pdf).clamp(0.0, 1.0) } else { 0.5 };
+
+        let uv = Vector2::new((x as f32 + dx) / width as f32, (y as f32 + dy) / height as f32);
+        let sin_theta = (uv.y * PI).sin();
+
+        let pdf = if sin_theta > 0.0 { row_pdf * column_pdf * (width * height) as f32 / (2.0 * PI * PI * sin_theta) } else { 0.0 };
+        (self.direction(uv), pdf)
+    }
+}
+
+/// The material texture array the way the shader samples it, bilinearly filtered with repeat wrapping.
+struct MaterialTextures {
+    width: u32,
+    height: u32,
+    layers: Vec<u8>,
+}
+
+impl MaterialTextures {
+    fn new(textures: &TextureBuffer) -> Self {
+        let (width, height) = textures.layer_size();
+        MaterialTextures {
+            width,
+            height,
+            layers: textures.layers(),
+        }
+    }
+
+    fn texel(&self, layer: u32, x: i64, y: i64) -> Vector4<f32> {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        let i = ((layer as usize * self.height as usize + y) * self.width as usize + x) * 4;
+        Vector4::new(self.layers[i], self.layers[i + 1], self.layers[i + 2], self.layers[i + 3]).map(|c| c as f32 / 255.0)
+    }
+
+    fn sample(&self, layer: u32, uv: Vector2<f32>) -> Vector4<f32> {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+        let (fx, fy) = (fract(x), fract(y));
+
+        let top = self.texel(layer, x0, y0).lerp(&self.texel(layer, x0 + 1, y0), fx);
+        let bottom = self.texel(layer, x0, y0 + 1).lerp(&self.texel(layer, x0 + 1, y0 + 1), fx);
+        top.lerp(&bottom, fy)
+    }
+}
+
+fn hit_sphere(ro: Vector3<f32>, rd: Vector3<f32>, center: Vector3<f32>, radius: f32) -> HitInfo {
+    let mut info = HitInfo::miss();
+    let oc = ro - center;
+    let a = rd.dot(&rd);
+    let half_b = oc.dot(&rd);
+    let c = oc.dot(&oc) - radius * radius;
+    let discr = half_b * half_b - a * c;
+    if discr < 0.0 {
+        return info;
+    }
+
+    let mut dist = (-half_b - discr.sqrt()) / a;
+
+    if dist < 0.001 {
+        dist = (-half_b + discr.sqrt()) / a;
+        if dist < 0.001 {
+            return info;
+        }
+    }
+
+    info.did_hit = true;
+    info.dist = dist;
+    let outward_normal = (ro + dist * rd - center).normalize();
+    info.front_face = rd.dot(&outward_normal) < 0.0;
+    info.normal = if info.front_face { outward_normal } else { -outward_normal };
+
+    info
+}
+
+fn hit_plane(ro: Vector3<f32>, rd: Vector3<f32>) -> HitInfo {
+    let dist = -ro.y / rd.y;
+    let mut info = HitInfo::miss();
+    info.did_hit = (0.001..MAX_DIST).contains(&dist);
+    info.dist = dist;
+    info.front_face = rd.y < 0.0;
+    info.normal = Vector3::new(0.0, if info.front_face { 1.0 } else { -1.0 }, 0.0);
+    info
+}
+
+fn hit_disk(ro: Vector3<f32>, rd: Vector3<f32>) -> HitInfo {
+    let mut info = hit_plane(ro, rd);
+    let p = ro.xz() + rd.xz() * info.dist;
+    info.did_hit = info.did_hit && p.dot(&p) <= 1.0;
+    info
+}
+
+fn hit_quad(ro: Vector3<f32>, rd: Vector3<f32>) -> HitInfo {
+    let mut info = hit_plane(ro, rd);
+    let p = ro.xz() + rd.xz() * info.dist;
+    info.did_hit = info.did_hit && p.x.abs().max(p.y.abs()) <= 1.0;
+    info
+}
+
+fn hit_box(ro: Vector3<f32>, rd: Vector3<f32>, s: Vector3<f32>) -> HitInfo {
+    let m = rd.map(|x| 1.0 / x);
+    let n = m.component_mul(&ro);
+    let k = m.abs().component_mul(&s);
+    let f = -n - k;
+    let b = -n + k;
+    let fl = f.x.max(f.y).max(f.z);
+    let bl = b.x.min(b.y).min(b.z);
+    let mut info = HitInfo::miss();
+    if fl > bl || bl < 0.001 {
+        return info;
+    }
+    let inside = fl < 0.001;
+    let no = if inside {
+        Vector3::new(
+            sign(rd.x) * step(b.x, b.y) * step(b.x, b.z),
+            sign(rd.y) * step(b.y, b.z) * step(b.y, b.x),
+            sign(rd.z) * step(b.z, b.x) * step(b.z, b.y),
+        )
+    } else {
+        Vector3::new(
+            -sign(rd.x) * step(f.y, f.x) * step(f.z, f.x),
+            -sign(rd.y) * step(f.z, f.y) * step(f.x, f.y),
+            -sign(rd.z) * step(f.x, f.z) * step(f.y, f.z),
+        )
+    };
+    info.did_hit = true;
+    info.dist = if inside { bl } else { fl };
+    info.front_face = rd.dot(&no) < 0.0;
+    info.normal = if info.front_face { no } else { -no };
+    info
+}
+
+fn hit_bounds(ro: Vector3<f32>, rd: Vector3<f32>, bounds_min: Vector3<f32>, bounds_max: Vector3<f32>, max_dist: f32) -> bool {
+    let m = rd.map(|d| 1.0 / d);
+    let t0 = (bounds_min - ro).component_mul(&m);
+    let t1 = (bounds_max - ro).component_mul(&m);
+    let near = t0.inf(&t1).max();
+    let far = t0.sup(&t1).min();
+    near <= far && far >= 0.0 && near < max_dist
+}
+
+fn mesh_position(meshes: &MeshBuffer, v: u32) -> Vector3<f32> {
+    let i = v as usize * VERTEX_SIZE;
+    Vector3::new(meshes.vertices[i], meshes.vertices[i + 1], meshes.vertices[i + 2])
+}
+
+fn mesh_normal(meshes: &MeshBuffer, v: u32) -> Vector3<f32> {
+    let i = v as usize * VERTEX_SIZE + 3;
+    Vector3::new(meshes.vertices[i], meshes.vertices[i + 1], meshes.vertices[i + 2])
+}
+
+fn mesh_uv(meshes: &MeshBuffer, v: u32) -> Vector2<f32> {
+    let i = v as usize * VERTEX_SIZE + 6;
+    Vector2::new(meshes.vertices[i], meshes.vertices[i + 1])
+}
+
+fn hit_mesh(meshes: &MeshBuffer, ro: Vector3<f32>, rd: Vector3<f32>, first_index: u32, triangle_count: u32) -> HitInfo {
+    let mut info = HitInfo::miss();
+
+    for t in 0..triangle_count {
+        let i0 = meshes.indices[(first_index + t * 3) as usize];
+        let i1 = meshes.indices[(first_index + t * 3 + 1) as usize];
+        let i2 = meshes.indices[(first_index + t * 3 + 2) as usize];
+
+        let v0 = mesh_position(meshes, i0);
+        let e1 = mesh_position(meshes, i1) - v0;
+        let e2 = mesh_position(meshes, i2) - v0;
+
+        let p = rd.cross(&e2);
+        let det = e1.dot(&p);
+        if det.abs() < 1e-9 {
+            continue;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ro - v0;
+        let u = s.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            continue;
+        }
+
+        let q = s.cross(&e1);
+        let v = rd.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            continue;
+        }
+
+        let dist = e2.dot(&q) * inv_det;
+        if dist < 0.001 || dist >= info.dist {
+            continue;
+        }
+
+        let geometric_normal = e1.cross(&e2);
+        let smooth_normal = ((1.0 - u - v) * mesh_normal(meshes, i0) + u * mesh_normal(meshes, i1) + v * mesh_normal(meshes, i2)).normalize();
+        let outward_normal = if smooth_normal.dot(&geometric_normal) < 0.0 { -smooth_normal } else { smooth_normal };
+
+        info.did_hit = true;
+        info.dist = dist;
+        info.front_face = rd.dot(&geometric_normal) < 0.0;
+        info.normal = if info.front_face { outward_normal } else { -outward_normal };
+
+        let uv0 = mesh_uv(meshes, i0);
+        let duv1 = mesh_uv(meshes, i1) - uv0;
+        let duv2 = mesh_uv(meshes, i2) - uv0;
+        info.uv = uv0 + u * duv1 + v * duv2;
+        let uv_det = duv1.x * duv2.y - duv2.x * duv1.y;
+        info.tangent = if uv_det.abs() > 1e-12 { (e1 * duv2.y - e2 * duv1.y) / uv_det } else { e1 };
+    }
+
+    info
+}
+
+fn read_material(scene: &GpuScene, i: usize) -> Material {
+    let material = &scene.materials[scene.objects[i].material as usize];
+    Material {
+        base_color: Vector3::from(material.base_color),
+        metallic: material.metallic,
+        roughness: material.roughness,
+        specular: material.specular,
+        clearcoat: material.clearcoat,
+        clearcoat_roughness: material.clearcoat_roughness,
+        subsurface: material.subsurface,
+        subsurface_color: Vector3::from(material.subsurface_color),
+        mean_free_path: Vector3::from(material.mean_free_path),
+        sheen: material.sheen,
+        transmission: material.transmission,
+        ior: material.ior,
+        absorption: Vector3::from(material.absorption),
+        // the tracer follows media by their index instead, see World::object_medium
+        medium: None,
+        // already premultiplied, keep the strength at 1 so `emission` matches the shader
+        emission: Vector3::from(material.emission),
+        emission_strength: 1.0,
+        albedo_texture: texture_slot(material.albedo_texture),
+        roughness_texture: texture_slot(material.roughness_texture),
+        metalness_texture: texture_slot(material.metalness_texture),
+        normal_texture: texture_slot(material.normal_texture),
+    }
+}
+
+fn texture_slot(layer: u32) -> Option<u32> {
+    (layer != NO_TEXTURE).then_some(layer)
+}
+
+/// Everything the shader reads from its storage buffers.
+struct World<'a> {
+    scene: &'a GpuScene,
+    lights: Vec<u32>,
+    meshes: &'a MeshBuffer,
+    textures: MaterialTextures,
+    environment: Option<Environment<'a>>,
+}
+
+impl<'a> World<'a> {
+    fn new(scene: &'a GpuScene, meshes: &'a MeshBuffer, textures: &TextureBuffer, environment: Option<&'a EnvironmentMap>) -> Self {
+        World {
+            scene,
+            lights: scene.emissive_objects(),
+            meshes,
+            textures: MaterialTextures::new(textures),
+            environment: environment.map(Environment::new),
+        }
+    }
+
+    fn object_medium(&self, i: usize) -> u32 {
+        self.scene.materials[self.scene.objects[i].material as usize].medium
+    }
+
+    fn evaluate_scattering(&self, material: &Material, n: Vector3<f32>, wo: Vector3<f32>, wi: Vector3<f32>, medium: u32) -> (Vector3<f32>, f32) {
+        if n == Vector3::zeros() {
+            let pdf = henyey_greenstein((-wo).dot(&wi), self.scene.media[medium as usize].anisotropy);
+            return (Vector3::repeat(pdf), pdf);
+        }
+        if wo == Vector3::zeros() {
+            return (Vector3::repeat(1.0 / PI), n.dot(&wi).max(0.0) / PI);
+        }
+        evaluate_bsdf(material, n, wo, wi)
+    }
+
+    fn medium_transmittance(&self, medium: u32, dist: f32) -> Vector3<f32> {
+        if medium == NO_MEDIUM {
+            return Vector3::repeat(1.0);
+        }
+        (-medium_extinction(&self.scene.media[medium as usize]) * dist).map(f32::exp)
+    }
+
+    fn sky_box(&self, rd: Vector3<f32>) -> Vector3<f32> {
+        let unit_direction = rd.normalize();
+
+        if let Some(environment) = &self.environment {
+            return environment.radiance(unit_direction);
+        }
+        if self.scene.sky.enabled != 0 {
+            return physical_sky(&self.scene.sky, unit_direction);
+        }
+
+        let t = 0.5 * (unit_direction.y + 1.0);
+        (1.0 - t) * Vector3::new(1.0, 1.0, 1.0) + t * Vector3::new(0.5, 0.7, 1.0)
+    }
+
+    fn has_sun(&self) -> bool {
+        self.scene.sky.enabled != 0 && self.scene.sky.sun_radiance != [0.0; 3]
+    }
+
+    fn sun_disk(&self, d: Vector3<f32>) -> Vector3<f32> {
+        let sky = &self.scene.sky;
+        if self.has_sun() && d.dot(&Vector3::from(sky.sun_direction)) >= sky.cos_sun_radius {
+            Vector3::from(sky.sun_radiance)
+        } else {
+            Vector3::zeros()
+        }
+    }
+
+    fn model_matrix(&self, i: usize) -> Matrix4<f32> {
+        Matrix4::from(self.scene.objects[i].world_to_local)
+    }
+
+    fn object_type(&self, i: usize) -> u32 {
+        self.scene.objects[i].object_type
+    }
+}
+
+/// What a reference render sees besides the scene itself.
+///
+/// The defaults are an 800x600 image at time 0 without meshes, textures or an environment map, seen from
+/// the camera of a scene file without a `[camera]` through the projection the headless mode uses.
+pub struct RenderInputs<'a> {
+    pub meshes: Option<&'a MeshBuffer>,
+    pub textures: Option<&'a TextureBuffer>,
+    pub environment: Option<&'a EnvironmentMap>,
+    pub camera: Option<&'a Camera>,
+    /// Defaults to the headless mode's for `width` by `height`.
+    pub projection: Option<Perspective3<f32>>,
+    pub width: u32,
+    pub height: u32,
+    pub time: f32,
+}
+
+impl Default for RenderInputs<'_> {
+    fn default() -> Self {
+        RenderInputs {
+            meshes: None,
+            textures: None,
+            environment: None,
+            camera: None,
+            projection: None,
+            width: 800,
+            height: 600,
+            time: 0.0,
+        }
+    }
+}
+
+/// Renders a scene on the CPU the same way `main.fsh` does on the GPU for the first accumulated frame.
+pub struct ReferenceRenderer {
+    pub max_bounces: u32,
+    pub samples_per_pixel: u32,
+}
+
+impl Default for ReferenceRenderer {
+    /// Matches the defines `main.fsh` is compiled with.
+    fn default() -> Self {
+        ReferenceRenderer {
+            max_bounces: 10,
+            samples_per_pixel: 10,
+        }
+    }
+}
+
+impl ReferenceRenderer {
+    // tests every object instead of walking the BVH like the shader, the closest hit comes out the same
+    fn hit_world(&self, world: &World, ro: Vector3<f32>, rd: Vector3<f32>) -> HitInfo {
+        let mut info = HitInfo::miss();
+        for (i, object) in world.scene.objects.iter().enumerate() {
+            let model_matrix = world.model_matrix(i);
+
+            let transformed_ro = (model_matrix * ro.push(1.0)).xyz();
+            let transformed_rd = (model_matrix * rd.push(0.0)).xyz();
+
+            let temp_info = if object.object_type == GpuObjectType::Sphere as u32 {
+                hit_sphere(transformed_ro, transformed_rd, Vector3::zeros(), 1.0)
+            } else if object.object_type == GpuObjectType::Box as u32 {
+                hit_box(transformed_ro, transformed_rd, Vector3::new(1.0, 1.0, 1.0))
+            } else if object.object_type == GpuObjectType::Plane as u32 {
+                hit_plane(transformed_ro, transformed_rd)
+            } else if object.object_type == GpuObjectType::Disk as u32 {
+                hit_disk(transformed_ro, transformed_rd)
+            } else if object.object_type == GpuObjectType::Quad as u32 {
+                hit_quad(transformed_ro, transformed_rd)
+            } else if object.object_type == GpuObjectType::Mesh as u32 {
+                let bounds_min = Vector4::from(object.bounds_min).xyz();
+                let bounds_max = Vector4::from(object.bounds_max).xyz();
+                if hit_bounds(transformed_ro, transformed_rd, bounds_min, bounds_max, info.dist) {
+                    hit_mesh(world.meshes, transformed_ro, transformed_rd, object.first_index, object.triangle_count)
+                } else {
+                    HitInfo::miss()
+                }
+            } else {
+                continue;
+            };
+
+            if temp_info.did_hit && temp_info.dist < info.dist {
+                info = temp_info;
+                info.normal = (model_matrix.transpose() * info.normal.push(0.0)).xyz().normalize();
+                info.material = read_material(world.scene, i);
+                info.object_index = i;
+            }
+        }
+
+        info
+    }
+
+    fn light_pdf(&self, world: &World, i: usize, ro: Vector3<f32>, p: Vector3<f32>) -> f32 {
+        let model_matrix = world.model_matrix(i);
+        let local_point = (model_matrix * p.push(1.0)).xyz();
+        let local_normal = local_surface_normal(world.object_type(i), local_point);
+
+        let (area_pdf, light_normal) = light_area_pdf(&model_matrix, world.object_type(i), local_normal);
+
+        let to_light = p - ro;
+        let dist2 = to_light.dot(&to_light);
+        let cos_light = light_normal.dot(&to_light).abs() / dist2.sqrt();
+
+        area_pdf * dist2 / cos_light.max(1e-6) / world.lights.len() as f32
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_lights(&self, world: &World, rng: &mut Rng, p: Vector3<f32>, n: Vector3<f32>, wo: Vector3<f32>, material: &Material, medium: u32) -> Vector3<f32> {
+        let light = ((rng.random() * world.lights.len() as f32) as usize).min(world.lights.len() - 1);
+        let i = world.lights[light] as usize;
+        let model_matrix = world.model_matrix(i);
+
+        let (local_point, local_normal) = rng.local_surface_sample(world.object_type(i));
+
+        let (area_pdf, light_normal) = light_area_pdf(&model_matrix, world.object_type(i), local_normal);
+        let light_point = (model_matrix.try_inverse().unwrap_or_else(Matrix4::identity) * local_point.push(1.0)).xyz();
+
+        let to_light = light_point - p;
+        let dist2 = to_light.dot(&to_light);
+        let wi = to_light / dist2.sqrt();
+
+        let cos_surface = scattering_cosine(n, wi);
+        let cos_light = light_normal.dot(&-wi);
+        if cos_surface <= 0.0 || cos_light <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let shadow = self.hit_world(world, p + n * 1e-4, wi);
+        if !shadow.did_hit || shadow.object_index != i {
+            return Vector3::zeros();
+        }
+
+        let pdf = area_pdf * dist2 / cos_light / world.lights.len() as f32;
+        let (bsdf, bsdf_pdf) = world.evaluate_scattering(material, n, wo, wi, medium);
+
+        bsdf.component_mul(&(shadow.material.emission * shadow.material.emission_strength))
+            .component_mul(&world.medium_transmittance(medium, shadow.dist)) * cos_surface * power_heuristic(pdf, bsdf_pdf) / pdf
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_analytic_lights(&self, world: &World, rng: &mut Rng, p: Vector3<f32>, n: Vector3<f32>, wo: Vector3<f32>, material: &Material, medium: u32) -> Vector3<f32> {
+        let mut result = Vector3::zeros();
+
+        for light in &world.scene.lights {
+            let intensity = Vector3::from(light.intensity);
+
+            let (wi, dist, irradiance) = if light.light_type == GpuLightType::Directional as u32 {
+                (rng.cone_sample(-Vector3::from(light.direction), light.cos_outer), MAX_DIST, intensity)
+            } else {
+                let to_light = Vector3::from(light.position) - p;
+                let dist2 = to_light.dot(&to_light);
+                let dist = dist2.sqrt();
+                let wi = to_light / dist;
+                let mut irradiance = intensity / dist2;
+                if light.light_type == GpuLightType::Spot as u32 {
+                    irradiance *= spot_falloff(light, -wi);
+                }
+                (wi, dist, irradiance)
+            };
+
+            let cos_surface = scattering_cosine(n, wi);
+            if cos_surface <= 0.0 || irradiance == Vector3::zeros() {
+                continue;
+            }
+
+            let shadow = self.hit_world(world, p + n * 1e-4, wi);
+            if shadow.did_hit && shadow.dist < dist {
+                continue;
+            }
+
+            result += world.evaluate_scattering(material, n, wo, wi, medium).0.component_mul(&irradiance).component_mul(&world.medium_transmittance(medium, dist)) * cos_surface;
+        }
+
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_environment(&self, world: &World, environment: &Environment, rng: &mut Rng, p: Vector3<f32>, n: Vector3<f32>, wo: Vector3<f32>, material: &Material, medium: u32) -> Vector3<f32> {
+        let (wi, pdf) = environment.sample_direction(rng);
+
+        let cos_surface = scattering_cosine(n, wi);
+        if pdf <= 0.0 || cos_surface <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        if self.hit_world(world, p + n * 1e-4, wi).did_hit {
+            return Vector3::zeros();
+        }
+
+        let (bsdf, bsdf_pdf) = world.evaluate_scattering(material, n, wo, wi, medium);
+
+        bsdf.component_mul(&world.sky_box(wi)).component_mul(&world.medium_transmittance(medium, MAX_DIST)) * cos_surface * power_heuristic(pdf, bsdf_pdf) / pdf
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_sun(&self, world: &World, rng: &mut Rng, p: Vector3<f32>, n: Vector3<f32>, wo: Vector3<f32>, material: &Material, medium: u32) -> Vector3<f32> {
+        let sky = &world.scene.sky;
+        let wi = rng.cone_sample(Vector3::from(sky.sun_direction), sky.cos_sun_radius);
+
+        let cos_surface = scattering_cosine(n, wi);
+        if cos_surface <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        if self.hit_world(world, p + n * 1e-4, wi).did_hit {
+            return Vector3::zeros();
+        }
+
+        let pdf = 1.0 / sky.sun_solid_angle;
+        let (bsdf, bsdf_pdf) = world.evaluate_scattering(material, n, wo, wi, medium);
+
+        bsdf.component_mul(&Vector3::from(sky.sun_radiance)).component_mul(&world.medium_transmittance(medium, MAX_DIST)) * cos_surface * power_heuristic(pdf, bsdf_pdf) / pdf
+    }
+
+    fn apply_textures(&self, world: &World, info: &mut HitInfo, p: Vector3<f32>) {
+        let world_to_local = world.model_matrix(info.object_index);
+        let local_point = (world_to_local * p.push(1.0)).xyz();
+        let object_type = world.object_type(info.object_index);
+        if object_type == GpuObjectType::Sphere as u32 {
+            (info.uv, info.tangent) = sphere_uv(local_point);
+        } else if object_type == GpuObjectType::Box as u32 {
+            (info.uv, info.tangent) = box_uv(local_point);
+        } else if object_type == GpuObjectType::Plane as u32 || object_type == GpuObjectType::Disk as u32 || object_type == GpuObjectType::Quad as u32 {
+            (info.uv, info.tangent) = plane_uv(local_point);
+        }
+
+        let material = &mut info.material;
+        if let Some(layer) = material.albedo_texture {
+            let albedo = world.textures.sample(layer, info.uv).xyz().map(|c| c.powf(2.2));
+            material.base_color = material.base_color.component_mul(&albedo);
+            material.subsurface_color = material.subsurface_color.component_mul(&albedo);
+        }
+        if let Some(layer) = material.roughness_texture {
+            material.roughness = world.textures.sample(layer, info.uv).y;
+        }
+        if let Some(layer) = material.metalness_texture {
+            material.metallic = world.textures.sample(layer, info.uv).z;
+        }
+        if let Some(layer) = material.normal_texture {
+            let outward_normal = if info.front_face { info.normal } else { -info.normal };
+
+            let local_to_world = world_to_local.fixed_view::<3, 3>(0, 0).try_inverse().unwrap_or_else(Matrix3::identity);
+            let mut t = local_to_world * info.tangent;
+            t -= outward_normal * t.dot(&outward_normal);
+            t = if t.dot(&t) > 1e-12 { t.normalize() } else { orthonormal_basis(outward_normal).0 };
+            let b = outward_normal.cross(&t);
+
+            let m = world.textures.sample(layer, info.uv).xyz() * 2.0 - Vector3::repeat(1.0);
+            let mapped = (t * m.x + b * m.y + outward_normal * m.z).normalize();
+            info.normal = if info.front_face { mapped } else { -mapped };
+        }
+    }
+
+    /// Mirror of `randomWalk`, returns the normal facing out of where the path left the object.
+    fn random_walk(&self, world: &World, rng: &mut Rng, m: &Material, ro: &mut Vector3<f32>, rd: &mut Vector3<f32>, att: &mut Vector3<f32>) -> Option<Vector3<f32>> {
+        let extinction = m.mean_free_path.map(|d| 1.0 / d);
+        let scattering = subsurface_albedo(m.subsurface_color).component_mul(&extinction);
+
+        for _ in 0..MAX_WALK_STEPS {
+            let info = self.hit_world(world, *ro, *rd);
+            if !info.did_hit {
+                return None;
+            }
+
+            let t = rng.free_flight_sample(extinction);
+            if t >= info.dist {
+                *att = att.component_mul(&free_flight_weight(extinction, scattering, info.dist, false));
+                *ro += *rd * info.dist;
+                return Some(if rd.dot(&info.normal) > 0.0 { info.normal } else { -info.normal });
+            }
+
+            *att = att.component_mul(&free_flight_weight(extinction, scattering, t, true));
+            *ro += *rd * t;
+            *rd = rng.random_unit_vector();
+        }
+
+        None
+    }
+
+    fn trace(&self, world: &World, rng: &mut Rng, ray_origin: Vector3<f32>, ray_direction: Vector3<f32>) -> Vector3<f32> {
+        let mut ro = ray_origin;
+        let mut rd = ray_direction;
+
+        let mut col = Vector3::zeros();
+        let mut att = Vector3::new(1.0, 1.0, 1.0);
+
+        let mut light_sampled = false;
+        let mut environment_sampled = false;
+        let mut sun_sampled = false;
+        let mut bsdf_pdf = 0.0;
+        let mut medium = world.scene.medium;
+
+        for _ in 0..self.max_bounces {
+            let mut info = self.hit_world(world, ro, rd);
+
+            if medium != NO_MEDIUM {
+                let gpu_medium = &world.scene.media[medium as usize];
+                let t = rng.free_flight_sample(medium_extinction(gpu_medium));
+                if t < info.dist {
+                    att = att.component_mul(&medium_weight(gpu_medium, t, true));
+                    ro += rd * t;
+                    let wo = -rd;
+                    let particle = Vector3::zeros();
+
+                    light_sampled = !world.lights.is_empty();
+                    if light_sampled {
+                        col += att.component_mul(&self.sample_lights(world, rng, ro, particle, wo, &info.material, medium));
+                    }
+                    col += att.component_mul(&self.sample_analytic_lights(world, rng, ro, particle, wo, &info.material, medium));
+                    environment_sampled = world.environment.is_some();
+                    if let Some(environment) = &world.environment {
+                        col += att.component_mul(&self.sample_environment(world, environment, rng, ro, particle, wo, &info.material, medium));
+                    }
+                    sun_sampled = world.has_sun();
+                    if sun_sampled {
+                        col += att.component_mul(&self.sample_sun(world, rng, ro, particle, wo, &info.material, medium));
+                    }
+
+                    let g = gpu_medium.anisotropy;
+                    rd = rng.henyey_greenstein_sample(rd, g);
+                    bsdf_pdf = henyey_greenstein((-wo).dot(&rd), g);
+                    continue;
+                }
+                att = att.component_mul(&medium_weight(gpu_medium, info.dist, false));
+            }
+
+            if !info.did_hit {
+                let weight = match &world.environment {
+                    Some(environment) if environment_sampled => power_heuristic(bsdf_pdf, environment.pdf(rd.normalize())),
+                    _ => 1.0,
+                };
+                col += att.component_mul(&world.sky_box(rd)) * weight;
+
+                let sun_weight = if sun_sampled { power_heuristic(bsdf_pdf, 1.0 / world.scene.sky.sun_solid_angle) } else { 1.0 };
+                col += att.component_mul(&world.sun_disk(rd.normalize())) * sun_weight;
+                break;
+            }
+
+            let hit_position = ro + rd * info.dist;
+
+            if !info.front_face {
+                att = att.component_mul(&(-info.material.absorption * info.dist * rd.norm()).map(f32::exp));
+            }
+
+            let material = &info.material;
+            if material.albedo_texture.is_some() || material.roughness_texture.is_some() || material.metalness_texture.is_some() || material.normal_texture.is_some() {
+                self.apply_textures(world, &mut info, hit_position);
+            }
+
+            let emission = info.material.emission * info.material.emission_strength;
+            let object_type = world.object_type(info.object_index);
+            let one_sided = object_type == GpuObjectType::Disk as u32 || object_type == GpuObjectType::Quad as u32;
+            if emission != Vector3::zeros() && (info.front_face || !one_sided) {
+                let weight = if light_sampled && object_type != GpuObjectType::Mesh as u32 && object_type != GpuObjectType::Plane as u32 {
+                    power_heuristic(bsdf_pdf, self.light_pdf(world, info.object_index, ro, hit_position))
+                } else {
+                    1.0
+                };
+                col += att.component_mul(&emission) * weight;
+            }
+
+            ro = hit_position;
+
+            let wo = -rd.normalize();
+            let facing_normal = if rd.dot(&info.normal) < 0.0 { info.normal } else { -info.normal };
+
+            light_sampled = false;
+            environment_sampled = false;
+            sun_sampled = false;
+
+            let material = &info.material;
+            if transmission_amount(material) < 1.0 {
+                if !world.lights.is_empty() {
+                    col += att.component_mul(&self.sample_lights(world, rng, ro, facing_normal, wo, material, medium));
+                    light_sampled = true;
+                }
+                col += att.component_mul(&self.sample_analytic_lights(world, rng, ro, facing_normal, wo, material, medium));
+                if let Some(environment) = &world.environment {
+                    col += att.component_mul(&self.sample_environment(world, environment, rng, ro, facing_normal, wo, material, medium));
+                    environment_sampled = true;
+                }
+                if world.has_sun() {
+                    col += att.component_mul(&self.sample_sun(world, rng, ro, facing_normal, wo, material, medium));
+                    sun_sampled = true;
+                }
+            }
+
+            let bsdf = sample_bsdf(rng, material, facing_normal, wo, info.front_face);
+            if bsdf.weight == Vector3::zeros() {
+                break;
+            }
+            if bsdf.is_subsurface {
+                att = att.component_mul(&bsdf.weight);
+                rd = bsdf.direction;
+                let Some(exit_normal) = self.random_walk(world, rng, material, &mut ro, &mut rd, &mut att) else {
+                    break;
+                };
+                let diffuse_exit = Vector3::zeros();
+
+                light_sampled = !world.lights.is_empty();
+                if light_sampled {
+                    col += att.component_mul(&self.sample_lights(world, rng, ro, exit_normal, diffuse_exit, material, medium));
+                }
+                col += att.component_mul(&self.sample_analytic_lights(world, rng, ro, exit_normal, diffuse_exit, material, medium));
+                environment_sampled = world.environment.is_some();
+                if let Some(environment) = &world.environment {
+                    col += att.component_mul(&self.sample_environment(world, environment, rng, ro, exit_normal, diffuse_exit, material, medium));
+                }
+                sun_sampled = world.has_sun();
+                if sun_sampled {
+                    col += att.component_mul(&self.sample_sun(world, rng, ro, exit_normal, diffuse_exit, material, medium));
+                }
+
+                let mut weight = 0.0;
+                rd = rng.hemisphere_cosine_sample(exit_normal, &mut weight);
+                bsdf_pdf = exit_normal.dot(&rd) / PI;
+                continue;
+            }
+            if bsdf.is_delta {
+                light_sampled = false;
+                environment_sampled = false;
+                sun_sampled = false;
+            }
+
+            if bsdf.direction.dot(&facing_normal) < 0.0 {
+                medium = if info.front_face { world.object_medium(info.object_index) } else { world.scene.medium };
+            }
+
+            bsdf_pdf = bsdf.pdf;
+            att = att.component_mul(&bsdf.weight);
+            rd = bsdf.direction;
+        }
+
+        col
+    }
+
+    /// Shades a single pixel and returns the tonemapped, gamma corrected color.
+    ///
+    /// `frag_coord` is in window coordinates with the origin at the bottom left like `gl_FragCoord`.
+    pub fn shade_pixel(&self, scene: &GpuScene, inputs: &RenderInputs, frag_coord: Vector2<f32>) -> Vector3<f32> {
+        self.with_inputs(scene, inputs, |world, camera, projection, resolution| {
+            self.shade(world, camera, projection, frag_coord, resolution, inputs.time)
+        })
+    }
+
+    // fills in the defaults of `inputs` for `f`
+    fn with_inputs<T>(&self, scene: &GpuScene, inputs: &RenderInputs, f: impl FnOnce(&World, &Camera, &Perspective3<f32>, Vector2<f32>) -> T) -> T {
+        let meshes = MeshBuffer::new();
+        let textures = TextureBuffer::new();
+        let camera = Camera::new(Vector3::new(0.0, 0.0, 3.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(-90.0, 0.0, 0.0));
+
+        let world = World::new(scene, inputs.meshes.unwrap_or(&meshes), inputs.textures.unwrap_or(&textures), inputs.environment);
+        let projection = inputs.projection.unwrap_or_else(|| Perspective3::new(inputs.width as f32 / inputs.height as f32, 45.0, 0.1, 100.0));
+        let resolution = Vector2::new(inputs.width as f32, inputs.height as f32);
+        f(&world, inputs.camera.unwrap_or(&camera), &projection, resolution)
+    }
+
+    fn shade(&self, world: &World, camera: &Camera, projection: &Perspective3<f32>, frag_coord: Vector2<f32>, resolution: Vector2<f32>, time: f32) -> Vector3<f32> {
+        let seed = (frag_coord.x.to_bits() as i32)
+            .wrapping_add((frag_coord.y * 5741.0).to_bits() as i32)
+            .wrapping_add((time * 26717.0).to_bits() as i32);
+        let mut rng = Rng { seed: seed as u32 };
+
+        let tex_coord = frag_coord.component_div(&resolution);
+        let ray_direction = ray_direction(camera, projection, tex_coord);
+
+        let mut color = Vector3::zeros();
+        let mut samples = 0;
+        for _ in 0..self.samples_per_pixel {
+            let radiance = self.trace(world, &mut rng, camera.position, ray_direction);
+            if radiance.iter().all(|c| c.is_finite()) {
+                color += radiance;
+                samples += 1;
+            }
+        }
+
+        if samples > 0 {
+            color /= samples as f32;
+        }
+
+        tonemap_aces(color).map(|c| c.powf(1.0 / 2.2))
+    }
+
+    /// Renders a full image as RGBA8 with the top row first, the same layout the headless mode writes out.
+    pub fn render(&self, scene: &GpuScene, inputs: &RenderInputs) -> Vec<u8> {
+        self.with_inputs(scene, inputs, |world, camera, projection, resolution| {
+            let mut pixels = Vec::with_capacity(inputs.width as usize * inputs.height as usize * 4);
+
+            for y in (0..inputs.height).rev() {
+                for x in 0..inputs.width {
+                    let frag_coord = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                    let color = self.shade(world, camera, projection, frag_coord, resolution, inputs.time);
+
+                    for c in color.iter() {
+                        pixels.push((c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                    }
+                    pixels.push(255);
+                }
+            }
+
+            pixels
+        })
+    }
+}
+
+fn ray_direction(camera: &Camera, projection: &Perspective3<f32>, uv: Vector2<f32>) -> Vector3<f32> {
+    let clip_space = Vector4::new(uv.x * 2.0 - 1.0, uv.y * 2.0 - 1.0, 1.0, 1.0);
+    let mut eye_space = projection.inverse() * clip_space;
+    eye_space.z = -1.0;
+    eye_space.w = 0.0;
+    let world_space = camera.get_view_matrix().try_inverse().unwrap_or_else(Matrix4::identity) * eye_space;
+    world_space.xyz().normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orthonormal_basis_works_in_every_direction() {
+        // (1, 1, 1) is where crossing a direction with its own swizzle breaks down
+        let directions = [Vector3::new(1.0, 1.0, 1.0), Vector3::new(-1.0, -1.0, -1.0), Vector3::z(), -Vector3::z(), Vector3::x(), Vector3::new(0.3, -0.2, -0.9)];
+        for n in directions.map(|d| d.normalize()) {
+            let (t, b) = orthonormal_basis(n);
+            assert!((t.norm() - 1.0).abs() < 1e-5 && (b.norm() - 1.0).abs() < 1e-5, "{:?} gave {:?} and {:?}", n, t, b);
+            assert!(t.dot(&n).abs() < 1e-5 && b.dot(&n).abs() < 1e-5 && t.dot(&b).abs() < 1e-5);
+            assert!((n.cross(&t) - b).norm() < 1e-5);
+        }
+    }
+}
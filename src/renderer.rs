@@ -0,0 +1,698 @@
+use nalgebra::Matrix4;
+
+use std::mem;
+use std::ptr;
+
+use crate::camera::Camera;
+use crate::environment::EnvironmentMap;
+use crate::gpu::{self, GpuScene, GpuSky};
+use crate::mesh::MeshBuffer;
+use crate::shader::{Shader, ShaderType};
+use crate::texture::TextureBuffer;
+
+/// Pair of floating point render targets, one holding the running average and one being written.
+struct AccumulationBuffer {
+    textures: [u32; 2],
+    fbos: [u32; 2],
+    // index of the target holding the latest average
+    current: usize,
+}
+
+impl AccumulationBuffer {
+    fn new(width: u32, height: u32) -> Self {
+        let mut textures = [0u32; 2];
+        let mut fbos = [0u32; 2];
+
+        unsafe {
+            gl::GenTextures(2, textures.as_mut_ptr());
+            gl::GenFramebuffers(2, fbos.as_mut_ptr());
+
+            for i in 0..2 {
+                gl::BindTexture(gl::TEXTURE_2D, textures[i]);
+                gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA32F as i32, width as i32, height as i32, 0, gl::RGBA, gl::FLOAT, ptr::null());
+                // the path tracer uses texelFetch, filtering only matters when scaling to the output
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+                gl::BindFramebuffer(gl::FRAMEBUFFER, fbos[i]);
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, textures[i], 0);
+            }
+
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+
+        AccumulationBuffer {
+            textures,
+            fbos,
+            current: 0,
+        }
+    }
+
+    fn swap(&mut self) {
+        self.current = 1 - self.current;
+    }
+}
+
+impl Drop for AccumulationBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(2, self.fbos.as_ptr());
+            gl::DeleteTextures(2, self.textures.as_ptr());
+        }
+    }
+}
+
+/// Which kind of program traces the paths, both produce the same image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A fragment shader drawn over a full screen quad into the accumulation buffer.
+    #[default]
+    Fragment,
+    /// A compute shader writing the accumulation buffer as an image, in workgroups of a configurable size.
+    Compute,
+}
+
+impl Backend {
+    // the stage of the tracing program holding the path tracer and its defines
+    fn tracer_stage(self) -> ShaderType {
+        match self {
+            Backend::Fragment => ShaderType::Fragment,
+            Backend::Compute => ShaderType::Compute,
+        }
+    }
+}
+
+/// Owns the GL state needed to trace paths with either backend and draw the result.
+///
+/// Every frame is blended into an accumulation buffer so the image converges while nothing
+/// changes. Uploading different scene data or drawing from a different camera starts over.
+///
+/// Paths are traced at the render size, which doesn't have to match the output size. The image
+/// is scaled to fit the output, keeping its aspect ratio with black bars filling the rest.
+pub struct Renderer {
+    pub shader: Shader,
+    backend: Backend,
+    pub display_shader: Shader,
+
+    vao: u32,
+    vbo: u32,
+    scene_ssbo: u32,
+    material_ssbo: u32,
+    light_ssbo: u32,
+    analytic_light_ssbo: u32,
+    mesh_vertex_ssbo: u32,
+    mesh_index_ssbo: u32,
+    bvh_ssbo: u32,
+    environment_ssbo: u32,
+    environment_texture: u32,
+    sky_ssbo: u32,
+    medium_ssbo: u32,
+    material_textures: u32,
+
+    accumulation: AccumulationBuffer,
+    width: u32,
+    height: u32,
+    output_width: u32,
+    output_height: u32,
+    sample_count: u32,
+
+    // None until the first upload, so even an empty scene gets its buffers filled
+    last_scene: Option<GpuScene>,
+    last_view: Matrix4<f32>,
+    last_projection: Matrix4<f32>,
+}
+
+impl Renderer {
+    /// Needs a current GL context with the function pointers already loaded.
+    ///
+    /// Renders and outputs at `width` by `height` until told otherwise, tracing with `backend`.
+    pub fn new(width: u32, height: u32, backend: Backend) -> Result<Self, String> {
+        let mut shader = match backend {
+            Backend::Fragment => Shader::new("main"),
+            Backend::Compute => Shader::new_compute("main"),
+        }.map_err(|e| e.to_string())?;
+
+        shader.inject("gpu_layout", &gpu::glsl_definitions(), backend.tracer_stage());
+
+        shader.compile().map_err(|e| e.to_string())?;
+
+        let mut display_shader = Shader::new("display").map_err(|e| e.to_string())?;
+
+        display_shader.compile().map_err(|e| e.to_string())?;
+
+        let verticies: [f32; 24] = [
+            -1.0, 1.0, 0.0, 1.0,
+            -1.0, -1.0, 0.0, 0.0,
+            1.0, -1.0, 1.0, 0.0,
+
+            -1.0, 1.0, 0.0, 1.0,
+            1.0, -1.0, 1.0, 0.0,
+            1.0, 1.0, 1.0, 1.0
+        ];
+
+        let mut vbo: u32 = 0;
+        let mut vao: u32 = 0;
+        let mut scene_ssbo: u32 = 0;
+        let mut material_ssbo: u32 = 0;
+        let mut light_ssbo: u32 = 0;
+        let mut analytic_light_ssbo: u32 = 0;
+        let mut mesh_vertex_ssbo: u32 = 0;
+        let mut mesh_index_ssbo: u32 = 0;
+        let mut bvh_ssbo: u32 = 0;
+        let mut environment_ssbo: u32 = 0;
+        let mut environment_texture: u32 = 0;
+        let mut sky_ssbo: u32 = 0;
+        let mut medium_ssbo: u32 = 0;
+        let mut material_textures: u32 = 0;
+
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::GenBuffers(1, &mut vbo);
+
+            gl::BindVertexArray(vao);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(gl::ARRAY_BUFFER, (verticies.len() * mem::size_of::<f32>()) as isize, verticies.as_ptr() as *const _, gl::STATIC_DRAW);
+
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 4 * mem::size_of::<f32>() as i32, ptr::null());
+            gl::EnableVertexAttribArray(0);
+
+            gl::VertexAttribPointer(1, 2, gl::FLOAT, gl::FALSE, 4 * mem::size_of::<f32>() as i32, (2 * mem::size_of::<f32>()) as *const _);
+            gl::EnableVertexAttribArray(1);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::BindVertexArray(0);
+
+            gl::GenBuffers(1, &mut scene_ssbo);
+            gl::GenBuffers(1, &mut material_ssbo);
+            gl::GenBuffers(1, &mut light_ssbo);
+            gl::GenBuffers(1, &mut analytic_light_ssbo);
+            gl::GenBuffers(1, &mut mesh_vertex_ssbo);
+            gl::GenBuffers(1, &mut mesh_index_ssbo);
+            gl::GenBuffers(1, &mut bvh_ssbo);
+            gl::GenBuffers(1, &mut environment_ssbo);
+            gl::GenBuffers(1, &mut sky_ssbo);
+            gl::GenBuffers(1, &mut medium_ssbo);
+
+            gl::GenTextures(1, &mut environment_texture);
+            gl::BindTexture(gl::TEXTURE_2D, environment_texture);
+            // the shader picks texels itself so they match what importance sampling assumed
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+
+            gl::GenTextures(1, &mut material_textures);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, material_textures);
+            // paths land anywhere on a surface, so there are no derivatives to pick mip levels with
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, 0);
+
+            // scenes without meshes never call upload_meshes
+            upload_storage_buffer::<(), f32>(mesh_vertex_ssbo, &(), &[]);
+            upload_storage_buffer::<(), u32>(mesh_index_ssbo, &(), &[]);
+        }
+
+        let mut renderer = Renderer {
+            shader,
+            backend,
+            display_shader,
+            vao,
+            vbo,
+            scene_ssbo,
+            material_ssbo,
+            light_ssbo,
+            analytic_light_ssbo,
+            mesh_vertex_ssbo,
+            mesh_index_ssbo,
+            bvh_ssbo,
+            environment_ssbo,
+            environment_texture,
+            sky_ssbo,
+            medium_ssbo,
+            material_textures,
+            accumulation: AccumulationBuffer::new(width, height),
+            width,
+            height,
+            output_width: width,
+            output_height: height,
+            sample_count: 0,
+            last_scene: None,
+            last_view: Matrix4::zeros(),
+            last_projection: Matrix4::zeros(),
+        };
+
+        // scenes without an environment map or textures never upload them
+        renderer.upload_environment(None);
+        renderer.upload_textures(&TextureBuffer::new());
+
+        Ok(renderer)
+    }
+
+    /// Kind of program the paths are traced with.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Resolution paths are traced at.
+    pub fn render_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Aspect ratio of the rendered image, the projection should be built with this.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    /// Changes the resolution paths are traced at, reallocating the accumulation buffer if it differs.
+    pub fn set_render_size(&mut self, width: u32, height: u32) {
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+
+        self.accumulation = AccumulationBuffer::new(width, height);
+        self.width = width;
+        self.height = height;
+        self.reset_accumulation();
+    }
+
+    /// Changes the size of the framebuffer `draw` outputs to, usually the window's.
+    pub fn set_output_size(&mut self, width: u32, height: u32) {
+        self.output_width = width;
+        self.output_height = height;
+    }
+
+    /// Recompiles the shaders whose sources changed on disk, the old programs stay in use if that fails.
+    pub fn reload_shaders(&mut self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        match self.shader.reload_if_changed() {
+            // the accumulated samples came from the old shader
+            Ok(true) => self.reset_accumulation(),
+            Ok(false) => (),
+            Err(e) => errors.push(e.to_string()),
+        }
+        if let Err(e) = self.display_shader.reload_if_changed() {
+            errors.push(e.to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Bounces per path the current shader permutation was built with.
+    pub fn max_bounces(&self) -> u32 {
+        self.compiled_define("MAX_BOUNCES")
+    }
+
+    /// Paths traced per pixel each frame by the current shader permutation.
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.compiled_define("SAMPLES_PER_PIXEL")
+    }
+
+    /// Invocations per workgroup along x and y, always 1x1 for the fragment backend.
+    pub fn workgroup_size(&self) -> (u32, u32) {
+        match self.backend {
+            Backend::Fragment => (1, 1),
+            Backend::Compute => (self.compiled_define("WORKGROUP_SIZE_X"), self.compiled_define("WORKGROUP_SIZE_Y")),
+        }
+    }
+
+    /// Recompiles the path tracer with a different number of bounces per path.
+    pub fn set_max_bounces(&mut self, bounces: u32) -> Result<(), String> {
+        if bounces == 0 {
+            return Err("Bounces per path must be at least 1".to_string());
+        }
+        self.set_defines(&[("MAX_BOUNCES", bounces)])
+    }
+
+    /// Recompiles the path tracer tracing a different number of paths per pixel each frame.
+    pub fn set_samples_per_pixel(&mut self, samples: u32) -> Result<(), String> {
+        if samples == 0 {
+            return Err("Samples per pixel must be at least 1".to_string());
+        }
+        self.set_defines(&[("SAMPLES_PER_PIXEL", samples)])
+    }
+
+    /// Recompiles the compute tracer with `x` by `y` invocations per workgroup.
+    ///
+    /// Fails on the fragment backend, or if the driver doesn't support workgroups that large.
+    pub fn set_workgroup_size(&mut self, x: u32, y: u32) -> Result<(), String> {
+        if self.backend != Backend::Compute {
+            return Err("Only the compute backend has a workgroup size".to_string());
+        }
+        if x == 0 || y == 0 {
+            return Err("Workgroup size must be at least 1x1".to_string());
+        }
+        self.set_defines(&[("WORKGROUP_SIZE_X", x), ("WORKGROUP_SIZE_Y", y)])
+    }
+
+    fn compiled_define(&self, name: &str) -> u32 {
+        self.shader.get_compiled_define(name, self.backend.tracer_stage()).and_then(|v| v.parse().ok()).unwrap_or(0)
+    }
+
+    // recompiles the path tracer with the defines changed, keeping the old permutation if that fails
+    fn set_defines(&mut self, defines: &[(&str, u32)]) -> Result<(), String> {
+        let stage = self.backend.tracer_stage();
+
+        let values: Vec<String> = defines.iter().map(|(_, value)| value.to_string()).collect();
+        if defines.iter().zip(&values).all(|((name, _), value)| self.shader.get_compiled_define(name, stage) == Some(value.as_str())) {
+            return Ok(());
+        }
+
+        let previous: Vec<Option<String>> = defines.iter().map(|(name, _)| self.shader.get_define(name, stage).map(str::to_string)).collect();
+        for ((name, _), value) in defines.iter().zip(&values) {
+            // numbers are always valid define values
+            self.shader.add_define(name, value, stage).unwrap();
+        }
+
+        if let Err(e) = self.shader.compile() {
+            for ((name, _), previous) in defines.iter().zip(previous) {
+                match previous {
+                    // it was accepted before, so it will be again
+                    Some(previous) => self.shader.add_define(name, &previous, stage).unwrap(),
+                    None => self.shader.remove_define(name, stage),
+                }
+            }
+            return Err(e.to_string());
+        }
+
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    /// Number of frames averaged into the image that will be shown next.
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// Throws away the accumulated image, the next frame starts converging from scratch.
+    pub fn reset_accumulation(&mut self) {
+        self.sample_count = 0;
+    }
+
+    /// Uploads the objects, materials and BVH of a scene, skipping the upload if nothing changed.
+    ///
+    /// The emissive objects in it are collected into a separate buffer for the shader to sample, next to the
+    /// buffers holding its point, spot and directional lights, its sky and its media.
+    pub fn upload_scene(&mut self, scene: &GpuScene) {
+        if self.last_scene.as_ref() == Some(scene) {
+            return;
+        }
+
+        self.last_scene = Some(scene.clone());
+        self.reset_accumulation();
+
+        let lights = scene.emissive_objects();
+
+        unsafe {
+            upload_storage_buffer(self.scene_ssbo, &scene.header(), &scene.objects);
+            upload_storage_buffer(self.material_ssbo, &(), &scene.materials);
+            upload_storage_buffer(self.light_ssbo, &(lights.len() as u32), &lights);
+            upload_storage_buffer(self.analytic_light_ssbo, &[scene.lights.len() as u32, 0, 0, 0], &scene.lights);
+            upload_storage_buffer::<GpuSky, u32>(self.sky_ssbo, &scene.sky, &[]);
+            upload_storage_buffer(self.medium_ssbo, &[scene.media.len() as u32, scene.medium, 0, 0], &scene.media);
+            // std430 aligns the nodes to 16 bytes, so the count gets padded out
+            upload_storage_buffer(self.bvh_ssbo, &[scene.bvh.len() as u32, 0, 0, 0], &scene.bvh);
+        }
+    }
+
+    /// Uploads the vertices and indices every `Mesh` in the scene points into.
+    pub fn upload_meshes(&mut self, meshes: &MeshBuffer) {
+        self.reset_accumulation();
+
+        unsafe {
+            upload_storage_buffer(self.mesh_vertex_ssbo, &(), &meshes.vertices);
+            upload_storage_buffer(self.mesh_index_ssbo, &(), &meshes.indices);
+        }
+    }
+
+    /// Uploads the image and CDF of the environment map surrounding the scene, or switches back to the
+    /// gradient sky with `None`.
+    ///
+    /// Changing the map's rotation or intensity only takes effect after uploading it again.
+    pub fn upload_environment(&mut self, environment: Option<&EnvironmentMap>) {
+        self.reset_accumulation();
+
+        let (header, cdf, pixels, width, height) = match environment {
+            Some(environment) => (environment.gpu_header(), environment.cdf(), environment.pixels(), environment.width(), environment.height()),
+            None => (Default::default(), &[][..], &[[0.0; 3]][..], 1, 1),
+        };
+
+        unsafe {
+            upload_storage_buffer(self.environment_ssbo, &header, cdf);
+
+            gl::BindTexture(gl::TEXTURE_2D, self.environment_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB32F as i32, width as i32, height as i32, 0, gl::RGB, gl::FLOAT, pixels.as_ptr() as *const _);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+    }
+
+    /// Uploads the images the materials' texture slots point to, as the layers of one texture array.
+    pub fn upload_textures(&mut self, textures: &TextureBuffer) {
+        self.reset_accumulation();
+
+        let (width, height) = textures.layer_size();
+        // a texture array needs at least one layer, no material points to this one
+        let (layers, count) = if textures.is_empty() { (vec![255; 4], 1) } else { (textures.layers(), textures.len()) };
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.material_textures);
+            gl::TexImage3D(gl::TEXTURE_2D_ARRAY, 0, gl::RGBA8 as i32, width as i32, height as i32, count as i32, 0, gl::RGBA, gl::UNSIGNED_BYTE, layers.as_ptr() as *const _);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, 0);
+        }
+    }
+
+    /// Traces one more frame into the accumulation buffer and draws the tonemapped average into `target`,
+    /// which has to be at least the output size.
+    pub fn draw(&mut self, camera: &Camera, projection: &nalgebra::Perspective3<f32>, time: f32, target: u32) {
+        let view = camera.get_view_matrix();
+        let projection = projection.to_homogeneous();
+
+        if view != self.last_view || projection != self.last_projection {
+            self.last_view = view;
+            self.last_projection = projection;
+            self.reset_accumulation();
+        }
+
+        let previous = self.accumulation.current;
+        self.accumulation.swap();
+        let current = self.accumulation.current;
+
+        unsafe {
+            self.shader.use_program();
+
+            self.shader.set_uniform("cameraPosition", camera.position);
+            self.shader.set_uniform("projectionMatrix", projection);
+            self.shader.set_uniform("viewMatrix", view);
+            self.shader.set_uniform("time", time);
+            self.shader.set_uniform("sampleCount", self.sample_count as i32);
+
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(gl::TEXTURE_2D, self.environment_texture);
+            self.shader.set_uniform("environmentTexture", 1);
+
+            gl::ActiveTexture(gl::TEXTURE2);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.material_textures);
+            self.shader.set_uniform("materialTextures", 2);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.accumulation.textures[previous]);
+            self.shader.set_uniform("accumulationTexture", 0);
+
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 0, self.scene_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 1, self.light_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 2, self.mesh_vertex_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 3, self.mesh_index_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 4, self.bvh_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 5, self.material_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 6, self.analytic_light_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 7, self.environment_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 8, self.sky_ssbo);
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 9, self.medium_ssbo);
+
+            gl::BindVertexArray(self.vao);
+
+            match self.backend {
+                Backend::Fragment => {
+                    gl::Viewport(0, 0, self.width as i32, self.height as i32);
+                    gl::BindFramebuffer(gl::FRAMEBUFFER, self.accumulation.fbos[current]);
+
+                    gl::DrawArrays(gl::TRIANGLES, 0, 6);
+                },
+                Backend::Compute => {
+                    gl::BindImageTexture(0, self.accumulation.textures[current], 0, gl::FALSE, 0, gl::WRITE_ONLY, gl::RGBA32F);
+
+                    // the shader skips the invocations hanging off the edge of the image
+                    let (x, y) = self.workgroup_size();
+                    gl::DispatchCompute(self.width.div_ceil(x), self.height.div_ceil(y), 1);
+
+                    // the display pass samples what was just written
+                    gl::MemoryBarrier(gl::TEXTURE_FETCH_BARRIER_BIT);
+                },
+            }
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, target);
+
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+
+            let (x, y, width, height) = self.display_viewport();
+            gl::Viewport(x, y, width, height);
+
+            self.display_shader.use_program();
+
+            gl::BindTexture(gl::TEXTURE_2D, self.accumulation.textures[current]);
+            self.display_shader.set_uniform("accumulationTexture", 0);
+
+            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+        }
+
+        self.sample_count += 1;
+    }
+
+    fn display_viewport(&self) -> (i32, i32, i32, i32) {
+        letterbox((self.width, self.height), (self.output_width, self.output_height))
+    }
+}
+
+// largest rectangle with the render size's aspect ratio that fits centered in the output, as x, y, width, height
+fn letterbox(render: (u32, u32), output: (u32, u32)) -> (i32, i32, i32, i32) {
+    let scale = (output.0 as f32 / render.0 as f32).min(output.1 as f32 / render.1 as f32);
+    let width = (render.0 as f32 * scale).round() as i32;
+    let height = (render.1 as f32 * scale).round() as i32;
+    ((output.0 as i32 - width) / 2, (output.1 as i32 - height) / 2, width, height)
+}
+
+/// Parses `--resolution <width>x<height>` out of the command line arguments, for rendering at a
+/// fixed size no matter how large the output is.
+pub fn resolution_from_args(args: &[String]) -> Result<Option<(u32, u32)>, String> {
+    size_from_args(args, "--resolution", "1280x720")
+}
+
+/// Parses `--workgroup-size <x>x<y>` out of the command line arguments, only used by the compute backend.
+pub fn workgroup_size_from_args(args: &[String]) -> Result<Option<(u32, u32)>, String> {
+    size_from_args(args, "--workgroup-size", "8x8")
+}
+
+/// Parses `--backend fragment|compute` out of the command line arguments, defaulting to the fragment backend.
+pub fn backend_from_args(args: &[String]) -> Result<Backend, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--backend") else {
+        return Ok(Backend::default());
+    };
+
+    match args.get(i + 1).map(String::as_str) {
+        Some("fragment") => Ok(Backend::Fragment),
+        Some("compute") => Ok(Backend::Compute),
+        Some(value) => Err(format!("Invalid value for --backend: {}, expected fragment or compute", value)),
+        None => Err("Missing value for --backend".to_string()),
+    }
+}
+
+fn size_from_args(args: &[String], name: &str, example: &str) -> Result<Option<(u32, u32)>, String> {
+    let Some(i) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+
+    let value = args.get(i + 1).ok_or(format!("Missing value for {}", name))?;
+    let invalid = || format!("Invalid value for {}: {}, expected something like {}", name, value, example);
+
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = width.parse().map_err(|_| invalid())?;
+    let height: u32 = height.parse().map_err(|_| invalid())?;
+
+    if width == 0 || height == 0 {
+        return Err(format!("{} must be at least 1x1", name));
+    }
+
+    Ok(Some((width, height)))
+}
+
+// writes `header` followed by `data`, pass `&()` for buffers without a header
+unsafe fn upload_storage_buffer<H: Copy, T: Copy>(buffer: u32, header: &H, data: &[T]) {
+    let header_size = mem::size_of::<H>();
+    let size = header_size + mem::size_of_val(data);
+
+    gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, buffer);
+    // binding a buffer with no storage is an error, so empty buffers still get a few bytes
+    gl::BufferData(gl::SHADER_STORAGE_BUFFER, size.max(16) as isize, ptr::null(), gl::DYNAMIC_DRAW);
+    gl::BufferSubData(gl::SHADER_STORAGE_BUFFER, 0, header_size as isize, header as *const H as *const _);
+    gl::BufferSubData(gl::SHADER_STORAGE_BUFFER, header_size as isize, mem::size_of_val(data) as isize, data.as_ptr() as *const _);
+    gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.scene_ssbo);
+            gl::DeleteBuffers(1, &self.material_ssbo);
+            gl::DeleteBuffers(1, &self.light_ssbo);
+            gl::DeleteBuffers(1, &self.analytic_light_ssbo);
+            gl::DeleteBuffers(1, &self.mesh_vertex_ssbo);
+            gl::DeleteBuffers(1, &self.mesh_index_ssbo);
+            gl::DeleteBuffers(1, &self.bvh_ssbo);
+            gl::DeleteBuffers(1, &self.environment_ssbo);
+            gl::DeleteTextures(1, &self.environment_texture);
+            gl::DeleteBuffers(1, &self.sky_ssbo);
+            gl::DeleteBuffers(1, &self.medium_ssbo);
+            gl::DeleteTextures(1, &self.material_textures);
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn letterbox_fills_an_output_of_the_same_shape() {
+        assert_eq!(letterbox((640, 360), (1280, 720)), (0, 0, 1280, 720));
+        assert_eq!(letterbox((800, 600), (800, 600)), (0, 0, 800, 600));
+    }
+
+    #[test]
+    fn letterbox_adds_bars_above_and_below_a_wider_render() {
+        assert_eq!(letterbox((1600, 600), (800, 600)), (0, 150, 800, 300));
+    }
+
+    #[test]
+    fn letterbox_adds_bars_beside_a_taller_render() {
+        assert_eq!(letterbox((600, 600), (800, 600)), (100, 0, 600, 600));
+    }
+
+    #[test]
+    fn resolution_is_parsed() {
+        assert_eq!(resolution_from_args(&args(&["--resolution", "320x240"])), Ok(Some((320, 240))));
+        assert_eq!(resolution_from_args(&args(&[])), Ok(None));
+    }
+
+    #[test]
+    fn resolution_rejects_bad_values() {
+        assert_eq!(
+            resolution_from_args(&args(&["--resolution", "800x"])),
+            Err("Invalid value for --resolution: 800x, expected something like 1280x720".to_string())
+        );
+        assert_eq!(
+            resolution_from_args(&args(&["--resolution", "0x600"])),
+            Err("--resolution must be at least 1x1".to_string())
+        );
+        assert_eq!(
+            resolution_from_args(&args(&["--resolution"])),
+            Err("Missing value for --resolution".to_string())
+        );
+    }
+}
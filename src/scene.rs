@@ -0,0 +1,645 @@
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bvh::Bvh;
+use crate::camera::Camera;
+use crate::compound_object::CompoundObject;
+use crate::environment::EnvironmentMap;
+use crate::gpu::GpuScene;
+use crate::light::Light;
+use crate::medium::Medium;
+use crate::mesh::{Mesh, MeshBuffer};
+use crate::objects::{self, Material, Object};
+use crate::sky::PhysicalSky;
+use crate::texture::TextureBuffer;
+use crate::transform::Transform;
+
+pub const DEFAULT_SCENE: &str = "assets/scenes/demo.toml";
+
+fn hue_to_rgb(hue: f32) -> Vector3<f32> {
+    let s = 1.0;
+    let v = 1.0;
+
+    let c = v * s;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = v - c;
+
+    let (r_prime, g_prime, b_prime) = if hue < 60.0 {
+        (c, x, 0.0)
+    } else if hue < 120.0 {
+        (x, c, 0.0)
+    } else if hue < 180.0 {
+        (0.0, c, x)
+    } else if hue < 240.0 {
+        (0.0, x, c)
+    } else if hue < 300.0 {
+        (x, 0.0, c)
+    } else {
+        (c, 0.0, x)
+    };
+
+    let r = (r_prime + m).clamp(0.0, 1.0);
+    let g = (g_prime + m).clamp(0.0, 1.0);
+    let b = (b_prime + m).clamp(0.0, 1.0);
+
+    Vector3::new(r, g, b)
+}
+
+/// Returns the path given with `--scene`, or the demo scene if there is none.
+pub fn path_from_args(args: &[String]) -> Result<PathBuf, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--scene") else {
+        return Ok(PathBuf::from(DEFAULT_SCENE));
+    };
+
+    args.get(i + 1).map(PathBuf::from).ok_or("Missing value for --scene".to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    camera: CameraDescription,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDescription>,
+    #[serde(default)]
+    objects: Vec<ObjectDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+    environment: Option<EnvironmentDescription>,
+    sky: Option<SkyDescription>,
+    #[serde(default)]
+    media: HashMap<String, MediumDescription>,
+    /// Name of the medium filling the space between objects.
+    medium: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    #[serde(default = "default_camera_position")]
+    position: [f32; 3],
+    #[serde(default = "default_camera_rotation")]
+    rotation: [f32; 3],
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        CameraDescription {
+            position: default_camera_position(),
+            rotation: default_camera_rotation(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDescription {
+    color: [f32; 3],
+    #[serde(default)]
+    metallic: f32,
+    #[serde(default = "half")]
+    roughness: f32,
+    #[serde(default = "half")]
+    specular: f32,
+    #[serde(default)]
+    clearcoat: f32,
+    #[serde(default = "default_clearcoat_roughness")]
+    clearcoat_roughness: f32,
+    #[serde(default)]
+    sheen: f32,
+    #[serde(default)]
+    subsurface: f32,
+    /// Defaults to `color`.
+    subsurface_color: Option<[f32; 3]>,
+    #[serde(default = "default_mean_free_path")]
+    mean_free_path: [f32; 3],
+    #[serde(default)]
+    transmission: f32,
+    #[serde(default = "default_ior")]
+    ior: f32,
+    #[serde(default)]
+    absorption: [f32; 3],
+    /// Name of the medium filling objects with this material.
+    medium: Option<String>,
+    #[serde(default)]
+    emission: [f32; 3],
+    #[serde(default = "one")]
+    emission_strength: f32,
+    /// PNG or JPEG images relative to the scene file, see `Material` for what each one does.
+    albedo_texture: Option<PathBuf>,
+    roughness_texture: Option<PathBuf>,
+    metalness_texture: Option<PathBuf>,
+    normal_texture: Option<PathBuf>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ObjectType {
+    Sphere,
+    Box,
+    Plane,
+    Disk,
+    Quad,
+    Mesh,
+    Group,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDescription {
+    #[serde(rename = "type")]
+    object_type: ObjectType,
+    material: Option<String>,
+    /// `.obj` file of a mesh, relative to the scene file.
+    path: Option<PathBuf>,
+    #[serde(default)]
+    position: [f32; 3],
+    #[serde(default = "default_scale")]
+    scale: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 3],
+    animation: Option<Animation>,
+    /// Children of a group, positioned relative to it.
+    #[serde(default)]
+    objects: Vec<ObjectDescription>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDescription {
+    /// Radiance `.hdr` file, relative to the scene file.
+    path: PathBuf,
+    /// Degrees around +y.
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "one")]
+    intensity: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MediumDescription {
+    #[serde(default)]
+    scattering: [f32; 3],
+    #[serde(default)]
+    absorption: [f32; 3],
+    #[serde(default)]
+    anisotropy: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDescription {
+    /// Degrees above the horizon.
+    sun_elevation: Option<f32>,
+    /// Degrees from -z towards +x.
+    sun_azimuth: Option<f32>,
+    /// Hours of local solar time, instead of `sun_elevation` and `sun_azimuth`.
+    time_of_day: Option<f32>,
+    /// Degrees north, only used with `time_of_day`.
+    #[serde(default = "default_latitude")]
+    latitude: f32,
+    /// Only used with `time_of_day`.
+    #[serde(default = "default_day_of_year")]
+    day_of_year: u32,
+    #[serde(default = "default_turbidity")]
+    turbidity: f32,
+    #[serde(default = "one")]
+    intensity: f32,
+    /// Degrees.
+    #[serde(default = "default_sun_radius")]
+    sun_radius: f32,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LightType {
+    Point,
+    Spot,
+    Directional,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDescription {
+    #[serde(rename = "type")]
+    light_type: LightType,
+    /// Point and spot lights only.
+    position: Option<[f32; 3]>,
+    /// Spot and directional lights only, the direction the light shines in.
+    direction: Option<[f32; 3]>,
+    #[serde(default = "white")]
+    color: [f32; 3],
+    #[serde(default = "one")]
+    intensity: f32,
+    /// Spot lights only, in degrees from the cone's axis.
+    inner_angle: Option<f32>,
+    outer_angle: Option<f32>,
+    /// Directional lights only, in degrees.
+    angular_radius: Option<f32>,
+}
+
+/// Procedural motion applied on top of an object's transform from the scene file.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Animation {
+    /// Amplitude of a sine wave added to the position on each axis.
+    #[serde(default)]
+    pub oscillate: [f32; 3],
+    /// Angular frequency of `oscillate` in radians per second.
+    #[serde(default = "one")]
+    pub frequency: f32,
+    /// Rotation speed on each axis in degrees per second.
+    #[serde(default)]
+    pub spin: [f32; 3],
+    /// Cycles the material color through the hues at this many degrees per second, 0 leaves it alone.
+    #[serde(default)]
+    pub hue: f32,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn half() -> f32 {
+    0.5
+}
+
+fn default_clearcoat_roughness() -> f32 {
+    0.03
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+fn default_mean_free_path() -> [f32; 3] {
+    [0.1; 3]
+}
+
+fn default_latitude() -> f32 {
+    45.0
+}
+
+fn default_day_of_year() -> u32 {
+    172
+}
+
+fn default_turbidity() -> f32 {
+    3.0
+}
+
+fn default_sun_radius() -> f32 {
+    0.27
+}
+
+fn white() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_camera_position() -> [f32; 3] {
+    [0.0, 0.0, 3.0]
+}
+
+fn default_camera_rotation() -> [f32; 3] {
+    [-90.0, 0.0, 0.0]
+}
+
+pub struct SceneObject {
+    pub object: std::boxed::Box<dyn Object>,
+    pub animation: Option<Animation>,
+    base_transform: Transform,
+}
+
+pub struct Scene {
+    pub camera: Camera,
+    pub objects: Vec<SceneObject>,
+    /// Triangles of every mesh in `objects`.
+    pub meshes: MeshBuffer,
+    /// Images the materials in `objects` point to.
+    pub textures: TextureBuffer,
+    pub lights: Vec<Light>,
+    /// Surrounds the scene instead of the gradient sky.
+    pub environment: Option<EnvironmentMap>,
+    /// Surrounds the scene instead of the gradient sky, a scene can't have this and `environment` at once.
+    pub sky: Option<PhysicalSky>,
+    /// Fills the space between objects, which keeps paths from ever reaching the sky if it scatters at all.
+    pub medium: Option<Medium>,
+    bvh: Bvh,
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read scene {}: {}", path.display(), e))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&source, directory).map_err(|e| format!("Failed to load scene {}: {}", path.display(), e))
+    }
+
+    /// Mesh, texture and environment map paths in `source` are resolved relative to `directory`.
+    pub fn parse(source: &str, directory: &Path) -> Result<Self, String> {
+        let file: SceneFile = toml::from_str(source).map_err(|e| e.to_string())?;
+
+        let camera = Camera::new(
+            Vector3::from(file.camera.position),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::from(file.camera.rotation)
+        );
+
+        let media = file.media.iter()
+            .map(|(name, description)| Ok((name.clone(), build_medium(name, description)?)))
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        let medium = file.medium.as_ref().map(|name| find_medium(&media, name)).transpose()?;
+
+        let mut meshes = MeshBuffer::new();
+        let mut textures = TextureBuffer::new();
+        let mut objects = Vec::new();
+        for description in &file.objects {
+            let object = build_object(description, &file.materials, &media, directory, &mut meshes, &mut textures)?;
+            objects.push(SceneObject {
+                base_transform: object.get_transform().clone(),
+                object,
+                animation: description.animation.clone(),
+            });
+        }
+
+        let lights = file.lights.iter().map(build_light).collect::<Result<_, _>>()?;
+
+        let environment = match &file.environment {
+            Some(description) => {
+                let mut environment = EnvironmentMap::load(&directory.join(&description.path))?;
+                environment.rotation = description.rotation;
+                environment.intensity = description.intensity;
+                Some(environment)
+            },
+            None => None,
+        };
+
+        if environment.is_some() && file.sky.is_some() {
+            return Err("A scene can have an environment map or a sky, not both".to_string());
+        }
+        let sky = file.sky.as_ref().map(build_sky).transpose()?;
+
+        let bvh = Bvh::build(&objects.iter().map(|o| o.object.bounds()).collect::<Vec<_>>());
+
+        Ok(Scene {
+            camera,
+            objects,
+            meshes,
+            textures,
+            lights,
+            environment,
+            sky,
+            medium,
+            bvh,
+        })
+    }
+
+    /// Moves the animated objects to where they should be `time` seconds after startup.
+    ///
+    /// Also brings the BVH up to date, so call this after changing `objects` by hand too.
+    pub fn update(&mut self, time: f32) {
+        for scene_object in &mut self.objects {
+            let Some(animation) = &scene_object.animation else {
+                continue;
+            };
+
+            let transform = scene_object.object.get_transform_mut();
+            *transform = scene_object.base_transform.clone();
+            transform.position += Vector3::from(animation.oscillate) * (time * animation.frequency).sin();
+            transform.rotation += Vector3::from(animation.spin) * time;
+
+            if animation.hue != 0.0 {
+                if let Some(material) = scene_object.object.get_material_mut() {
+                    material.base_color = hue_to_rgb((time * animation.hue).rem_euclid(360.0));
+                }
+            }
+        }
+
+        let bounds: Vec<_> = self.objects.iter().map(|o| o.object.bounds()).collect();
+        if !self.bvh.refit(&bounds) {
+            self.bvh = Bvh::build(&bounds);
+        }
+    }
+
+    /// Everything the renderer needs to draw the scene's current state, apart from `meshes`, `textures` and `environment`.
+    pub fn get_gpu_data(&self) -> GpuScene {
+        let mut gpu_scene = GpuScene::new();
+        let mut ranges = Vec::new();
+        for scene_object in &self.objects {
+            let first = gpu_scene.objects.len();
+            scene_object.object.write_gpu_data(&mut gpu_scene);
+            ranges.push((first as u32, (gpu_scene.objects.len() - first) as u32));
+        }
+        gpu_scene.bvh = self.bvh.get_gpu_data(&ranges);
+        for light in &self.lights {
+            light.write_gpu_data(&mut gpu_scene);
+        }
+        if let Some(sky) = &self.sky {
+            gpu_scene.sky = sky.gpu_data();
+        }
+        if let Some(medium) = &self.medium {
+            gpu_scene.medium = gpu_scene.add_medium(medium);
+        }
+        gpu_scene
+    }
+}
+
+fn build_object(
+    description: &ObjectDescription,
+    materials: &HashMap<String, MaterialDescription>,
+    media: &HashMap<String, Medium>,
+    directory: &Path,
+    meshes: &mut MeshBuffer,
+    textures: &mut TextureBuffer,
+) -> Result<std::boxed::Box<dyn Object>, String> {
+    let transform = Transform::new(
+        Vector3::from(description.position),
+        Vector3::from(description.scale),
+        Vector3::from(description.rotation)
+    );
+
+    if description.object_type != ObjectType::Group && !description.objects.is_empty() {
+        return Err("Only groups can contain objects".to_string());
+    }
+
+    if (description.object_type == ObjectType::Mesh) != description.path.is_some() {
+        return Err("Meshes, and only meshes, need a path".to_string());
+    }
+
+    let object: std::boxed::Box<dyn Object> = match description.object_type {
+        ObjectType::Sphere => std::boxed::Box::new(objects::Sphere::new(transform, build_material(description, materials, media, directory, textures)?)),
+        ObjectType::Box => std::boxed::Box::new(objects::Box::new(transform, build_material(description, materials, media, directory, textures)?)),
+        ObjectType::Plane => std::boxed::Box::new(objects::Plane::new(transform, build_material(description, materials, media, directory, textures)?)),
+        ObjectType::Disk => std::boxed::Box::new(objects::Disk::new(transform, build_material(description, materials, media, directory, textures)?)),
+        ObjectType::Quad => std::boxed::Box::new(objects::Quad::new(transform, build_material(description, materials, media, directory, textures)?)),
+        ObjectType::Mesh => {
+            let path = directory.join(description.path.as_ref().unwrap());
+            std::boxed::Box::new(Mesh::load(&path, meshes, transform, build_material(description, materials, media, directory, textures)?)?)
+        },
+        ObjectType::Group => {
+            let mut group = CompoundObject::new(transform);
+            for child in &description.objects {
+                if child.animation.is_some() {
+                    return Err("Objects inside a group can't be animated, animate the group instead".to_string());
+                }
+                group.add_object(build_object(child, materials, media, directory, meshes, textures)?);
+            }
+            std::boxed::Box::new(group)
+        },
+    };
+
+    Ok(object)
+}
+
+fn build_material(
+    description: &ObjectDescription,
+    materials: &HashMap<String, MaterialDescription>,
+    media: &HashMap<String, Medium>,
+    directory: &Path,
+    textures: &mut TextureBuffer,
+) -> Result<Material, String> {
+    let name = description.material.as_ref().ok_or("Object is missing a material")?;
+    let material = materials.get(name).ok_or(format!("Unknown material \"{}\"", name))?;
+
+    let fractions = [
+        ("metallic", material.metallic),
+        ("roughness", material.roughness),
+        ("specular", material.specular),
+        ("clearcoat", material.clearcoat),
+        ("clearcoat_roughness", material.clearcoat_roughness),
+        ("sheen", material.sheen),
+        ("subsurface", material.subsurface),
+        ("transmission", material.transmission),
+    ];
+    if let Some((parameter, _)) = fractions.iter().find(|(_, value)| !(0.0..=1.0).contains(value)) {
+        return Err(format!("Material \"{}\" has its {} outside of 0 to 1", name, parameter));
+    }
+    if material.ior <= 0.0 {
+        return Err(format!("Material \"{}\" has an ior that isn't positive", name));
+    }
+    if material.absorption.iter().any(|&a| a < 0.0) {
+        return Err(format!("Material \"{}\" has a negative absorption", name));
+    }
+    if material.mean_free_path.iter().any(|&d| d <= 0.0) {
+        return Err(format!("Material \"{}\" has a mean free path that isn't positive", name));
+    }
+
+    let mut load_texture = |path: &Option<PathBuf>| path.as_ref().map(|path| textures.load(&directory.join(path))).transpose();
+
+    Ok(Material {
+        base_color: Vector3::from(material.color),
+        metallic: material.metallic,
+        roughness: material.roughness,
+        specular: material.specular,
+        clearcoat: material.clearcoat,
+        clearcoat_roughness: material.clearcoat_roughness,
+        subsurface: material.subsurface,
+        subsurface_color: Vector3::from(material.subsurface_color.unwrap_or(material.color)),
+        mean_free_path: Vector3::from(material.mean_free_path),
+        sheen: material.sheen,
+        transmission: material.transmission,
+        ior: material.ior,
+        absorption: Vector3::from(material.absorption),
+        medium: material.medium.as_ref().map(|name| find_medium(media, name)).transpose()?,
+        emission: Vector3::from(material.emission),
+        emission_strength: material.emission_strength,
+        albedo_texture: load_texture(&material.albedo_texture)?,
+        roughness_texture: load_texture(&material.roughness_texture)?,
+        metalness_texture: load_texture(&material.metalness_texture)?,
+        normal_texture: load_texture(&material.normal_texture)?,
+    })
+}
+
+fn build_medium(name: &str, description: &MediumDescription) -> Result<Medium, String> {
+    if description.scattering.iter().chain(&description.absorption).any(|&c| c < 0.0) {
+        return Err(format!("Medium \"{}\" has a negative scattering or absorption", name));
+    }
+    // a g of 1 or -1 would scatter everything exactly forward or back
+    if !(-1.0 < description.anisotropy && description.anisotropy < 1.0) {
+        return Err(format!("Medium \"{}\" needs an anisotropy between -1 and 1", name));
+    }
+
+    Ok(Medium::new(Vector3::from(description.scattering), Vector3::from(description.absorption), description.anisotropy))
+}
+
+fn find_medium(media: &HashMap<String, Medium>, name: &str) -> Result<Medium, String> {
+    media.get(name).cloned().ok_or(format!("Unknown medium \"{}\"", name))
+}
+
+fn build_sky(description: &SkyDescription) -> Result<PhysicalSky, String> {
+    let mut sky = PhysicalSky::new(0.0, 0.0, description.turbidity);
+    sky.intensity = description.intensity;
+    sky.sun_radius = description.sun_radius;
+
+    match (description.sun_elevation, description.sun_azimuth, description.time_of_day) {
+        (Some(elevation), Some(azimuth), None) => {
+            sky.sun_elevation = elevation;
+            sky.sun_azimuth = azimuth;
+        },
+        (None, None, Some(hours)) => sky.set_time_of_day(hours, description.latitude, description.day_of_year),
+        _ => return Err("The sky needs either a sun_elevation and sun_azimuth, or a time_of_day".to_string()),
+    }
+
+    if !(1.0..=20.0).contains(&sky.turbidity) {
+        return Err("Sky turbidity has to be between 1 and 20".to_string());
+    }
+
+    Ok(sky)
+}
+
+fn build_light(description: &LightDescription) -> Result<Light, String> {
+    let has_position = description.light_type != LightType::Directional;
+    let has_direction = description.light_type != LightType::Point;
+    let has_cone = description.light_type == LightType::Spot;
+    let has_radius = description.light_type == LightType::Directional;
+
+    if description.position.is_some() != has_position {
+        return Err("Point and spot lights, and only those, need a position".to_string());
+    }
+    if description.direction.is_some() != has_direction {
+        return Err("Spot and directional lights, and only those, need a direction".to_string());
+    }
+    if !has_cone && (description.inner_angle.is_some() || description.outer_angle.is_some()) {
+        return Err("Only spot lights have cone angles".to_string());
+    }
+    if !has_radius && description.angular_radius.is_some() {
+        return Err("Only directional lights have an angular radius".to_string());
+    }
+
+    let position = Vector3::from(description.position.unwrap_or_default());
+    let direction = Vector3::from(description.direction.unwrap_or_default());
+    if has_direction && direction == Vector3::zeros() {
+        return Err("Light direction can't be zero".to_string());
+    }
+
+    let color = Vector3::from(description.color);
+    let intensity = description.intensity;
+
+    Ok(match description.light_type {
+        LightType::Point => Light::Point { position, color, intensity },
+        LightType::Spot => {
+            let outer_angle = description.outer_angle.ok_or("Spot lights need an outer_angle")?;
+            let inner_angle = description.inner_angle.unwrap_or(outer_angle);
+            if inner_angle > outer_angle {
+                return Err("A spot light's inner_angle can't be larger than its outer_angle".to_string());
+            }
+            Light::Spot { position, direction, color, intensity, inner_angle, outer_angle }
+        },
+        LightType::Directional => Light::Directional {
+            direction,
+            color,
+            intensity,
+            angular_radius: description.angular_radius.unwrap_or(0.0),
+        },
+    })
+}
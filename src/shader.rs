@@ -1,179 +1,320 @@
 use gl::types::*;
+use nalgebra::{Matrix2, Matrix3, Matrix4, Vector2, Vector3, Vector4};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ShaderType {
     Vertex,
     Fragment,
+    Compute,
+}
+
+impl ShaderType {
+    fn gl_enum(self) -> GLenum {
+        match self {
+            ShaderType::Vertex => gl::VERTEX_SHADER,
+            ShaderType::Fragment => gl::FRAGMENT_SHADER,
+            ShaderType::Compute => gl::COMPUTE_SHADER,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ShaderType::Vertex => "vsh",
+            ShaderType::Fragment => "fsh",
+            ShaderType::Compute => "csh",
+        }
+    }
+}
+
+impl fmt::Display for ShaderType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderType::Vertex => write!(f, "vertex"),
+            ShaderType::Fragment => write!(f, "fragment"),
+            ShaderType::Compute => write!(f, "compute"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    /// A source file or something it includes couldn't be read.
+    Io { path: PathBuf, error: io::Error },
+    /// A malformed `#include`, or files including each other.
+    Include(String),
+    /// The log has the file, line and offending source line for every message it could place.
+    ///
+    /// Lines of included files reach the driver numbered from `file * 100000`, where `file` is
+    /// the include's position in the order files were first included, so a message left as the
+    /// driver wrote it can point at e.g. line 200042, line 42 of the second included file.
+    Compile { stage: ShaderType, path: PathBuf, log: String },
+    Link { log: String },
+    /// Names have to be identifiers and values fit on one line.
+    InvalidDefine { name: String, value: String },
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Io { path, error } => write!(f, "Failed to read {}: {}", path.display(), error),
+            ShaderError::Include(message) => write!(f, "{}", message),
+            ShaderError::Compile { stage, path, log } => write!(f, "Failed to compile {} shader {}:\n{}", stage, path.display(), log),
+            ShaderError::Link { log } => write!(f, "Failed to link shader program:\n{}", log),
+            ShaderError::InvalidDefine { name, value } => write!(f, "Invalid define: #define {} {}", name, value),
+        }
+    }
 }
 
+impl std::error::Error for ShaderError {}
+
 pub struct Shader {
     pub id: u32,
 
-    vertex_code: String,
-    fragment_code: String,
+    name: String,
+    stages: Vec<Stage>,
 
-    vertex_defines: Vec<(String, String)>,
-    fragment_defines: Vec<(String, String)>,
+    // active uniforms of the program by name, array elements share their array's entry
+    uniforms: HashMap<String, UniformInfo>,
+    // uniforms set_uniform already complained about
+    warned_uniforms: RefCell<HashSet<String>>,
 
     compiled: bool,
 }
 
+// one source file of a program and everything that changes it before it's compiled
+struct Stage {
+    shader_type: ShaderType,
+    path: PathBuf,
+    source: Source,
+
+    injections: Vec<(String, String)>,
+    // set by add_define and remove_define, None removes the define
+    define_overrides: Vec<(String, Option<String>)>,
+
+    // what the next compile will be built with, the file's own defines with the overrides applied
+    defines: Vec<(String, String)>,
+    compiled_defines: Vec<(String, String)>,
+}
+
+impl Stage {
+    fn load(path: PathBuf, shader_type: ShaderType) -> Result<Self, ShaderError> {
+        let source = Source::load(&path)?;
+
+        Ok(Stage {
+            shader_type,
+            path,
+            defines: source.defines(),
+            source,
+            injections: Vec::new(),
+            define_overrides: Vec::new(),
+            compiled_defines: Vec::new(),
+        })
+    }
+
+    fn update_defines(&mut self) {
+        self.defines = apply_define_overrides(self.source.defines(), &self.define_overrides);
+    }
+}
+
 impl Shader {
-    pub fn new(shader_name: &str) -> Self {
-        let vertex_path = format!("assets/shaders/{}.vsh", shader_name);
-        let fragment_path = format!("assets/shaders/{}.fsh", shader_name);
+    /// Reads `assets/shaders/<shader_name>.vsh` and `.fsh`, it still has to be compiled before use.
+    pub fn new(shader_name: &str) -> Result<Self, ShaderError> {
+        Self::with_stages(shader_name, &[ShaderType::Vertex, ShaderType::Fragment])
+    }
 
-        let vertex_code = fs::read_to_string(vertex_path).expect("Failed to read vertex shader");
-        let fragment_code = fs::read_to_string(fragment_path).expect("Failed to read fragment shader");
+    /// Reads `assets/shaders/<shader_name>.csh`, it still has to be compiled before use.
+    pub fn new_compute(shader_name: &str) -> Result<Self, ShaderError> {
+        Self::with_stages(shader_name, &[ShaderType::Compute])
+    }
+
+    fn with_stages(shader_name: &str, shader_types: &[ShaderType]) -> Result<Self, ShaderError> {
+        let paths = shader_types
+            .iter()
+            .map(|&shader_type| (PathBuf::from(format!("assets/shaders/{}.{}", shader_name, shader_type.extension())), shader_type));
+        Self::from_paths(shader_name, paths)
+    }
 
-        let vertex_defines = Self::extract_defines(&vertex_code);
-        let fragment_defines = Self::extract_defines(&fragment_code);
+    // `name` is only used in warnings
+    fn from_paths(name: &str, paths: impl IntoIterator<Item = (PathBuf, ShaderType)>) -> Result<Self, ShaderError> {
+        let stages = paths
+            .into_iter()
+            .map(|(path, shader_type)| Stage::load(path, shader_type))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Shader {
+        Ok(Shader {
             id: 0,
-            vertex_code,
-            fragment_code,
-            vertex_defines,
-            fragment_defines,
+            name: name.to_string(),
+            stages,
+            uniforms: HashMap::new(),
+            warned_uniforms: RefCell::new(HashSet::new()),
             compiled: false,
-        }
+        })
     }
 
-    pub fn compile(&mut self) {
-        let c_str_vert: std::ffi::CString = std::ffi::CString::new(self.vertex_code.as_bytes()).unwrap();
-        let c_str_frag: std::ffi::CString = std::ffi::CString::new(self.fragment_code.as_bytes()).unwrap();
-
-        let vertex_shader;
-        let fragment_shader;
-        let shader_program;
+    /// Compiles and links the current sources, replacing the program in `id` only if that succeeds.
+    ///
+    /// The error holds the info log with the offending source lines next to each message.
+    pub fn compile(&mut self) -> Result<(), ShaderError> {
+        let mut shaders = Vec::new();
 
         unsafe {
-            vertex_shader = gl::CreateShader(gl::VERTEX_SHADER);
-            gl::ShaderSource(vertex_shader, 1, &c_str_vert.as_ptr(), std::ptr::null());
-            gl::CompileShader(vertex_shader);
-
-            let mut success = gl::FALSE as GLint;
-            gl::GetShaderiv(vertex_shader, gl::COMPILE_STATUS, &mut success);
-            if success != gl::TRUE as GLint {
-                let mut len = 0;
-                gl::GetShaderiv(vertex_shader, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = vec![0; len as usize];
-                gl::GetShaderInfoLog(vertex_shader, len, std::ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
-                panic!(
-                    "{}",
-                    std::str::from_utf8(&buf)
-                        .map_err(|_| "ShaderInfoLog not valid utf8")
-                        .unwrap()
-                );
+            for stage in &self.stages {
+                let code = preprocess(&stage.source, &stage.define_overrides, &stage.injections);
+                match compile_stage(stage.shader_type.gl_enum(), &code) {
+                    Ok(shader) => shaders.push(shader),
+                    Err(log) => {
+                        for shader in shaders {
+                            gl::DeleteShader(shader);
+                        }
+                        return Err(ShaderError::Compile {
+                            stage: stage.shader_type,
+                            path: stage.path.clone(),
+                            log: annotate_log(&log, &stage.source),
+                        });
+                    },
+                }
             }
 
-            fragment_shader = gl::CreateShader(gl::FRAGMENT_SHADER);
-            gl::ShaderSource(fragment_shader, 1, &c_str_frag.as_ptr(), std::ptr::null());
-            gl::CompileShader(fragment_shader);
-
-            let mut success = gl::FALSE as GLint;
-            gl::GetShaderiv(fragment_shader, gl::COMPILE_STATUS, &mut success);
-            if success != gl::TRUE as GLint {
-                let mut len = 0;
-                gl::GetShaderiv(fragment_shader, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = vec![0; len as usize];
-                gl::GetShaderInfoLog(fragment_shader, len, std::ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
-                panic!(
-                    "{}",
-                    std::str::from_utf8(&buf)
-                        .map_err(|_| "ShaderInfoLog not valid utf8")
-                        .unwrap()
-                );
+            let shader_program = gl::CreateProgram();
+            for &shader in &shaders {
+                gl::AttachShader(shader_program, shader);
             }
-
-            shader_program = gl::CreateProgram();
-            gl::AttachShader(shader_program, vertex_shader);
-            gl::AttachShader(shader_program, fragment_shader);
             gl::LinkProgram(shader_program);
 
+            for shader in shaders {
+                gl::DeleteShader(shader);
+            }
+
             let mut success = gl::FALSE as GLint;
             gl::GetProgramiv(shader_program, gl::LINK_STATUS, &mut success);
             if success != gl::TRUE as GLint {
                 let mut len = 0;
                 gl::GetProgramiv(shader_program, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = vec![0; len as usize];
+                let mut buf = vec![0u8; len as usize];
                 gl::GetProgramInfoLog(shader_program, len, std::ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
-                panic!(
-                    "{}",
-                    std::str::from_utf8(&buf)
-                        .map_err(|_| "ShaderInfoLog not valid utf8")
-                        .unwrap()
-                );
+                gl::DeleteProgram(shader_program);
+                return Err(ShaderError::Link {
+                    log: String::from_utf8_lossy(&buf).trim_end_matches('\0').to_string(),
+                });
+            }
+
+            if self.id != 0 {
+                gl::DeleteProgram(self.id);
             }
+            self.id = shader_program;
+            self.uniforms = active_uniforms(shader_program);
         }
+        self.warned_uniforms.borrow_mut().clear();
 
-        self.id = shader_program;
+        self.compiled = true;
+        for stage in &mut self.stages {
+            stage.compiled_defines = stage.defines.clone();
+        }
 
-        unsafe {
-            gl::DeleteShader(vertex_shader);
-            gl::DeleteShader(fragment_shader);
+        Ok(())
+    }
+
+    /// Rereads and recompiles the sources if any file, or anything they include, changed on disk
+    /// since they were last read.
+    ///
+    /// Returns whether the program was replaced. If the new sources fail to compile the previous
+    /// program stays in use, and the same sources won't be tried again until they change. If a file
+    /// can't be read, say because an editor is halfway through saving it, nothing is replaced and
+    /// the next call tries again.
+    pub fn reload_if_changed(&mut self) -> Result<bool, ShaderError> {
+        if !self.stages.iter().any(|stage| stage.source.changed()) {
+            return Ok(false);
         }
+
+        // read every stage before replacing any, so they're never out of sync with each other
+        let sources = self
+            .stages
+            .iter()
+            .map(|stage| Source::load(&stage.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (stage, source) in self.stages.iter_mut().zip(sources) {
+            stage.source = source;
+            stage.update_defines();
+        }
+
+        self.compile()?;
+        Ok(true)
     }
 
-    pub fn add_define(&mut self, name: &str, value: &str, shader_type: ShaderType) {
-        let add_or_replace_define = |mut shader_code: String| {
-            if let Some(pos) = shader_code.find("#version") {
-                if let Some(new_line_pos) = shader_code[pos..].find('\n') {
-                    let insert_pos = pos + new_line_pos + 1;
-                    let define_string = format!("#define {} {}", name, value);
-                    if let Some(define_pos) = shader_code.find(&define_string) {
-                        let end = shader_code[define_pos..].find('\n').unwrap_or(shader_code.len());
-                        shader_code.replace_range(define_pos..define_pos + end, &define_string);
-                    } else {
-                        shader_code.insert_str(insert_pos, &format!("{}\n", define_string));
-                    }
-                }
-            }
-        };
+    // the program's stage of `shader_type`, if it has one
+    fn stage(&self, shader_type: ShaderType) -> Option<&Stage> {
+        self.stages.iter().find(|stage| stage.shader_type == shader_type)
+    }
 
-        match shader_type {
-            ShaderType::Vertex => {
-                add_or_replace_define(self.vertex_code.clone());
-                self.vertex_defines.push((name.to_string(), value.to_string()));
-            },
-            ShaderType::Fragment => {
-                add_or_replace_define(self.fragment_code.clone());
-                self.fragment_defines.push((name.to_string(), value.to_string()));
-            },
+    fn stage_mut(&mut self, shader_type: ShaderType) -> Option<&mut Stage> {
+        self.stages.iter_mut().find(|stage| stage.shader_type == shader_type)
+    }
+
+    /// Sets `name` to `value`, overriding the source's own `#define` of it or adding one after `#version`.
+    ///
+    /// Takes effect the next time the shader is compiled, does nothing for stages the program doesn't have.
+    pub fn add_define(&mut self, name: &str, value: &str, shader_type: ShaderType) -> Result<(), ShaderError> {
+        let is_identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier || value.contains(['\n', '\r']) {
+            return Err(ShaderError::InvalidDefine {
+                name: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+
+        self.set_define_override(name, Some(value.to_string()), shader_type);
+        Ok(())
+    }
+
+    /// Replaces the `#pragma <name>` line in the source with `code`, for GLSL generated on the Rust side.
+    ///
+    /// Kept across reloads, and line numbers in compile errors still match the file.
+    pub fn inject(&mut self, name: &str, code: &str, shader_type: ShaderType) {
+        if let Some(stage) = self.stage_mut(shader_type) {
+            stage.injections.retain(|(n, _)| n != name);
+            stage.injections.push((name.to_string(), code.to_string()));
         }
     }
 
+    /// Drops the source's `#define` of `name`, and any value given to `add_define`, the next time the shader is compiled.
     pub fn remove_define(&mut self, name: &str, shader_type: ShaderType) {
-        let remove_define = |mut shader_code: String| {
-            if let Some(pos) = shader_code.find(format!("#define {}", name).as_str()) {
-                let end = shader_code[pos..].find('\n').unwrap_or(shader_code.len());
-                shader_code.replace_range(pos..pos + end, "");
-            }
-        };
+        self.set_define_override(name, None, shader_type);
+    }
 
-        match shader_type {
-            ShaderType::Vertex => {
-                remove_define(self.vertex_code.clone());
-                self.vertex_defines.retain(|(n, _)| n != name);
-            },
-            ShaderType::Fragment => {
-                remove_define(self.fragment_code.clone());
-                self.fragment_defines.retain(|(n, _)| n != name);
-            },
+    fn set_define_override(&mut self, name: &str, value: Option<String>, shader_type: ShaderType) {
+        if let Some(stage) = self.stage_mut(shader_type) {
+            stage.define_overrides.retain(|(n, _)| n != name);
+            stage.define_overrides.push((name.to_string(), value));
+            stage.update_defines();
         }
     }
 
+    /// Value of `name` the next compile will use.
     pub fn get_define(&self, name: &str, shader_type: ShaderType) -> Option<&str> {
-        match shader_type {
-            ShaderType::Vertex => {
-                self.vertex_defines.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
-            },
-            ShaderType::Fragment => {
-                self.fragment_defines.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
-            },
-        }
+        self.stage(shader_type).and_then(|stage| find_define(&stage.defines, name))
     }
 
+    /// Value of `name` the program in `id` was built with.
+    pub fn get_compiled_define(&self, name: &str, shader_type: ShaderType) -> Option<&str> {
+        self.stage(shader_type).and_then(|stage| find_define(&stage.compiled_defines, name))
+    }
+
+    /// Whether defines were added or removed since the program was last compiled.
+    pub fn needs_recompile(&self) -> bool {
+        self.stages.iter().any(|stage| stage.defines != stage.compiled_defines)
+    }
+
+    /// Whether `id` holds a linked program, which stays true when a later recompile fails.
     pub fn is_compiled(&self) -> bool {
         self.compiled
     }
@@ -184,114 +325,826 @@ impl Shader {
         }
     }
 
-    pub fn set_bool(&self, name: &str, value: bool) {
-        unsafe {
-            gl::Uniform1i(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), value as i32);
-        }
+    /// Sets a uniform of the program, which has to be in use.
+    ///
+    /// Uniforms that don't exist, were optimized out or have a different type are skipped with a
+    /// warning, printed once per name until the next compile.
+    pub fn set_uniform<T: Uniform>(&self, name: &str, value: T) {
+        self.set_uniform_in_array(name, value, 0);
     }
 
-    pub fn set_int(&self, name: &str, value: i32) {
-        unsafe {
-            gl::Uniform1i(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), value);
+    /// Sets element `index` of a uniform array, or the uniform itself if it isn't an array and `index` is 0.
+    pub fn set_uniform_in_array<T: Uniform>(&self, name: &str, value: T, index: usize) {
+        let Some(uniform) = self.uniforms.get(name) else {
+            self.warn(name, format!("{} has no active uniform {}", self.name, name));
+            return;
+        };
+        if !T::matches(uniform.gl_type) {
+            self.warn(name, format!("Uniform {} in {} can't be set from {}", name, self.name, std::any::type_name::<T>()));
+            return;
         }
-    }
+        let Some(&location) = uniform.locations.get(index) else {
+            self.warn(name, format!("Uniform {} in {} has no element {}", name, self.name, index));
+            return;
+        };
 
-    pub fn set_float(&self, name: &str, value: f32) {
         unsafe {
-            gl::Uniform1f(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), value);
+            value.upload(location);
         }
     }
 
-    pub fn set_vec2(&self, name: &str, value: [f32; 2]) {
-        unsafe {
-            gl::Uniform2fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, value.as_ptr());
+    fn warn(&self, name: &str, message: String) {
+        if self.warned_uniforms.borrow_mut().insert(name.to_string()) {
+            eprintln!("Warning: {}", message);
         }
     }
+}
 
-    pub fn set_vec3(&self, name: &str, value: [f32; 3]) {
-        unsafe {
-            gl::Uniform3fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, value.as_ptr());
+impl Drop for Shader {
+    fn drop(&mut self) {
+        if self.id != 0 {
+            unsafe {
+                gl::DeleteProgram(self.id);
+            }
         }
     }
+}
 
-    pub fn set_vec4(&self, name: &str, value: [f32; 4]) {
-        unsafe {
-            gl::Uniform4fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, value.as_ptr());
+/// A Rust type that can be uploaded to uniforms of matching GLSL types.
+pub trait Uniform {
+    /// Whether a uniform declared as `gl_type`, like `gl::FLOAT_VEC3`, can be set from this type.
+    fn matches(gl_type: GLenum) -> bool;
+
+    /// # Safety
+    ///
+    /// `location` has to belong to a uniform of the program in use whose type `matches`.
+    unsafe fn upload(&self, location: GLint);
+}
+
+impl Uniform for bool {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::BOOL
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform1i(location, *self as i32);
+    }
+}
+
+impl Uniform for i32 {
+    // samplers and images are set to the unit they read from
+    fn matches(gl_type: GLenum) -> bool {
+        matches!(
+            gl_type,
+            gl::INT
+                | gl::SAMPLER_1D
+                | gl::SAMPLER_2D
+                | gl::SAMPLER_3D
+                | gl::SAMPLER_CUBE
+                | gl::SAMPLER_2D_ARRAY
+                | gl::INT_SAMPLER_2D
+                | gl::UNSIGNED_INT_SAMPLER_2D
+                | gl::IMAGE_2D
+        )
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform1i(location, *self);
+    }
+}
+
+impl Uniform for u32 {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::UNSIGNED_INT
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform1ui(location, *self);
+    }
+}
+
+impl Uniform for f32 {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform1f(location, *self);
+    }
+}
+
+impl Uniform for [f32; 2] {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC2
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform2fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for [f32; 3] {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC3
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform3fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for [f32; 4] {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC4
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform4fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for Vector2<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC2
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform2fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for Vector3<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC3
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform3fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for Vector4<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_VEC4
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::Uniform4fv(location, 1, self.as_ptr());
+    }
+}
+
+impl Uniform for Matrix2<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_MAT2
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::UniformMatrix2fv(location, 1, gl::FALSE, self.as_ptr());
+    }
+}
+
+impl Uniform for Matrix3<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_MAT3
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::UniformMatrix3fv(location, 1, gl::FALSE, self.as_ptr());
+    }
+}
+
+impl Uniform for Matrix4<f32> {
+    fn matches(gl_type: GLenum) -> bool {
+        gl_type == gl::FLOAT_MAT4
+    }
+
+    unsafe fn upload(&self, location: GLint) {
+        gl::UniformMatrix4fv(location, 1, gl::FALSE, self.as_ptr());
+    }
+}
+
+struct UniformInfo {
+    gl_type: GLenum,
+    // one per array element, or just the one for uniforms that aren't arrays
+    locations: Vec<GLint>,
+}
+
+// every uniform of a linked program that has a location of its own
+unsafe fn active_uniforms(program: u32) -> HashMap<String, UniformInfo> {
+    let mut count = 0;
+    gl::GetProgramiv(program, gl::ACTIVE_UNIFORMS, &mut count);
+    let mut max_length = 0;
+    gl::GetProgramiv(program, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_length);
+
+    let location = |name: &str| CString::new(name).map_or(-1, |name| gl::GetUniformLocation(program, name.as_ptr()));
+
+    let mut uniforms = HashMap::new();
+    for i in 0..count.max(0) as u32 {
+        let mut buf = vec![0u8; max_length.max(1) as usize];
+        let mut length = 0;
+        let mut size = 0;
+        let mut gl_type = 0;
+        gl::GetActiveUniform(program, i, max_length, &mut length, &mut size, &mut gl_type, buf.as_mut_ptr() as *mut GLchar);
+        let name = String::from_utf8_lossy(&buf[..length.max(0) as usize]).into_owned();
+
+        // arrays are reported as their first element
+        let (name, is_array) = match name.strip_suffix("[0]") {
+            Some(base) => (base.to_string(), true),
+            None => (name, false),
+        };
+
+        let locations: Vec<GLint> = if is_array {
+            (0..size).map(|j| location(&format!("{}[{}]", name, j))).collect()
+        } else {
+            vec![location(&name)]
+        };
+
+        // members of uniform blocks don't have locations
+        if locations.first().is_some_and(|&l| l >= 0) {
+            uniforms.insert(name, UniformInfo { gl_type, locations });
         }
     }
 
-    pub fn set_mat2(&self, name: &str, value: nalgebra::Matrix2<f32>) {
-        unsafe {
-            gl::UniformMatrix2fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, gl::FALSE, value.as_slice().as_ptr());
+    uniforms
+}
+
+// a line of a stage's source after includes were expanded, and where it came from
+struct SourceLine {
+    text: String,
+    // index into Source::files, used as the source string number in #line
+    file: usize,
+    number: usize,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    code: String,
+    // when it was last read, to tell if it changed on disk
+    modified: Option<SystemTime>,
+}
+
+// One shader stage with every `#include "file"` replaced by that file's lines. Includes are
+// resolved relative to the directory of the stage's file, and every file is only included once.
+struct Source {
+    lines: Vec<SourceLine>,
+    files: Vec<SourceFile>,
+}
+
+impl Source {
+    fn load(path: &Path) -> Result<Self, ShaderError> {
+        let mut source = Source {
+            lines: Vec::new(),
+            files: Vec::new(),
+        };
+        let directory = path.parent().unwrap_or(Path::new(""));
+        source.include(path, directory, &mut Vec::new())?;
+        Ok(source)
+    }
+
+    // `stack` holds the files currently being included, to catch ones that include themselves
+    fn include(&mut self, path: &Path, directory: &Path, stack: &mut Vec<PathBuf>) -> Result<(), ShaderError> {
+        if stack.iter().any(|p| p == path) {
+            let cycle: Vec<String> = stack.iter().chain([&path.to_path_buf()]).map(|p| p.display().to_string()).collect();
+            return Err(ShaderError::Include(format!("Include cycle: {}", cycle.join(" -> "))));
+        }
+        if self.files.iter().any(|file| file.path == path) {
+            return Ok(());
         }
+
+        let code = fs::read_to_string(path).map_err(|error| ShaderError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let file = self.files.len();
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            code: code.clone(),
+            modified: modified_time(path),
+        });
+
+        stack.push(path.to_path_buf());
+        for (i, line) in code.lines().enumerate() {
+            match parse_include(line) {
+                Some(Ok(name)) => self.include(&directory.join(name), directory, stack)?,
+                Some(Err(e)) => return Err(ShaderError::Include(format!("{}:{}: {}", path.display(), i + 1, e))),
+                None => self.lines.push(SourceLine {
+                    text: line.to_string(),
+                    file,
+                    number: i + 1,
+                }),
+            }
+        }
+        stack.pop();
+
+        Ok(())
     }
 
-    pub fn set_mat3(&self, name: &str, value: nalgebra::Matrix3<f32>) {
-        unsafe {
-            gl::UniformMatrix3fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, gl::FALSE, value.as_slice().as_ptr());
+    // whether any of the files was modified since it was read, missing ones are likely an editor
+    // halfway through saving them
+    fn changed(&self) -> bool {
+        self.files.iter().any(|file| modified_time(&file.path).is_some_and(|modified| Some(modified) != file.modified))
+    }
+
+    fn defines(&self) -> Vec<(String, String)> {
+        self.lines.iter().filter_map(|line| parse_define(&line.text)).collect()
+    }
+}
+
+// the file name of an `#include "file"` line
+fn parse_include(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim().strip_prefix("#include")?;
+    let name = rest.trim().strip_prefix('"').and_then(|rest| rest.strip_suffix('"'));
+    Some(name.ok_or_else(|| format!("Expected #include \"file\", got {}", line.trim())))
+}
+
+// name and value of a `#define NAME value` line
+fn parse_define(line: &str) -> Option<(String, String)> {
+    let mut parts = line.split_whitespace();
+    if parts.next() != Some("#define") {
+        return None;
+    }
+    let name = parts.next()?.to_string();
+    let value = parts.collect::<Vec<&str>>().join(" ");
+    Some((name, value))
+}
+
+fn find_define<'a>(defines: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    defines.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+fn apply_define_overrides(mut defines: Vec<(String, String)>, overrides: &[(String, Option<String>)]) -> Vec<(String, String)> {
+    for (name, value) in overrides {
+        match (defines.iter_mut().find(|(n, _)| n == name), value) {
+            (Some(define), Some(value)) => define.1 = value.clone(),
+            (None, Some(value)) => defines.push((name.clone(), value.clone())),
+            (_, None) => defines.retain(|(n, _)| n != name),
         }
     }
+    defines
+}
 
-    pub fn set_mat4(&self, name: &str, value: nalgebra::Matrix4<f32>) {
-        unsafe {
-            gl::UniformMatrix4fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(name).unwrap().as_ptr()), 1, gl::FALSE, value.as_slice().as_ptr());
+// Mesa reports most errors with source string 0 whatever #line said, so lines of included files
+// are numbered from file * FILE_LINE_STRIDE to tell which file an error is in from the line alone
+const FILE_LINE_STRIDE: usize = 100_000;
+
+// Applies define overrides and injections to the source. Wherever the lines handed to the
+// compiler stop following each other in a file a #line is added, so errors can be traced back
+// to the file and line they came from.
+fn preprocess(source: &Source, define_overrides: &[(String, Option<String>)], injections: &[(String, String)]) -> String {
+    let mut lines: Vec<(String, Option<(usize, usize)>)> = Vec::new();
+    let mut overridden = Vec::new();
+    let mut after_version = None;
+
+    for line in &source.lines {
+        let mut text = line.text.clone();
+
+        if let Some((name, _)) = parse_define(&text) {
+            if let Some((_, value)) = define_overrides.iter().find(|(n, _)| *n == name) {
+                text = value.as_ref().map_or(String::new(), |value| format!("#define {} {}", name, value));
+                overridden.push(name);
+            }
+        }
+
+        if let Some((_, code)) = injections.iter().find(|(name, _)| text.trim() == format!("#pragma {}", name)) {
+            lines.extend(code.lines().map(|code_line| (code_line.to_string(), None)));
+            continue;
+        }
+
+        if after_version.is_none() && text.trim_start().starts_with("#version") {
+            after_version = Some(lines.len() + 1);
         }
+        lines.push((text, Some((line.file, line.number))));
     }
 
-    pub fn set_bool_in_array(&self, name: &str, value: bool, index: usize) {
-        unsafe {
-            gl::Uniform1i(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), value as i32);
+    // defines the file doesn't have go right after #version, which has to stay first
+    let added = define_overrides
+        .iter()
+        .filter(|(name, _)| !overridden.contains(name))
+        .filter_map(|(name, value)| value.as_ref().map(|value| (format!("#define {} {}", name, value), None)));
+    if let Some(i) = after_version {
+        lines.splice(i..i, added);
+    }
+
+    let mut code = String::new();
+    let mut next = Some((0, 1));
+    for (text, location) in lines {
+        if let Some((file, number)) = location {
+            if next != Some((file, number)) {
+                code += &format!("#line {} {}\n", file * FILE_LINE_STRIDE + number, file);
+            }
         }
+        next = location.map(|(file, number)| (file, number + 1));
+
+        code += &text;
+        code += "\n";
     }
 
-    pub fn set_int_in_array(&self, name: &str, value: i32, index: usize) {
-        unsafe {
-            gl::Uniform1i(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), value);
+    code
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// returns the shader object, or the info log if it didn't compile
+unsafe fn compile_stage(stage: GLenum, code: &str) -> Result<u32, String> {
+    let c_str = CString::new(code.as_bytes()).map_err(|_| "Shader source contains a nul byte".to_string())?;
+
+    let shader = gl::CreateShader(stage);
+    gl::ShaderSource(shader, 1, &c_str.as_ptr(), std::ptr::null());
+    gl::CompileShader(shader);
+
+    let mut success = gl::FALSE as GLint;
+    gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut success);
+    if success != gl::TRUE as GLint {
+        let mut len = 0;
+        gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+        let mut buf = vec![0u8; len as usize];
+        gl::GetShaderInfoLog(shader, len, std::ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
+        gl::DeleteShader(shader);
+        return Err(String::from_utf8_lossy(&buf).trim_end_matches('\0').to_string());
+    }
+
+    Ok(shader)
+}
+
+// splits messages like "0:12(5): error" from Mesa, "0(12) : error" from Nvidia or
+// "ERROR: 0:12: error" from AMD into what comes before the location, the source string, the
+// line and what comes after it
+fn log_location(message: &str) -> Option<(&str, usize, usize, &str)> {
+    let location = message.trim_start_matches(|c: char| c.is_ascii_alphabetic() || c == ':' || c == ' ');
+    let prefix = &message[..message.len() - location.len()];
+
+    let rest = location.trim_start_matches(|c: char| c.is_ascii_digit());
+    let source = location[..location.len() - rest.len()].parse().ok()?;
+
+    let rest = rest.strip_prefix(':').or_else(|| rest.strip_prefix('('))?;
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let line = rest[..end].parse().ok()?;
+
+    Some((prefix, source, line, rest[end..].trim_start_matches(')')))
+}
+
+// replaces the locations in the info log with file names and lines, and follows every message
+// with the line it points at
+fn annotate_log(log: &str, source: &Source) -> String {
+    let mut annotated = String::new();
+    let mut unplaced = false;
+
+    for message in log.lines().filter(|message| !message.trim().is_empty()) {
+        let location = log_location(message).and_then(|(prefix, source_string, line, rest)| {
+            let file = if line >= FILE_LINE_STRIDE { line / FILE_LINE_STRIDE } else { source_string };
+            let number = line % FILE_LINE_STRIDE;
+            source.files.get(file).map(|file| (prefix, file, number, rest))
+        });
+
+        let Some((prefix, file, number, rest)) = location else {
+            unplaced |= message.split(|c: char| !c.is_ascii_digit()).any(|n| n.parse().is_ok_and(|n: usize| n >= FILE_LINE_STRIDE));
+            annotated += message;
+            annotated += "\n";
+            continue;
+        };
+
+        annotated += &format!("{}{}:{}{}\n", prefix, file.path.display(), number, rest);
+        if let Some(line) = number.checked_sub(1).and_then(|i| file.code.lines().nth(i)) {
+            annotated += &format!("{:>6} | {}\n", number, line);
         }
     }
 
-    pub fn set_float_in_array(&self, name: &str, value: f32, index: usize) {
-        unsafe {
-            gl::Uniform1f(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), value);
+    // say how to read the numbers the driver's own messages have for included files
+    if unplaced {
+        annotated += &format!("Lines from {} up are file * {} + line, of the files:\n", FILE_LINE_STRIDE, FILE_LINE_STRIDE);
+        for (i, file) in source.files.iter().enumerate().skip(1) {
+            annotated += &format!("{:>6} | {}\n", i, file.path.display());
         }
     }
 
-    pub fn set_vec2_in_array(&self, name: &str, value: [f32; 2], index: usize) {
-        unsafe {
-            gl::Uniform2fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), 1, value.as_ptr());
+    annotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // writes `files` into a fresh directory and returns the path of the first one
+    fn write_sources(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("yee-rt-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for (name, code) in files {
+            fs::write(directory.join(name), code).unwrap();
         }
+        directory.join(files[0].0)
     }
 
-    pub fn set_vec3_in_array(&self, name: &str, value: [f32; 3], index: usize) {
-        unsafe {
-            gl::Uniform3fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), 1, value.as_ptr());
+    // makes the file look modified whatever the file system's timestamp resolution
+    fn touch(path: &Path) {
+        let file = fs::File::options().append(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_the_old_sources_until_every_stage_reads() {
+        let vertex = write_sources("reload", &[
+            ("main.vsh", "#version 430\nvoid main() {}\n"),
+            ("main.fsh", "#version 430\nvoid main() {}\n"),
+        ]);
+        let fragment = vertex.with_file_name("main.fsh");
+        let mut shader = Shader::from_paths("main", [(vertex.clone(), ShaderType::Vertex), (fragment.clone(), ShaderType::Fragment)]).unwrap();
+
+        // an editor saving the vertex stage while the fragment stage is halfway through being replaced
+        fs::write(&vertex, "#version 430\n#define CHANGED 1\nvoid main() {}\n").unwrap();
+        touch(&vertex);
+        fs::remove_file(&fragment).unwrap();
+
+        assert!(matches!(shader.reload_if_changed(), Err(ShaderError::Io { path, .. }) if path == fragment));
+        assert_eq!(shader.get_define("CHANGED", ShaderType::Vertex), None);
+        assert!(shader.stages.iter().all(|stage| stage.source.lines.len() == 2));
+        // nothing was marked as read, so the next call tries again
+        assert!(shader.stages[0].source.changed());
+    }
+
+    const COMPUTE_MAIN: &str = "#version 430\nlayout(local_size_x = 1) in;\n#include \"body.glsl\"\nvoid main() { body(); }\n";
+    const WORKING_BODY: &str = "void body() {\n}\n";
+    const BROKEN_BODY: &str = "void body() {\n    undeclared = 1;\n}\n";
+
+    fn compute_shader(test: &str, body: &str) -> (Shader, PathBuf) {
+        let path = write_sources(test, &[("main.csh", COMPUTE_MAIN), ("body.glsl", body)]);
+        (Shader::from_paths(test, [(path.clone(), ShaderType::Compute)]).unwrap(), path.with_file_name("body.glsl"))
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn compile_error_points_at_the_included_line() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let (mut shader, body) = compute_shader("compile-error", BROKEN_BODY);
+
+        match shader.compile() {
+            Err(ShaderError::Compile { stage, path, log }) => {
+                assert_eq!(stage, ShaderType::Compute);
+                assert_eq!(path, body.with_file_name("main.csh"));
+                assert!(log.starts_with(&format!("{}:2(", body.display())), "{}", log);
+                assert!(log.contains("     2 |     undeclared = 1;"), "{}", log);
+            },
+            Err(e) => panic!("expected a compile error, got {}", e),
+            Ok(()) => panic!("expected a compile error"),
         }
+        assert_eq!(shader.id, 0);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn failed_reload_keeps_the_last_program() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let (mut shader, body) = compute_shader("failed-reload", BROKEN_BODY);
+
+        assert!(shader.compile().is_err());
+        touch(&body);
+        assert!(shader.reload_if_changed().is_err());
+        assert!(!shader.is_compiled());
+
+        fs::write(&body, WORKING_BODY).unwrap();
+        touch(&body);
+        assert!(shader.reload_if_changed().unwrap());
+        assert!(shader.is_compiled());
+        let id = shader.id;
+
+        fs::write(&body, BROKEN_BODY).unwrap();
+        touch(&body);
+        assert!(matches!(shader.reload_if_changed(), Err(ShaderError::Compile { .. })));
+        assert!(shader.is_compiled());
+        assert_eq!(shader.id, id);
+        // the broken sources were read, so they aren't tried again until they change
+        assert!(!shader.reload_if_changed().unwrap());
     }
 
-    pub fn set_vec4_in_array(&self, name: &str, value: [f32; 4], index: usize) {
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn dropping_deletes_the_program() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let (mut shader, _) = compute_shader("drop", WORKING_BODY);
+
+        shader.compile().unwrap();
+        let id = shader.id;
+        assert_eq!(unsafe { gl::IsProgram(id) }, gl::TRUE);
+        drop(shader);
+        assert_eq!(unsafe { gl::IsProgram(id) }, gl::FALSE);
+    }
+
+    const UNIFORMS: &str = "\
+#version 430
+layout(local_size_x = 1) in;
+#define SCALE_NAME scale
+uniform float SCALE_NAME;
+uniform vec3 tint;
+uniform float weights[3];
+layout(std430, binding = 0) buffer Result { float result; };
+void main() { result = SCALE_NAME + tint.x + weights[0] + weights[1] + weights[2]; }
+";
+
+    fn uniform_shader(test: &str) -> Shader {
+        let path = write_sources(test, &[("uniforms.csh", UNIFORMS)]);
+        let mut shader = Shader::from_paths(test, [(path, ShaderType::Compute)]).unwrap();
+        shader.compile().unwrap();
+        shader.use_program();
+        shader
+    }
+
+    fn uniform_value(shader: &Shader, name: &str) -> [f32; 3] {
+        let mut value = [0.0; 3];
+        let name = CString::new(name).unwrap();
         unsafe {
-            gl::Uniform4fv(gl::GetUniformLocation(self.id, std::ffi::CString::new(format!("{}[{}]", name, index)).unwrap().as_ptr()), 1, value.as_ptr());
+            gl::GetUniformfv(shader.id, gl::GetUniformLocation(shader.id, name.as_ptr()), value.as_mut_ptr());
         }
+        value
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn uniforms_are_set_through_the_cached_locations() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let shader = uniform_shader("uniform-cache");
+
+        let mut names: Vec<&str> = shader.uniforms.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["scale", "tint", "weights"]);
+        assert_eq!(shader.uniforms["weights"].locations.len(), 3);
+
+        shader.set_uniform("scale", 2.0f32);
+        shader.set_uniform("tint", Vector3::new(0.25f32, 0.5, 0.75));
+        shader.set_uniform_in_array("weights", 4.0f32, 2);
+        assert_eq!(uniform_value(&shader, "scale")[0], 2.0);
+        assert_eq!(uniform_value(&shader, "tint"), [0.25, 0.5, 0.75]);
+        assert_eq!(uniform_value(&shader, "weights[2]")[0], 4.0);
+        assert!(shader.warned_uniforms.borrow().is_empty());
     }
 
-    fn extract_defines(shader_code: &str) -> Vec<(String, String)> {
-        let mut defines = Vec::new();
-        let mut pos = 0;
-    
-        while let Some(start) = shader_code[pos..].find("#define ") {
-            pos += start;
-            let end = shader_code[pos..].find('\n').unwrap_or(shader_code.len() - pos) + pos;
-            let define = &shader_code[pos..end];
-    
-            let mut parts = define.split_whitespace();
-            let _ = parts.next();
-            let name = parts.next().unwrap_or("").to_string();
-            let value = parts.collect::<Vec<&str>>().join(" ");
-    
-            defines.push((name, value));
-            pos = end + 1;
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn relinking_replaces_the_cached_uniforms() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let mut shader = uniform_shader("uniform-relink");
+
+        shader.set_uniform("gain", 2.0f32);
+        assert!(shader.warned_uniforms.borrow().contains("gain"));
+
+        shader.add_define("SCALE_NAME", "gain", ShaderType::Compute).unwrap();
+        shader.compile().unwrap();
+        shader.use_program();
+        assert!(shader.uniforms.contains_key("gain"));
+        assert!(!shader.uniforms.contains_key("scale"));
+        // warnings are about the old program
+        assert!(shader.warned_uniforms.borrow().is_empty());
+
+        shader.set_uniform("gain", 2.0f32);
+        assert_eq!(uniform_value(&shader, "gain")[0], 2.0);
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn mismatched_uniforms_are_skipped_with_a_warning() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let shader = uniform_shader("uniform-mismatch");
+
+        shader.set_uniform("tint", 1.0f32);
+        shader.set_uniform_in_array("weights", 1.0f32, 3);
+        shader.set_uniform("scale", 1u32);
+
+        assert_eq!(uniform_value(&shader, "tint"), [0.0; 3]);
+        assert_eq!(uniform_value(&shader, "scale")[0], 0.0);
+        let warned = shader.warned_uniforms.borrow();
+        assert!(["tint", "weights", "scale"].iter().all(|name| warned.contains(*name)), "{:?}", warned);
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let path = write_sources("cycle", &[("a.glsl", "#include \"b.glsl\"\n"), ("b.glsl", "#include \"a.glsl\"\n")]);
+        match Source::load(&path) {
+            Err(ShaderError::Include(message)) => assert!(message.starts_with("Include cycle: "), "{}", message),
+            Err(e) => panic!("expected an include cycle, got {}", e),
+            Ok(_) => panic!("expected an include cycle"),
         }
-    
-        defines
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let path = write_sources("once", &[
+            ("main.csh", "#version 430\n#include \"common.glsl\"\n#include \"scene.glsl\"\n"),
+            ("scene.glsl", "#include \"common.glsl\"\nvoid scene() {}\n"),
+            ("common.glsl", "void common() {}\n"),
+        ]);
+        let source = Source::load(&path).unwrap();
+        assert_eq!(source.files.len(), 3);
+        assert_eq!(source.lines.iter().filter(|line| line.text == "void common() {}").count(), 1);
+    }
+
+    #[test]
+    fn log_points_at_included_file() {
+        let path = write_sources("lines", &[
+            ("main.csh", "#version 430\n#include \"scene.glsl\"\nvoid main() {}\n"),
+            ("scene.glsl", "float scene() {\n    return 1.;\n}\n"),
+        ]);
+        let source = Source::load(&path).unwrap();
+
+        let code = preprocess(&source, &[], &[]);
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(lines, ["#version 430", "#line 100001 1", "float scene() {", "    return 1.;", "}", "#line 3 0", "void main() {}"]);
+
+        // Mesa numbers the lines after #line but keeps source string 0
+        assert_eq!(log_location("0:100002(5): error: oops"), Some(("", 0, 100002, "(5): error: oops")));
+        let scene = path.with_file_name("scene.glsl");
+        assert_eq!(annotate_log("0:100002(5): error: oops\n", &source), format!("{}:2(5): error: oops\n     2 |     return 1.;\n", scene.display()));
+        assert_eq!(annotate_log("0:3(1): error: oops\n", &source), format!("{}:3(1): error: oops\n     3 | void main() {{}}\n", path.display()));
+    }
+
+    #[test]
+    fn unplaced_line_numbers_are_explained() {
+        let path = write_sources("unplaced", &[("main.csh", "#version 430\n#include \"scene.glsl\"\n"), ("scene.glsl", "void scene() {}\n")]);
+        let source = Source::load(&path).unwrap();
+
+        let annotated = annotate_log("error: scene() at line 100001 is unused\n", &source);
+        let scene = path.with_file_name("scene.glsl");
+        assert_eq!(annotated, format!("error: scene() at line 100001 is unused\nLines from 100000 up are file * 100000 + line, of the files:\n     1 | {}\n", scene.display()));
+    }
+
+    const TRACER: &str = "#version 430\n#define MAX_BOUNCES 10\n#define SAMPLES_PER_PIXEL 1\nvoid main() {}\n";
+
+    fn preprocess_tracer(test: &str, define_overrides: &[(&str, Option<&str>)]) -> String {
+        let source = Source::load(&write_sources(test, &[("tracer.csh", TRACER)])).unwrap();
+        let define_overrides: Vec<(String, Option<String>)> =
+            define_overrides.iter().map(|(name, value)| (name.to_string(), value.map(str::to_string))).collect();
+        preprocess(&source, &define_overrides, &[])
+    }
+
+    #[test]
+    fn overridden_define_keeps_its_place() {
+        let code = preprocess_tracer("override", &[("MAX_BOUNCES", Some("3"))]);
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(lines[..3], ["#version 430", "#define MAX_BOUNCES 3", "#define SAMPLES_PER_PIXEL 1"]);
+    }
+
+    #[test]
+    fn removed_define_leaves_an_empty_line() {
+        let code = preprocess_tracer("remove", &[("MAX_BOUNCES", None)]);
+        assert!(!code.contains("MAX_BOUNCES"));
+        // so the lines after it keep their numbers
+        assert_eq!(code.lines().nth(2), Some("#define SAMPLES_PER_PIXEL 1"));
+    }
+
+    #[test]
+    fn added_define_goes_after_version() {
+        let code = preprocess_tracer("add", &[("WORKGROUP_SIZE_X", Some("8"))]);
+        let lines: Vec<&str> = code.lines().collect();
+        assert_eq!(lines[..4], ["#version 430", "#define WORKGROUP_SIZE_X 8", "#line 2 0", "#define MAX_BOUNCES 10"]);
+    }
+
+    #[test]
+    fn overrides_apply_in_order() {
+        let defines = vec![("MAX_BOUNCES".to_string(), "10".to_string()), ("SAMPLES_PER_PIXEL".to_string(), "1".to_string())];
+        let overrides = [
+            ("MAX_BOUNCES".to_string(), Some("3".to_string())),
+            ("SAMPLES_PER_PIXEL".to_string(), None),
+            ("WORKGROUP_SIZE_X".to_string(), Some("8".to_string())),
+        ];
+        let defines = apply_define_overrides(defines, &overrides);
+        assert_eq!(defines, [("MAX_BOUNCES".to_string(), "3".to_string()), ("WORKGROUP_SIZE_X".to_string(), "8".to_string())]);
+    }
+
+    #[test]
+    fn defines_wait_for_the_next_compile() {
+        let mut shader = Shader::new_compute("main").unwrap();
+        shader.add_define("MAX_BOUNCES", "3", ShaderType::Compute).unwrap();
+        assert_eq!(shader.get_define("MAX_BOUNCES", ShaderType::Compute), Some("3"));
+        assert_eq!(shader.get_compiled_define("MAX_BOUNCES", ShaderType::Compute), None);
+        assert!(shader.needs_recompile());
+
+        // a removed override falls back to the file's own define
+        shader.remove_define("MAX_BOUNCES", ShaderType::Compute);
+        assert_ne!(shader.get_define("MAX_BOUNCES", ShaderType::Compute), Some("3"));
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn renderer_reports_the_permutation_it_set() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+        let mut renderer = crate::renderer::Renderer::new(8, 8, crate::renderer::Backend::Compute).unwrap();
+
+        renderer.set_max_bounces(3).unwrap();
+        renderer.set_samples_per_pixel(2).unwrap();
+        assert_eq!((renderer.max_bounces(), renderer.samples_per_pixel()), (3, 2));
+    }
+
+    #[test]
+    #[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+    fn failed_compile_keeps_the_compiled_defines() {
+        let _context = crate::headless::HeadlessContext::new().unwrap();
+
+        let mut shader = Shader::new_compute("main").unwrap();
+        shader.inject("gpu_layout", &crate::gpu::glsl_definitions(), ShaderType::Compute);
+        shader.add_define("MAX_BOUNCES", "3", ShaderType::Compute).unwrap();
+        shader.compile().unwrap();
+        shader.add_define("MAX_BOUNCES", "(", ShaderType::Compute).unwrap();
+        assert!(shader.compile().is_err());
+        assert_eq!(shader.get_compiled_define("MAX_BOUNCES", ShaderType::Compute), Some("3"));
+        assert!(shader.needs_recompile());
     }
 }
@@ -0,0 +1,131 @@
+use nalgebra::Vector3;
+
+use std::f32::consts::PI;
+
+use crate::gpu::GpuSky;
+
+// the model's luminances are in kcd/m², this makes a clear midday sky about as bright as the gradient one
+const LUMINANCE_SCALE: f32 = 0.1;
+// roughly the sun's luminance outside the atmosphere, in kcd/m²
+const SUN_LUMINANCE: f32 = 1.6e6;
+// the sky fades out while the sun is up to this many degrees below the horizon
+const TWILIGHT: f32 = 6.0;
+
+/// Preetham's analytic daylight sky and the sun, an alternative to an environment map.
+///
+/// Paths see the sky wherever they leave the scene, and diffuse surfaces sample the sun disk directly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhysicalSky {
+    /// Degrees above the horizon.
+    pub sun_elevation: f32,
+    /// Degrees from -z towards +x, so with -z as north 90 puts the sun in the east.
+    pub sun_azimuth: f32,
+    /// Haziness, from about 2 for a very clear day to 10 for a hazy one.
+    pub turbidity: f32,
+    /// Multiplies the sky and sun.
+    pub intensity: f32,
+    /// Angular radius of the sun disk in degrees.
+    pub sun_radius: f32,
+}
+
+impl PhysicalSky {
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32) -> Self {
+        PhysicalSky {
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+            intensity: 1.0,
+            sun_radius: 0.27,
+        }
+    }
+
+    /// Moves the sun to where it is at `hours` local solar time, 12 being noon, on `day_of_year` at `latitude`
+    /// degrees north, with -z pointing north.
+    pub fn set_time_of_day(&mut self, hours: f32, latitude: f32, day_of_year: u32) {
+        let declination = (-23.44f32).to_radians() * (2.0 * PI / 365.0 * (day_of_year as f32 + 10.0)).cos();
+        let hour_angle = (15.0 * (hours - 12.0)).to_radians();
+        let latitude = latitude.to_radians();
+
+        let sin_elevation = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+        let azimuth = (-declination.cos() * hour_angle.sin())
+            .atan2(declination.sin() * latitude.cos() - declination.cos() * latitude.sin() * hour_angle.cos());
+
+        self.sun_elevation = sin_elevation.clamp(-1.0, 1.0).asin().to_degrees();
+        self.sun_azimuth = azimuth.to_degrees().rem_euclid(360.0);
+    }
+
+    /// Unit vector pointing at the sun.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let elevation = self.sun_elevation.to_radians();
+        let azimuth = self.sun_azimuth.to_radians();
+        Vector3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+    }
+
+    pub fn gpu_data(&self) -> GpuSky {
+        let t = self.turbidity;
+        // the fit only holds with the sun above the horizon, below it the sky just fades out
+        let sun_theta = (90.0 - self.sun_elevation.max(0.0)).to_radians();
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608],
+            [-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092],
+            [-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102],
+            [0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537],
+            [-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529],
+        ].map(Vector3::from);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = Vector3::new(sun_theta.powi(3), sun_theta.powi(2), sun_theta);
+        let zenith_x = t * t * theta.dot(&Vector3::new(0.00166, -0.00375, 0.00209))
+            + t * (theta.dot(&Vector3::new(-0.02903, 0.06377, -0.03202)) + 0.00394)
+            + theta.dot(&Vector3::new(0.11693, -0.21196, 0.06052)) + 0.25886;
+        let zenith_y = t * t * theta.dot(&Vector3::new(0.00275, -0.00610, 0.00317))
+            + t * (theta.dot(&Vector3::new(-0.04214, 0.08970, -0.04153)) + 0.00516)
+            + theta.dot(&Vector3::new(0.15346, -0.26756, 0.06670)) + 0.26688;
+
+        let fade = ((self.sun_elevation + TWILIGHT) / TWILIGHT).clamp(0.0, 1.0);
+        let zenith = Vector3::new(zenith_luminance * LUMINANCE_SCALE * self.intensity * fade, zenith_x, zenith_y);
+        // the shader multiplies by the Perez function and has to get the zenith values back straight up
+        let zenith = zenith.component_div(&perez_function(&perez, 0.0, sun_theta));
+
+        let cos_sun_radius = (self.sun_radius as f64).to_radians().cos();
+
+        GpuSky {
+            sun_direction: self.sun_direction().into(),
+            enabled: 1,
+            sun_radiance: (self.sun_transmittance() * SUN_LUMINANCE * LUMINANCE_SCALE * self.intensity).into(),
+            cos_sun_radius: cos_sun_radius as f32,
+            zenith: zenith.into(),
+            // 1 - cos loses most of its precision in single precision for a disk this small
+            sun_solid_angle: (2.0 * std::f64::consts::PI * (1.0 - cos_sun_radius)) as f32,
+            perez: perez.map(|c| c.push(0.0).into()),
+        }
+    }
+
+    // how much of the sunlight at red, green and blue wavelengths makes it through the atmosphere
+    fn sun_transmittance(&self) -> Vector3<f32> {
+        if self.sun_elevation < -self.sun_radius {
+            return Vector3::zeros();
+        }
+
+        // Kasten and Young's relative air mass, which stays finite at the horizon
+        let zenith_angle = (90.0 - self.sun_elevation).min(90.0);
+        let air_mass = 1.0 / (zenith_angle.to_radians().cos() + 0.50572 * (96.07995 - zenith_angle).powf(-1.6364));
+
+        let wavelengths = Vector3::<f32>::new(0.68, 0.55, 0.44);
+        let rayleigh = wavelengths.map(|l| 0.008735 * l.powf(-4.08));
+        // Angstrom's formula with Preetham's turbidity to aerosol relation
+        let aerosol = wavelengths.map(|l| (0.04608 * self.turbidity - 0.04586) * l.powf(-1.3));
+
+        (-(rayleigh + aerosol) * air_mass).map(f32::exp)
+    }
+}
+
+// luminance and chromaticity relative to the zenith, `theta` from the zenith and `gamma` from the sun
+fn perez_function(perez: &[Vector3<f32>; 5], theta: f32, gamma: f32) -> Vector3<f32> {
+    let [a, b, c, d, e] = perez;
+    let cos_gamma = gamma.cos();
+    (Vector3::repeat(1.0) + a.component_mul(&(b / theta.cos()).map(f32::exp)))
+        .component_mul(&(Vector3::repeat(1.0) + c.component_mul(&(d * gamma).map(f32::exp)) + e * cos_gamma * cos_gamma))
+}
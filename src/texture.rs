@@ -0,0 +1,78 @@
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+use std::path::{Path, PathBuf};
+
+/// Image textures of every material in a scene, uploaded to the shader as the layers of one texture array.
+///
+/// Materials refer to textures by layer. All layers of an array are the same size, so images smaller
+/// than the largest width or height get stretched to it.
+#[derive(Default)]
+pub struct TextureBuffer {
+    images: Vec<RgbaImage>,
+    // where each image was loaded from, so materials sharing a file share its layer
+    paths: Vec<Option<PathBuf>>,
+}
+
+impl TextureBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Layer of the PNG or JPEG image at `path`, loading it unless it's already in the buffer.
+    pub fn load(&mut self, path: &Path) -> Result<u32, String> {
+        if let Some(layer) = self.paths.iter().position(|p| p.as_deref() == Some(path)) {
+            return Ok(layer as u32);
+        }
+
+        let image = image::open(path)
+            .map_err(|e| format!("Failed to load texture {}: {}", path.display(), e))?
+            .into_rgba8();
+
+        self.images.push(image);
+        self.paths.push(Some(path.to_path_buf()));
+        Ok(self.images.len() as u32 - 1)
+    }
+
+    /// Adds an image from RGBA8 `pixels` with the top row first and returns its layer.
+    pub fn add(&mut self, width: u32, height: u32, pixels: Vec<u8>) -> Result<u32, String> {
+        let expected = width as usize * height as usize * 4;
+        if pixels.len() != expected {
+            return Err(format!("A {}x{} texture needs {} bytes of pixels, got {}", width, height, expected, pixels.len()));
+        }
+        // the size was checked above
+        let image = RgbaImage::from_raw(width, height, pixels).unwrap();
+
+        self.images.push(image);
+        self.paths.push(None);
+        Ok(self.images.len() as u32 - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Width and height of every layer, the largest of any image.
+    pub fn layer_size(&self) -> (u32, u32) {
+        self.images.iter().fold((1, 1), |(width, height), image| (width.max(image.width()), height.max(image.height())))
+    }
+
+    /// RGBA8 pixels of every layer at `layer_size`, one layer after the other with the top row first.
+    pub fn layers(&self) -> Vec<u8> {
+        let (width, height) = self.layer_size();
+
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 4 * self.images.len());
+        for image in &self.images {
+            if image.dimensions() == (width, height) {
+                pixels.extend_from_slice(image.as_raw());
+            } else {
+                pixels.extend_from_slice(imageops::resize(image, width, height, FilterType::Triangle).as_raw());
+            }
+        }
+        pixels
+    }
+}
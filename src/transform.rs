@@ -1,6 +1,7 @@
 use nalgebra::{Vector3, Matrix4};
 use std::ops::Mul;
 
+#[derive(Clone)]
 pub struct Transform {
     pub position: Vector3<f32>,
     pub scale: Vector3<f32>,
@@ -16,23 +17,23 @@ impl Transform {
         }
     }
 
+    /// Maps world space into the object's local space, where spheres and boxes span -1 to 1.
     pub fn get_model_matrix(&self) -> Matrix4<f32> {
         let mut translation = Matrix4::identity();
-        translation[(3, 0)] = self.position.x;
-        translation[(3, 1)] = self.position.y;
-        translation[(3, 2)] = self.position.z;
+        translation[(3, 0)] = -self.position.x;
+        translation[(3, 1)] = -self.position.y;
+        translation[(3, 2)] = -self.position.z;
         let inverse_scale = Vector3::new(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z);
         let scale = Matrix4::new_nonuniform_scaling(&inverse_scale);
         let rotation = Matrix4::from_euler_angles(self.rotation.x.to_radians(), self.rotation.y.to_radians(), self.rotation.z.to_radians());
         translation * rotation * scale
     }
 
-    pub fn clone(&self) -> Self {
-        Transform {
-            position: self.position,
-            scale: self.scale,
-            rotation: self.rotation
-        }
+    /// Maps local space back into world space.
+    ///
+    /// `get_model_matrix` is laid out for the shader, which reads it back transposed.
+    pub fn get_inverse_model_matrix(&self) -> Matrix4<f32> {
+        self.get_model_matrix().transpose().try_inverse().unwrap_or_else(Matrix4::identity)
     }
 }
 
@@ -49,4 +50,19 @@ impl Mul for Transform {
             rotation
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector4;
+
+    #[test]
+    fn model_matrix_moves_the_position_to_the_origin() {
+        let transform = Transform::new(Vector3::new(1.0, 2.0, 3.0), Vector3::new(2.0, 2.0, 2.0), Vector3::zeros());
+        // the shader reads the matrix transposed
+        let to_local = transform.get_model_matrix().transpose();
+
+        assert_eq!(to_local * Vector4::new(1.0, 2.0, 3.0, 1.0), Vector4::new(0.0, 0.0, 0.0, 1.0));
+        assert_eq!(to_local * Vector4::new(3.0, 2.0, 3.0, 1.0), Vector4::new(1.0, 0.0, 0.0, 1.0));
+    }
+}
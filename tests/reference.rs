@@ -0,0 +1,695 @@
+use nalgebra::Vector3;
+
+use yee_rt::environment::EnvironmentMap;
+use yee_rt::gpu::{self, GpuScene};
+use yee_rt::headless::{self, HeadlessOptions};
+use yee_rt::light::Light;
+use yee_rt::medium::Medium;
+use yee_rt::objects::{self, Material, Object, Sphere};
+use yee_rt::reference::{ReferenceRenderer, RenderInputs};
+use yee_rt::renderer::Backend;
+use yee_rt::scene::{self, Scene};
+use yee_rt::sky::PhysicalSky;
+use yee_rt::texture::TextureBuffer;
+use yee_rt::transform::Transform;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+
+// the default camera sits at z = 3 looking down -z
+fn inputs<'a>() -> RenderInputs<'a> {
+    RenderInputs {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    }
+}
+
+fn scene_inputs(scene: &Scene) -> RenderInputs<'_> {
+    RenderInputs {
+        meshes: Some(&scene.meshes),
+        textures: Some(&scene.textures),
+        environment: scene.environment.as_ref(),
+        camera: Some(&scene.camera),
+        ..inputs()
+    }
+}
+
+fn pixel(image: &[u8], x: u32, y: u32) -> [u8; 4] {
+    let i = ((y * WIDTH + x) * 4) as usize;
+    [image[i], image[i + 1], image[i + 2], image[i + 3]]
+}
+
+fn mean_abs_difference(a: &[u8], b: &[u8]) -> f32 {
+    let total: u32 = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b) as u32).sum();
+    total as f32 / a.len() as f32
+}
+
+#[test]
+fn empty_scene_is_all_sky() {
+    let image = ReferenceRenderer::default().render(&GpuScene::new(), &inputs());
+
+    // the camera looks straight down -z, so the sky is mirrored left to right
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH / 2 {
+            let left = pixel(&image, x, y);
+            let right = pixel(&image, WIDTH - 1 - x, y);
+            for c in 0..4 {
+                assert!(left[c].abs_diff(right[c]) <= 1);
+            }
+        }
+    }
+
+    // the gradient goes from blue overhead to white at the horizon
+    let top = pixel(&image, 0, 0);
+    let bottom = pixel(&image, 0, HEIGHT - 1);
+    assert!(top[2] >= top[0]);
+    assert!(bottom[0] > top[0]);
+}
+
+#[test]
+fn render_is_deterministic() {
+    let scene = Scene::load(scene::DEFAULT_SCENE.as_ref()).unwrap();
+    let data = scene.get_gpu_data();
+    let renderer = ReferenceRenderer::default();
+
+    let a = renderer.render(&data, &RenderInputs { camera: None, ..scene_inputs(&scene) });
+    let b = renderer.render(&data, &RenderInputs { camera: None, ..scene_inputs(&scene) });
+
+    assert_eq!(a, b);
+}
+
+#[test]
+fn red_sphere_in_front_of_camera() {
+    let sphere = Sphere::new(
+        Transform::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(0.0, 0.0, 0.0)
+        ),
+        Material {
+            base_color: Vector3::new(1.0, 0.0, 0.0),
+            // no white highlights
+            specular: 0.0,
+            ..Default::default()
+        }
+    );
+    let mut gpu_scene = GpuScene::new();
+    sphere.write_gpu_data(&mut gpu_scene);
+
+    let image = ReferenceRenderer::default().render(&gpu_scene, &inputs());
+
+    let center = pixel(&image, WIDTH / 2, HEIGHT / 2);
+    assert!(center[0] > 0);
+    // even without specular the Fresnel term reflects a little white off microfacets seen at grazing angles
+    assert!(center[1] <= 2);
+    assert!(center[2] <= 2);
+}
+
+#[test]
+fn lights_only_reach_what_they_face() {
+    let sphere = Sphere::new(Transform::new(Vector3::zeros(), Vector3::new(1.0, 1.0, 1.0), Vector3::zeros()), Material::default());
+    let mut unlit = GpuScene::new();
+    sphere.write_gpu_data(&mut unlit);
+
+    let mut front = unlit.clone();
+    Light::Point {
+        position: Vector3::new(0.0, 2.0, 2.0),
+        color: Vector3::new(1.0, 1.0, 1.0),
+        intensity: 10.0,
+    }.write_gpu_data(&mut front);
+
+    // behind the sphere and pointing away from the camera, nothing visible faces either of them
+    let mut back = unlit.clone();
+    Light::Point {
+        position: Vector3::new(0.0, 0.0, -3.0),
+        color: Vector3::new(1.0, 1.0, 1.0),
+        intensity: 10.0,
+    }.write_gpu_data(&mut back);
+    Light::Spot {
+        position: Vector3::new(0.0, 0.0, 1.5),
+        direction: Vector3::new(0.0, 0.0, 1.0),
+        color: Vector3::new(1.0, 1.0, 1.0),
+        intensity: 10.0,
+        inner_angle: 20.0,
+        outer_angle: 30.0,
+    }.write_gpu_data(&mut back);
+
+    let renderer = ReferenceRenderer::default();
+    let render = |scene: &GpuScene| renderer.render(scene, &inputs());
+    let unlit = render(&unlit);
+
+    assert!(pixel(&render(&front), WIDTH / 2, HEIGHT / 2)[0] > pixel(&unlit, WIDTH / 2, HEIGHT / 2)[0]);
+    assert_eq!(render(&back), unlit);
+}
+
+#[test]
+fn environment_cdf_follows_brightness() {
+    let mut pixels = vec![[0.1, 0.1, 0.1]; 8 * 4];
+    pixels[8 + 5] = [100.0, 100.0, 100.0];
+    let environment = EnvironmentMap::new(8, 4, pixels).unwrap();
+
+    let cdf = environment.cdf();
+    assert_eq!(cdf.len(), 8 * 4 + 4);
+    // every row and the marginal distribution end at 1
+    for end in [7, 15, 23, 31, 35] {
+        assert!((cdf[end] - 1.0).abs() < 1e-6);
+    }
+    // the bright pixel takes up almost all of its row, and its row almost all of the image
+    assert!(cdf[8 + 5] - cdf[8 + 4] > 0.9);
+    assert!(cdf[32 + 1] - cdf[32] > 0.9);
+}
+
+#[test]
+fn environment_map_needs_every_pixel() {
+    let error = EnvironmentMap::new(8, 4, vec![[0.5; 3]; 31]).err().unwrap();
+    assert_eq!(error, "A 8x4 environment map needs 32 pixels, got 31");
+}
+
+#[test]
+fn environment_map_replaces_sky() {
+    let environment = EnvironmentMap::new(4, 2, vec![[0.5, 0.25, 0.125]; 8]).unwrap();
+    let image = ReferenceRenderer::default().render(&GpuScene::new(), &RenderInputs { environment: Some(&environment), ..inputs() });
+
+    let color = pixel(&image, 0, 0);
+    assert!(color[0] > color[1] && color[1] > color[2]);
+    assert!(image.chunks_exact(4).all(|p| p == color));
+}
+
+// radius 1 at the origin, right in front of the default camera
+fn unit_sphere(material: Material) -> Sphere {
+    Sphere::new(Transform::new(Vector3::zeros(), Vector3::new(1.0, 1.0, 1.0), Vector3::zeros()), material)
+}
+
+// renders `object` made of `material` inside an environment that is equally bright in every direction.
+fn render_in_furnace<O: Object>(object: impl FnOnce(Material) -> O, material: Material) -> Vec<u8> {
+    let environment = EnvironmentMap::new(8, 4, vec![[0.5; 3]; 32]).unwrap();
+    let mut gpu_scene = GpuScene::new();
+    object(material).write_gpu_data(&mut gpu_scene);
+
+    ReferenceRenderer::default().render(&gpu_scene, &RenderInputs { environment: Some(&environment), ..inputs() })
+}
+
+// the mean red value of the 8x8 pixels around the middle of the image.
+fn center_average(pixels: &[u8]) -> f32 {
+    let mut total = 0.0;
+    for y in HEIGHT / 2 - 4..HEIGHT / 2 + 4 {
+        for x in WIDTH / 2 - 4..WIDTH / 2 + 4 {
+            total += pixel(pixels, x, y)[0] as f32;
+        }
+    }
+    total / 64.0
+}
+
+#[test]
+fn smooth_white_metal_vanishes_in_a_furnace() {
+    // inside a uniformly bright environment a perfect reflector looks exactly like its surroundings,
+    // which only holds if sampling the BSDF and the environment weigh each other correctly
+    let image = render_in_furnace(unit_sphere, Material {
+        metallic: 1.0,
+        roughness: 0.2,
+        ..Default::default()
+    });
+    let background = pixel(&image, 0, 0)[0] as f32;
+
+    let sphere = center_average(&image);
+    assert!((sphere - background).abs() < 6.0, "sphere {} against background {}", sphere, background);
+}
+
+#[test]
+fn thick_glass_absorbs_more_than_thin_glass() {
+    // the same tinted glass at two sizes in front of a white background, light crossing the big one travels
+    // twice as far through it and so comes out a deeper red
+    let glass = Material {
+        transmission: 1.0,
+        roughness: 0.0,
+        absorption: Vector3::new(0.0, 1.0, 1.0),
+        ..Default::default()
+    };
+
+    let render = |radius: f32| {
+        render_in_furnace(|material| Sphere::new(Transform::new(Vector3::zeros(), Vector3::repeat(radius), Vector3::zeros()), material), glass.clone())
+    };
+
+    let thin = pixel(&render(0.5), WIDTH / 2, HEIGHT / 2);
+    let thick = pixel(&render(1.0), WIDTH / 2, HEIGHT / 2);
+    assert!(thin[0] > thin[1] && thin[1] == thin[2]);
+    assert!(thick[1] < thin[1]);
+    // red isn't absorbed at all, only a little gets reflected off the surface either way
+    assert!((thick[0] as i32 - thin[0] as i32).abs() < 10);
+}
+
+fn render_volume(medium: Medium) -> Vec<u8> {
+    // glass with an index of refraction of 1 only marks where the medium starts
+    render_in_furnace(unit_sphere, Material {
+        transmission: 1.0,
+        ior: 1.0,
+        medium: Some(medium),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn absorbing_volume_dims_the_background_with_depth() {
+    let image = render_volume(Medium::new(Vector3::zeros(), Vector3::new(0.2, 1.0, 1.0), 0.0));
+
+    let background = pixel(&image, 0, 0);
+    let center = pixel(&image, WIDTH / 2, HEIGHT / 2);
+    let edge = pixel(&image, WIDTH / 2 + WIDTH / 8, HEIGHT / 2);
+    // red is hardly absorbed, and light crossing the middle goes through more of the medium than near the edge
+    assert!(center[0] > center[1] && center[1] == center[2]);
+    assert!(center[1] < edge[1] && edge[1] < background[1]);
+}
+
+#[test]
+fn scattering_volume_vanishes_in_a_furnace() {
+    // without absorption light only changes direction, so in a uniformly bright environment the volume can't be seen
+    let image = render_volume(Medium::new(Vector3::repeat(1.0), Vector3::zeros(), 0.4));
+    let background = pixel(&image, 0, 0)[0] as f32;
+
+    let volume = center_average(&image);
+    assert!((volume - background).abs() < 6.0, "volume {} against background {}", volume, background);
+}
+
+#[test]
+fn subsurface_slab_lets_light_through() {
+    // lit only from behind, so the face towards the camera can only glow with light that went through the slab
+    let render = |subsurface: f32| {
+        let material = Material {
+            base_color: Vector3::new(0.8, 0.8, 0.8),
+            subsurface,
+            subsurface_color: Vector3::new(0.8, 0.8, 0.8),
+            mean_free_path: Vector3::repeat(0.2),
+            ..Default::default()
+        };
+        let slab = objects::Box::new(Transform::new(Vector3::zeros(), Vector3::new(1.0, 1.0, 0.1), Vector3::zeros()), material);
+        let mut gpu_scene = GpuScene::new();
+        slab.write_gpu_data(&mut gpu_scene);
+        Light::Point {
+            position: Vector3::new(0.0, 0.0, -2.0),
+            color: Vector3::new(1.0, 1.0, 1.0),
+            intensity: 10.0,
+        }.write_gpu_data(&mut gpu_scene);
+
+        let environment = EnvironmentMap::new(8, 4, vec![[0.0; 3]; 32]).unwrap();
+        ReferenceRenderer::default().render(&gpu_scene, &RenderInputs { environment: Some(&environment), ..inputs() })
+    };
+
+    let diffuse = render(0.0);
+    let subsurface = render(1.0);
+    assert_eq!(pixel(&diffuse, WIDTH / 2, HEIGHT / 2)[0], 0);
+    assert!(pixel(&subsurface, WIDTH / 2, HEIGHT / 2)[0] > 20, "{:?}", pixel(&subsurface, WIDTH / 2, HEIGHT / 2));
+}
+
+#[test]
+fn quad_lights_shine_from_their_front() {
+    let render = |rotation: Vector3<f32>| {
+        let panel = Material {
+            base_color: Vector3::zeros(),
+            emission: Vector3::new(1.0, 1.0, 1.0),
+            emission_strength: 1.0,
+            ..Default::default()
+        };
+        let quad = objects::Quad::new(Transform::new(Vector3::zeros(), Vector3::new(1.0, 1.0, 1.0), rotation), panel);
+        let mut gpu_scene = GpuScene::new();
+        quad.write_gpu_data(&mut gpu_scene);
+
+        let environment = EnvironmentMap::new(8, 4, vec![[0.0; 3]; 32]).unwrap();
+        ReferenceRenderer::default().render(&gpu_scene, &RenderInputs { environment: Some(&environment), ..inputs() })
+    };
+
+    // +y turned towards the camera and away from it
+    let front = render(Vector3::new(90.0, 0.0, 0.0));
+    let back = render(Vector3::new(-90.0, 0.0, 0.0));
+    assert!(pixel(&front, WIDTH / 2, HEIGHT / 2)[0] > 100);
+    assert_eq!(pixel(&back, WIDTH / 2, HEIGHT / 2)[0], 0);
+    assert_eq!(pixel(&front, 0, 0)[0], 0);
+}
+
+#[test]
+fn plane_reaches_the_horizon() {
+    let ground = Material {
+        emission: Vector3::new(1.0, 1.0, 1.0),
+        emission_strength: 1.0,
+        ..Default::default()
+    };
+    let environment = EnvironmentMap::new(8, 4, vec![[0.0; 3]; 32]).unwrap();
+
+    // scaling the plane down mustn't shrink how far it reaches
+    for scale in [1.0, 0.001] {
+        let plane = objects::Plane::new(Transform::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(scale, 1.0, scale), Vector3::zeros()), ground.clone());
+        let mut gpu_scene = GpuScene::new();
+        plane.write_gpu_data(&mut gpu_scene);
+        assert!(gpu_scene.emissive_objects().is_empty());
+        // the shader only finds the plane where its bounds in the BVH reach
+        assert!(plane.bounds().min.x <= -10000.0 && plane.bounds().max.z >= 10000.0);
+
+        let image = ReferenceRenderer::default().render(&gpu_scene, &RenderInputs { environment: Some(&environment), ..inputs() });
+
+        // the camera looks along the plane from above it, so it fills the whole bottom half right up to the middle
+        assert!(pixel(&image, 0, HEIGHT - 1)[0] > 100);
+        assert!(pixel(&image, WIDTH - 1, HEIGHT / 2 + 1)[0] > 100, "plane scaled by {} stops short of the horizon", scale);
+        assert_eq!(pixel(&image, WIDTH / 2, HEIGHT / 2 - 2)[0], 0);
+    }
+}
+
+#[test]
+fn time_of_day_moves_the_sun() {
+    let mut sky = PhysicalSky::new(0.0, 0.0, 3.0);
+
+    // around the equinox at the equator the sun rises in the east and passes straight overhead
+    sky.set_time_of_day(12.0, 0.0, 80);
+    assert!(sky.sun_elevation > 85.0);
+    sky.set_time_of_day(9.0, 0.0, 80);
+    assert!((sky.sun_elevation - 45.0).abs() < 3.0);
+    assert!(sky.sun_direction().x > 0.5);
+
+    // a northern summer afternoon has the sun in the south west
+    sky.set_time_of_day(16.0, 45.0, 172);
+    let direction = sky.sun_direction();
+    assert!(direction.x < 0.0 && direction.z > 0.0 && direction.y > 0.0);
+
+    // at night the sun is gone and the sky fades out
+    sky.set_time_of_day(0.0, 45.0, 172);
+    let night = sky.gpu_data();
+    assert_eq!(night.sun_radiance, [0.0; 3]);
+    assert_eq!(night.zenith[0], 0.0);
+}
+
+fn parse_error(source: &str) -> String {
+    Scene::parse(source, "assets/scenes".as_ref()).err().expect("scene should have been rejected")
+}
+
+#[test]
+fn scene_groups_expand_into_their_children() {
+    let scene = Scene::parse(r#"
+        [materials.white]
+        color = [1.0, 1.0, 1.0]
+
+        [[objects]]
+        type = "group"
+        position = [0.0, 0.0, -5.0]
+
+        [[objects.objects]]
+        type = "sphere"
+        material = "white"
+        position = [-2.0, 0.0, 0.0]
+
+        [[objects.objects]]
+        type = "box"
+        material = "white"
+        position = [2.0, 0.0, 0.0]
+    "#, "assets/scenes".as_ref()).unwrap();
+
+    assert_eq!(scene.objects.len(), 1);
+    let gpu_scene = scene.get_gpu_data();
+    assert_eq!(gpu_scene.objects.len(), 2);
+    // one leaf pointing at both children
+    assert_eq!((gpu_scene.bvh[0].offset, gpu_scene.bvh[0].count), (0, 2));
+
+    // the children are placed relative to the group
+    let bounds = scene.objects[0].object.bounds();
+    assert_eq!(bounds.min, Vector3::new(-3.0, -1.0, -6.0));
+    assert_eq!(bounds.max, Vector3::new(3.0, 1.0, -4.0));
+}
+
+#[test]
+fn scene_rejects_bad_objects() {
+    let material = "[materials.white]\ncolor = [1.0, 1.0, 1.0]\n";
+
+    assert_eq!(parse_error("[[objects]]\ntype = \"sphere\"\nmaterial = \"red\""), "Unknown material \"red\"");
+    assert_eq!(parse_error("[[objects]]\ntype = \"sphere\""), "Object is missing a material");
+    assert_eq!(parse_error(&format!("{}[[objects]]\ntype = \"mesh\"\nmaterial = \"white\"", material)), "Meshes, and only meshes, need a path");
+    assert_eq!(
+        parse_error(&format!("{}[[objects]]\ntype = \"sphere\"\nmaterial = \"white\"\n[[objects.objects]]\ntype = \"sphere\"\nmaterial = \"white\"", material)),
+        "Only groups can contain objects"
+    );
+    assert_eq!(
+        parse_error(&format!("{}[[objects]]\ntype = \"group\"\n[[objects.objects]]\ntype = \"sphere\"\nmaterial = \"white\"\nanimation = {{ spin = [0.0, 90.0, 0.0] }}", material)),
+        "Objects inside a group can't be animated, animate the group instead"
+    );
+    // unknown fields are typos, not something to ignore
+    assert!(parse_error("[[objects]]\ntype = \"sphere\"\nmaterail = \"white\"").contains("unknown field `materail`"));
+}
+
+#[test]
+fn scene_rejects_bad_materials() {
+    let error = |fields: &str| parse_error(&format!("[materials.m]\ncolor = [1.0, 1.0, 1.0]\n{}\n[[objects]]\ntype = \"sphere\"\nmaterial = \"m\"", fields));
+
+    assert_eq!(error("metallic = 1.5"), "Material \"m\" has its metallic outside of 0 to 1");
+    assert_eq!(error("roughness = -0.1"), "Material \"m\" has its roughness outside of 0 to 1");
+    assert_eq!(error("transmission = 2.0"), "Material \"m\" has its transmission outside of 0 to 1");
+    assert_eq!(error("ior = 0.0"), "Material \"m\" has an ior that isn't positive");
+    assert_eq!(error("absorption = [0.0, -1.0, 0.0]"), "Material \"m\" has a negative absorption");
+    assert_eq!(error("mean_free_path = [0.1, 0.0, 0.1]"), "Material \"m\" has a mean free path that isn't positive");
+    assert_eq!(error("medium = \"fog\""), "Unknown medium \"fog\"");
+}
+
+#[test]
+fn scene_rejects_bad_media() {
+    assert_eq!(parse_error("medium = \"fog\""), "Unknown medium \"fog\"");
+    assert_eq!(parse_error("[media.fog]\nscattering = [-0.1, 0.0, 0.0]"), "Medium \"fog\" has a negative scattering or absorption");
+    assert_eq!(parse_error("[media.fog]\nanisotropy = 1.0"), "Medium \"fog\" needs an anisotropy between -1 and 1");
+}
+
+#[test]
+fn scene_rejects_bad_lights() {
+    let error = |fields: &str| parse_error(&format!("[[lights]]\n{}", fields));
+
+    assert_eq!(error("type = \"point\""), "Point and spot lights, and only those, need a position");
+    assert_eq!(error("type = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nposition = [0.0, 1.0, 0.0]"), "Point and spot lights, and only those, need a position");
+    assert_eq!(error("type = \"point\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]"), "Spot and directional lights, and only those, need a direction");
+    assert_eq!(error("type = \"point\"\nposition = [0.0, 1.0, 0.0]\nouter_angle = 30.0"), "Only spot lights have cone angles");
+    assert_eq!(error("type = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\nangular_radius = 1.0"), "Only directional lights have an angular radius");
+    assert_eq!(error("type = \"directional\"\ndirection = [0.0, 0.0, 0.0]"), "Light direction can't be zero");
+    assert_eq!(error("type = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]"), "Spot lights need an outer_angle");
+    assert_eq!(
+        error("type = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\ninner_angle = 40.0\nouter_angle = 30.0"),
+        "A spot light's inner_angle can't be larger than its outer_angle"
+    );
+}
+
+#[test]
+fn scene_rejects_bad_skies() {
+    assert_eq!(parse_error("[sky]\nsun_elevation = 30.0"), "The sky needs either a sun_elevation and sun_azimuth, or a time_of_day");
+    assert_eq!(parse_error("[sky]\nsun_elevation = 30.0\nsun_azimuth = 0.0\ntime_of_day = 12.0"), "The sky needs either a sun_elevation and sun_azimuth, or a time_of_day");
+    assert_eq!(parse_error("[sky]\ntime_of_day = 12.0\nturbidity = 30.0"), "Sky turbidity has to be between 1 and 20");
+    assert_eq!(
+        parse_error("[sky]\ntime_of_day = 12.0\n[environment]\npath = \"../environments/sky.hdr\""),
+        "A scene can have an environment map or a sky, not both"
+    );
+}
+
+#[test]
+fn scene_path_needs_a_value() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(scene::path_from_args(&args(&[])).unwrap().to_str(), Some(scene::DEFAULT_SCENE));
+    assert_eq!(scene::path_from_args(&args(&["--scene", "x.toml"])).unwrap().to_str(), Some("x.toml"));
+    assert_eq!(scene::path_from_args(&args(&["--scene"])).err().unwrap(), "Missing value for --scene");
+}
+
+#[test]
+fn mesh_loads_relative_to_scene() {
+    let scene = Scene::load("assets/scenes/mesh.toml".as_ref()).unwrap();
+
+    // two tori sharing one buffer, each with its own copy of the triangles
+    assert_eq!(scene.meshes.indices.len() % 3, 0);
+    assert_eq!(scene.meshes.indices.len(), 2 * 24 * 12 * 6);
+
+    let image = ReferenceRenderer::default().render(&scene.get_gpu_data(), &scene_inputs(&scene));
+    let sky = ReferenceRenderer::default().render(&GpuScene::new(), &RenderInputs { camera: Some(&scene.camera), ..inputs() });
+    assert_ne!(pixel(&image, WIDTH / 4, HEIGHT / 2), pixel(&sky, WIDTH / 4, HEIGHT / 2));
+}
+
+#[test]
+fn albedo_texture_maps_across_box_face() {
+    let mut textures = TextureBuffer::new();
+    let layer = textures.add(2, 1, vec![0, 0, 0, 255, 255, 255, 255, 255]).unwrap();
+
+    // the face towards the camera runs from u = 0 on its left to 1 on its right
+    let center_color = |x: f32| {
+        let object = objects::Box::new(
+            Transform::new(Vector3::new(x, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0), Vector3::zeros()),
+            Material {
+                albedo_texture: Some(layer),
+                specular: 0.0,
+                ..Default::default()
+            }
+        );
+        let mut gpu_scene = GpuScene::new();
+        object.write_gpu_data(&mut gpu_scene);
+
+        let image = ReferenceRenderer::default().render(&gpu_scene, &RenderInputs { textures: Some(&textures), ..inputs() });
+        pixel(&image, WIDTH / 2, HEIGHT / 2)
+    };
+
+    assert!(center_color(0.5)[0] < 10);
+    assert!(center_color(-0.5)[0] > 100);
+}
+
+#[test]
+fn texture_needs_every_pixel() {
+    let error = TextureBuffer::new().add(2, 2, vec![255; 12]).err().unwrap();
+    assert_eq!(error, "A 2x2 texture needs 16 bytes of pixels, got 12");
+}
+
+#[test]
+fn texture_files_are_loaded_once() {
+    let scene = Scene::load("assets/scenes/textures.toml".as_ref()).unwrap();
+
+    // five texture slots across three materials, pointing at three images
+    assert_eq!(scene.textures.len(), 3);
+    assert_eq!(scene.textures.layer_size(), (64, 64));
+    assert_eq!(scene.textures.layers().len(), 64 * 64 * 4 * 3);
+}
+
+#[test]
+fn gpu_layout_matches_glsl() {
+    // panics if any struct would be laid out differently under std430
+    let glsl = gpu::glsl_definitions();
+    assert!(glsl.contains("struct GpuObject {"));
+}
+
+#[test]
+fn headless_rejects_unknown_arguments() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+    let options = HeadlessOptions::from_args(&args(&["--headless", "--backend", "compute", "--samples", "4", "--output", "x.png"])).unwrap();
+    assert_eq!(options.samples_per_pixel, Some(4));
+    assert_eq!(options.output.to_str(), Some("x.png"));
+
+    let error = HeadlessOptions::from_args(&args(&["--headless", "--ouput", "x.png"])).err().unwrap();
+    assert_eq!(error, "Unknown argument --ouput");
+}
+
+#[test]
+fn headless_renders_at_least_one_frame() {
+    let args: Vec<String> = ["--headless", "--frames", "0"].iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(HeadlessOptions::from_args(&args).err().unwrap(), "Frames must be at least 1");
+}
+
+#[test]
+#[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+fn gpu_matches_reference() {
+    for path in [scene::DEFAULT_SCENE, "assets/scenes/empty.toml", "assets/scenes/cornell.toml", "assets/scenes/mesh.toml", "assets/scenes/lights.toml", "assets/scenes/environment.toml", "assets/scenes/sky.toml", "assets/scenes/textures.toml", "assets/scenes/glass.toml", "assets/scenes/media.toml", "assets/scenes/subsurface.toml", "assets/scenes/primitives.toml"] {
+        let options = HeadlessOptions {
+            width: WIDTH,
+            height: HEIGHT,
+            frames: 1,
+            scene: path.into(),
+            ..Default::default()
+        };
+        let gpu = headless::render(&options).unwrap();
+
+        let mut scene = Scene::load(path.as_ref()).unwrap();
+        scene.update(0.0);
+        let cpu = ReferenceRenderer::default().render(&scene.get_gpu_data(), &scene_inputs(&scene));
+
+        // individual paths diverge with float differences between drivers, but the image as a whole shouldn't
+        let difference = mean_abs_difference(&gpu, &cpu);
+        assert!(difference < 1.0, "mean difference of {} per channel in {}", difference, path);
+    }
+}
+
+#[test]
+#[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+fn gpu_scaled_plane_matches_reference() {
+    let path = std::env::temp_dir().join(format!("yee-rt-scaled-plane-{}.toml", std::process::id()));
+    std::fs::write(&path, "\
+[materials.ground]
+color = [0.0, 0.0, 0.0]
+# red, so it stands out from the nearly white sky at the horizon
+emission = [1.0, 0.0, 0.0]
+
+[[objects]]
+type = \"plane\"
+material = \"ground\"
+position = [0.0, -1.0, 0.0]
+scale = [0.001, 1.0, 0.001]
+").unwrap();
+
+    let options = HeadlessOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        frames: 1,
+        scene: path.clone(),
+        ..Default::default()
+    };
+    let gpu = headless::render(&options).unwrap();
+
+    let mut scene = Scene::load(&path).unwrap();
+    scene.update(0.0);
+    let cpu = ReferenceRenderer::default().render(&scene.get_gpu_data(), &scene_inputs(&scene));
+
+    // the reference doesn't traverse the BVH, so it sees the plane reach the horizon whatever its bounds say
+    let difference = mean_abs_difference(&gpu, &cpu);
+    assert!(difference < 1.0, "mean difference of {} per channel", difference);
+}
+
+#[test]
+#[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+fn gpu_permutation_matches_reference() {
+    let path = "assets/scenes/cornell.toml";
+    let options = HeadlessOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        frames: 1,
+        scene: path.into(),
+        max_bounces: Some(2),
+        samples_per_pixel: Some(4),
+        ..Default::default()
+    };
+    let gpu = headless::render(&options).unwrap();
+
+    let mut scene = Scene::load(path.as_ref()).unwrap();
+    scene.update(0.0);
+    let reference = ReferenceRenderer {
+        max_bounces: 2,
+        samples_per_pixel: 4,
+    };
+    let cpu = reference.render(&scene.get_gpu_data(), &scene_inputs(&scene));
+    let full = ReferenceRenderer::default().render(&scene.get_gpu_data(), &scene_inputs(&scene));
+
+    let difference = mean_abs_difference(&gpu, &cpu);
+    assert!(difference < 1.0, "mean difference of {} per channel", difference);
+    // two bounces can't reach everything ten can, so the define has to have made it into the shader
+    assert!(mean_abs_difference(&gpu, &full) > difference);
+}
+
+#[test]
+#[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+fn headless_rejects_zero_bounces() {
+    let options = HeadlessOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        frames: 1,
+        max_bounces: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(headless::render(&options).err().unwrap(), "Bounces per path must be at least 1");
+}
+
+#[test]
+#[ignore = "needs an OpenGL 4.3 capable EGL driver, e.g. Mesa llvmpipe"]
+fn gpu_compute_matches_fragment() {
+    let path = "assets/scenes/cornell.toml";
+    let options = HeadlessOptions {
+        width: WIDTH,
+        height: HEIGHT,
+        frames: 2,
+        scene: path.into(),
+        ..Default::default()
+    };
+    let fragment = headless::render(&options).unwrap();
+    // a workgroup size that doesn't divide the image, so the edges get dispatched partially
+    let compute = headless::render(&HeadlessOptions {
+        backend: Backend::Compute,
+        workgroup_size: Some((5, 7)),
+        ..options
+    }).unwrap();
+
+    // both trace the same paths with the same seeds, so only float differences between the stages remain
+    let difference = mean_abs_difference(&fragment, &compute);
+    assert!(difference < 0.5, "mean difference of {} per channel", difference);
+}